@@ -1,7 +1,37 @@
-use crate::config::{Config, Connection};
-use crate::terminal::VirtualTerminal;
-use color_eyre::Result;
-use tokio::sync::mpsc;
+use crate::config::{Config, Connection, RemoteHost, UNTAGGED};
+use crate::git::{self, GitRef, GitStatus};
+use crate::history::{self, BuildRecord, RunInfo};
+use crate::nix::closure::{self, ClosureDiff};
+use crate::nix::diagnostics::{Diagnostic, DiagnosticsCache};
+use crate::nix::facts::{self, HostFacts};
+use crate::nix::flake::{self, Drift, FlakeInput, InputChange};
+use crate::nix::generations::{self, Generation};
+use crate::nix::progress::BuildProgress;
+use crate::nix::reachability::{self, Reachability};
+use crate::nix::rebuild::{ProcessHandle, PtyCommand};
+use crate::nix::RebuildCommand;
+use crate::session::{BuildSession, BuildStatus};
+use crate::terminal::{SearchMatch, VirtualTerminal};
+use crate::util::BackgroundTask;
+use color_eyre::{eyre::Context, Result};
+use regex::Regex;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Session the flake input updates run in, next to the hosts' sessions
+pub const FLAKE_SESSION: &str = "(flake)";
+
+/// How long quitting waits for builds to exit after SIGTERM before SIGKILL
+const QUIT_GRACE: Duration = Duration::from_secs(2);
+
+/// How long quitting then waits for history records still being written;
+/// one that is still waiting on its flake revision is lost after that
+const SAVE_GRACE: Duration = Duration::from_secs(2);
+
+/// How often the flake checkout's git status is re-read
+const GIT_REFRESH: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FocusedPanel {
@@ -15,6 +45,8 @@ pub enum EditMode {
     FlakePath,
     HostConnection,
     ExtraArgs,
+    BuildHost,
+    GitRef,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -39,6 +71,16 @@ impl RebuildOperation {
         }
     }
 
+    /// Whether the operation puts a new system on the host
+    pub fn activates(&self) -> bool {
+        matches!(self, Self::Switch | Self::Boot | Self::Test)
+    }
+
+    /// Parse an operation from its nixos-rebuild subcommand name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::all().into_iter().find(|op| op.as_str() == name)
+    }
+
     pub fn all() -> Vec<Self> {
         vec![
             Self::Switch,
@@ -63,15 +105,225 @@ impl RebuildOperation {
     }
 }
 
+/// Formats the output buffer can be exported in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    PlainText,
+    Ansi,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::PlainText => "txt",
+            Self::Ansi => "ansi",
+        }
+    }
+}
+
+/// Past builds of a host, listed in place of its output
+pub struct HistoryView {
+    pub host: String,
+    pub records: Vec<BuildRecord>,
+    pub selected: usize,
+}
+
+/// A recorded build's log, replayed into its own terminal
+pub struct Replay {
+    pub record: BuildRecord,
+    pub terminal: VirtualTerminal,
+    pub diagnostics: DiagnosticsCache,
+}
+
+/// The diagnostics panel shown next to the output
+pub struct DiagnosticsPanel {
+    pub selected: usize,
+    /// Show the trace of the selected diagnostic
+    pub expanded: bool,
+}
+
+/// A regex search over the output being shown
+pub struct OutputSearch {
+    pub query: String,
+    /// Compiled query, None while it is empty or invalid
+    pub regex: Option<Regex>,
+    /// Still typing the query
+    pub editing: bool,
+    /// Line and column of the selected match
+    pub current: Option<(usize, usize)>,
+    /// Matches of `regex`, found at the terminal revision and query in
+    /// `searched`
+    pub matches: Vec<SearchMatch>,
+    pub searched: Option<(u64, String)>,
+}
+
+impl OutputSearch {
+    pub fn is_invalid(&self) -> bool {
+        self.regex.is_none() && !self.query.is_empty()
+    }
+}
+
+/// System generations of a host, listed in place of its output
+pub struct GenerationsView {
+    pub host: String,
+    pub generations: Vec<Generation>,
+    pub selected: usize,
+    /// Listing still running on the host
+    pub loading: Option<BackgroundTask<Result<Vec<Generation>>>>,
+    pub error: Option<String>,
+}
+
+/// Something that changes a host and needs a yes before it runs
+#[derive(Debug, Clone, PartialEq)]
+pub enum PendingAction {
+    Rollback {
+        host: String,
+    },
+    SwitchGeneration {
+        host: String,
+        number: u32,
+    },
+    /// Deploy the target hosts from a checkout with uncommitted changes
+    DirtyDeploy {
+        reviewed: bool,
+    },
+}
+
+/// What is waiting on a git ref being resolved in the background
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AfterGitRef {
+    /// The ref was just chosen
+    Select,
+    /// Deploy the target hosts from it
+    Deploy { reviewed: bool },
+}
+
+/// A pending action and the question asked about it
+pub struct Confirmation {
+    pub prompt: String,
+    pub action: PendingAction,
+}
+
+/// The flake's inputs, shown above the output of updating them
+pub struct InputsView {
+    pub inputs: Vec<FlakeInput>,
+    pub selected: usize,
+    pub loading: Option<BackgroundTask<Result<Vec<FlakeInput>>>>,
+    pub error: Option<String>,
+    /// Inputs as they were locked before the running update
+    pub before: Option<Vec<FlakeInput>>,
+    /// What the last update changed, once the lock has been read again
+    pub changes: Option<Vec<InputChange>>,
+}
+
+/// Where a reviewed deploy of a host stands
+pub enum ReviewStage {
+    /// Waiting for the build operation to finish
+    Building,
+    /// Copying the closure and diffing it against the running system
+    Diffing(BackgroundTask<Result<ClosureDiff>>),
+    /// Waiting for the user to switch, boot or abort
+    Ready(ClosureDiff),
+    Failed(String),
+}
+
+/// A deploy that builds first and only activates once the closure diff has
+/// been reviewed
+pub struct DeployReview {
+    /// Directory the build runs in; its `result` link is the new toplevel
+    pub build_dir: PathBuf,
+    pub toplevel: Option<String>,
+    /// The build ran with --upgrade
+    pub upgrade: bool,
+    /// The git ref the build was made from, if not the working tree
+    pub git_ref: Option<GitRef>,
+    pub stage: ReviewStage,
+    pub scroll: u16,
+}
+
+/// A row of the host list
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostRow {
+    /// Header of a group's section, when the list is grouped by tag
+    Group(String),
+    Host(String, Connection),
+}
+
+/// An operation run against every configured host of a group
+pub struct GroupRun {
+    pub operation: RebuildOperation,
+    /// All hosts at once, up to max_parallel; otherwise one after another,
+    /// stopping at the first that doesn't succeed
+    pub parallel: bool,
+    /// Hosts in the order they are started
+    pub hosts: Vec<String>,
+    /// How many of `hosts` have been started on
+    pub started: usize,
+    /// Started hosts that couldn't be queued, because they were already busy
+    /// or their command couldn't be put together; they count as failed
+    pub not_queued: Vec<String>,
+    /// Set once every host has finished or been skipped
+    pub summary: Option<String>,
+}
+
+/// The last reachability check of a host, and the one running now
+#[derive(Default)]
+pub struct HostCheck {
+    pub result: Option<Reachability>,
+    pub checked_at: Option<Instant>,
+    pub task: Option<BackgroundTask<Reachability>>,
+}
+
+/// The facts of a host last gathered, and the gathering running now
+#[derive(Default)]
+pub struct FactsEntry {
+    pub facts: Option<HostFacts>,
+    pub error: Option<String>,
+    pub fetched_at: Option<Instant>,
+    pub loading: Option<BackgroundTask<Result<HostFacts>>>,
+}
+
 pub struct App {
     pub config: Config,
     pub focused_panel: FocusedPanel,
     pub selected_host_idx: usize,
     pub selected_operation: RebuildOperation,
-    pub terminal: VirtualTerminal,
-    pub is_building: bool,
-    pub output_receiver: Option<mpsc::Receiver<Vec<u8>>>,
-    pub input_sender: Option<mpsc::Sender<Vec<u8>>>,
+    pub sessions: HashMap<String, BuildSession>,
+    pub build_queue: VecDeque<String>,
+    pub marked_hosts: BTreeSet<String>,
+    /// Show the host list in sections by tag
+    pub grouped: bool,
+    pub collapsed_groups: BTreeSet<String>,
+    /// Whether group operations run their hosts in parallel or in sequence
+    pub group_parallel: bool,
+    pub group_runs: HashMap<String, GroupRun>,
+    /// Reachability of the configured hosts, by host name
+    pub host_checks: HashMap<String, HostCheck>,
+    pub idle_terminal: VirtualTerminal,
+    pub history_view: Option<HistoryView>,
+    pub replay: Option<Replay>,
+    pub generations_view: Option<GenerationsView>,
+    pub inputs_view: Option<InputsView>,
+    pub confirmation: Option<Confirmation>,
+    pub search: Option<OutputSearch>,
+    pub diagnostics_panel: Option<DiagnosticsPanel>,
+    /// Show the selected host's facts next to the output
+    pub facts_panel: bool,
+    pub host_facts: HashMap<String, FactsEntry>,
+    /// Whether each host runs what the flake evaluates to
+    pub drift: HashMap<String, Drift>,
+    /// Hosts waiting for a drift check; they are evaluated one at a time
+    drift_queue: VecDeque<String>,
+    drift_task: Option<(String, BackgroundTask<Drift>)>,
+    pub reviews: HashMap<String, DeployReview>,
+    /// Git state of the flake, if it is a local checkout
+    pub git_status: Option<GitStatus>,
+    git_checked_at: Option<Instant>,
+    git_task: Option<BackgroundTask<Result<Option<GitStatus>>>>,
+    /// Commit of the flake's repository rebuilds deploy, until cleared
+    pub git_ref: Option<GitRef>,
+    git_ref_task: Option<(AfterGitRef, BackgroundTask<Result<GitRef>>)>,
+    pub status_message: Option<String>,
     pub input_mode: bool,
     pub edit_mode: EditMode,
     pub edit_buffer: String,
@@ -89,10 +341,34 @@ impl App {
             focused_panel: FocusedPanel::Main,
             selected_host_idx: 0,
             selected_operation: RebuildOperation::Switch,
-            terminal: VirtualTerminal::new(200, 100), // Initial size, will be resized on first render
-            is_building: false,
-            output_receiver: None,
-            input_sender: None,
+            sessions: HashMap::new(),
+            build_queue: VecDeque::new(),
+            marked_hosts: BTreeSet::new(),
+            grouped: false,
+            collapsed_groups: BTreeSet::new(),
+            group_parallel: true,
+            group_runs: HashMap::new(),
+            host_checks: HashMap::new(),
+            idle_terminal: VirtualTerminal::new(80, 24), // Initial size, will be resized on first render
+            history_view: None,
+            replay: None,
+            generations_view: None,
+            inputs_view: None,
+            confirmation: None,
+            search: None,
+            diagnostics_panel: None,
+            facts_panel: false,
+            host_facts: HashMap::new(),
+            drift: HashMap::new(),
+            drift_queue: VecDeque::new(),
+            drift_task: None,
+            reviews: HashMap::new(),
+            git_status: None,
+            git_checked_at: None,
+            git_task: None,
+            git_ref: None,
+            git_ref_task: None,
+            status_message: None,
             input_mode: false,
             edit_mode: EditMode::None,
             edit_buffer: String::new(),
@@ -104,233 +380,1830 @@ impl App {
         }
     }
 
-    /// Resize the terminal to match the output area
-    pub fn resize_terminal(&mut self, width: usize, height: usize) {
-        self.terminal.resize(width, height);
-        self.terminal_cols = width as u16;
-        self.terminal_rows = height as u16;
-    }
-
-    /// Scroll output up
-    pub fn scroll_output_up(&mut self) {
-        let total_lines = self.terminal.get_scrollback().len() + self.terminal.get_screen().len();
-        let max_scroll = total_lines.saturating_sub(1);
-        if self.output_scroll < max_scroll {
-            self.output_scroll = self.output_scroll.saturating_add(1);
+    /// Resize the terminals, and the PTYs of running builds, to match the
+    /// output area
+    pub fn resize_terminal(&mut self, cols: u16, rows: u16) {
+        if (cols, rows) == (self.terminal_cols, self.terminal_rows) {
+            return;
+        }
+
+        self.idle_terminal.resize(cols as usize, rows as usize);
+        if let Some(ref mut replay) = self.replay {
+            replay.terminal.resize(cols as usize, rows as usize);
+        }
+        for session in self.sessions.values_mut() {
+            session.resize(cols, rows);
+        }
+        self.terminal_cols = cols;
+        self.terminal_rows = rows;
+    }
+
+    /// Session of the currently selected host, if it has one
+    pub fn selected_session(&self) -> Option<&BuildSession> {
+        self.sessions.get(&self.shown_session_name()?)
+    }
+
+    /// Session whose output is shown: the flake updates' while the inputs
+    /// view is open, otherwise the selected host's
+    fn shown_session_name(&self) -> Option<String> {
+        if self.inputs_view.is_some() {
+            return Some(FLAKE_SESSION.to_string());
+        }
+        self.get_selected_host().map(|(name, _)| name)
+    }
+
+    /// Get or create the session for a host
+    fn session_mut(&mut self, host: &str) -> &mut BuildSession {
+        let (cols, rows) = (self.terminal_cols as usize, self.terminal_rows as usize);
+        self.sessions
+            .entry(host.to_string())
+            .or_insert_with(|| BuildSession::new(cols, rows))
+    }
+
+    /// Terminal showing the selected host's output, or the replayed build
+    pub fn terminal(&self) -> &VirtualTerminal {
+        if let Some(ref replay) = self.replay {
+            return &replay.terminal;
+        }
+        self.selected_session()
+            .map(|s| &s.terminal)
+            .unwrap_or(&self.idle_terminal)
+    }
+
+    /// Terminal showing the selected host's output
+    pub fn terminal_mut(&mut self) -> &mut VirtualTerminal {
+        match self.shown_session_name() {
+            Some(name) => &mut self.session_mut(&name).terminal,
+            None => &mut self.idle_terminal,
+        }
+    }
+
+    /// Full log of the shown output, if it was saved to a file
+    pub fn shown_log_path(&self) -> Option<&Path> {
+        match self.replay {
+            Some(ref replay) => replay.record.log_path.as_deref(),
+            None => self.selected_session()?.log_path.as_deref(),
+        }
+    }
+
+    /// Export the shown output buffer to a file in the exports directory
+    pub fn export_output(&mut self, format: ExportFormat) {
+        let terminal = self.terminal();
+        let text = match format {
+            ExportFormat::PlainText => terminal.to_plain_text(),
+            ExportFormat::Ansi => terminal.to_ansi(),
+        };
+
+        let name = match self.replay {
+            Some(ref replay) => replay.record.id.clone(),
+            None => {
+                let host = self
+                    .get_selected_host()
+                    .map(|(name, _)| name)
+                    .unwrap_or_else(|| "output".to_string());
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                format!("{}-{}", now, host)
+            }
+        };
+
+        let result = history::exports_dir().and_then(|dir| {
+            fs::create_dir_all(&dir).wrap_err("Failed to create exports directory")?;
+            let path = dir.join(format!("{}.{}", name, format.extension()));
+            fs::write(&path, text).wrap_err("Failed to write export")?;
+            Ok(path)
+        });
+
+        self.status_message = Some(match result {
+            Ok(path) => format!("exported output to {}", path.display()),
+            Err(e) => format!("export failed: {}", e),
+        });
+    }
+
+    /// Whether any host has a build queued or running
+    pub fn is_building(&self) -> bool {
+        self.sessions.values().any(|s| s.is_active())
+    }
+
+    /// Whether the selected host has a build running
+    pub fn selected_is_building(&self) -> bool {
+        self.selected_session().is_some_and(|s| s.is_running())
+    }
+
+    /// Number of builds currently running
+    pub fn running_count(&self) -> usize {
+        self.sessions.values().filter(|s| s.is_running()).count()
+    }
+
+    /// Build status of a host
+    pub fn host_status(&self, host: &str) -> BuildStatus {
+        self.sessions
+            .get(host)
+            .map(|s| s.status)
+            .unwrap_or(BuildStatus::Idle)
+    }
+
+    /// Scroll output up
+    pub fn scroll_output_up(&mut self) {
+        let terminal = self.terminal();
+        let total_lines = terminal.get_scrollback().len() + terminal.get_screen().len();
+        let max_scroll = total_lines.saturating_sub(1);
+        if self.output_scroll < max_scroll {
+            self.output_scroll = self.output_scroll.saturating_add(1);
+        }
+    }
+
+    /// Scroll output down
+    pub fn scroll_output_down(&mut self) {
+        if self.output_scroll > 0 {
+            self.output_scroll -= 1;
+        }
+    }
+
+    /// Start typing a search over the output
+    pub fn start_search(&mut self) {
+        self.search = Some(OutputSearch {
+            query: String::new(),
+            regex: None,
+            editing: true,
+            current: None,
+            matches: Vec::new(),
+            searched: None,
+        });
+    }
+
+    pub fn is_typing_search(&self) -> bool {
+        self.search.as_ref().is_some_and(|s| s.editing)
+    }
+
+    /// Handle character input while typing a search
+    pub fn search_insert_char(&mut self, c: char) {
+        if let Some(ref mut search) = self.search {
+            search.query.push(c);
+        }
+        self.compile_search();
+    }
+
+    /// Handle backspace while typing a search
+    pub fn search_backspace(&mut self) {
+        if let Some(ref mut search) = self.search {
+            search.query.pop();
+        }
+        self.compile_search();
+    }
+
+    /// Recompile the query and select the match nearest the end of the output
+    fn compile_search(&mut self) {
+        let Some(ref mut search) = self.search else {
+            return;
+        };
+        search.regex = Regex::new(&search.query)
+            .ok()
+            .filter(|_| !search.query.is_empty());
+        search.current = None;
+        self.search_next(false);
+    }
+
+    /// Stop typing and keep the search for n/N
+    pub fn commit_search(&mut self) {
+        match self.search {
+            Some(ref mut search) if search.regex.is_some() => search.editing = false,
+            _ => self.search = None,
+        }
+    }
+
+    pub fn cancel_search(&mut self) {
+        self.search = None;
+    }
+
+    /// Matches of the search in the output being shown
+    pub fn search_matches(&self) -> &[SearchMatch] {
+        self.search.as_ref().map_or(&[], |s| &s.matches)
+    }
+
+    /// Search the output again if it or the query changed since the last time
+    fn refresh_search(&mut self) {
+        let revision = self.terminal().revision();
+        let Some(ref search) = self.search else {
+            return;
+        };
+        if search
+            .searched
+            .as_ref()
+            .is_some_and(|(r, query)| *r == revision && *query == search.query)
+        {
+            return;
+        }
+        let matches = match search.regex {
+            Some(ref regex) => self.terminal().search(regex),
+            None => Vec::new(),
+        };
+        if let Some(ref mut search) = self.search {
+            search.matches = matches;
+            search.searched = Some((revision, search.query.clone()));
+        }
+    }
+
+    /// Position of the selected match and the number of matches, for display
+    pub fn search_counter(&self) -> (Option<usize>, usize) {
+        let matches = self.search_matches();
+        let current = self.search.as_ref().and_then(|s| s.current);
+        let position = matches
+            .iter()
+            .position(|m| Some((m.line, m.start)) == current)
+            .map(|idx| idx + 1);
+        (position, matches.len())
+    }
+
+    /// Select the next match after the current one, or the one before it,
+    /// wrapping around, and scroll it into view
+    /// Without a current match, going back starts from the end of the output
+    pub fn search_next(&mut self, forward: bool) {
+        self.refresh_search();
+        let Some(ref mut search) = self.search else {
+            return;
+        };
+        let matches = &search.matches;
+        let next = match (search.current, forward) {
+            (Some(current), true) => matches
+                .iter()
+                .find(|m| (m.line, m.start) > current)
+                .or(matches.first()),
+            (Some(current), false) => matches
+                .iter()
+                .rev()
+                .find(|m| (m.line, m.start) < current)
+                .or(matches.last()),
+            (None, true) => matches.first(),
+            (None, false) => matches.last(),
+        };
+        let Some(next) = next.copied() else {
+            return;
+        };
+        search.current = Some((next.line, next.start));
+        self.scroll_to_line(next.line);
+    }
+
+    /// Scroll the output so a line sits a third of the way down the view
+    fn scroll_to_line(&mut self, line: usize) {
+        let terminal = self.terminal();
+        // The note about trimmed lines takes the first line
+        let offset = usize::from(terminal.dropped_lines() > 0);
+        let total_lines = offset + terminal.line_count();
+        let visible = self.terminal_rows as usize;
+        let start = (offset + line).saturating_sub(visible / 3);
+        self.output_scroll = total_lines.saturating_sub(visible).saturating_sub(start);
+    }
+
+    /// Progress nix reported for the selected host's build, if it did
+    pub fn selected_progress(&self) -> Option<&BuildProgress> {
+        if self.replay.is_some() {
+            return None;
+        }
+        self.selected_session()
+            .filter(|s| s.progress.active)
+            .map(|s| &s.progress.progress)
+    }
+
+    /// Errors and warnings found in the output being shown
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        if let Some(ref replay) = self.replay {
+            return replay.diagnostics.entries();
+        }
+        self.selected_session()
+            .map(|s| s.diagnostics.entries())
+            .unwrap_or_default()
+    }
+
+    /// Open or close the diagnostics panel
+    pub fn toggle_diagnostics(&mut self) {
+        if self.diagnostics_panel.take().is_none() {
+            self.diagnostics_panel = Some(DiagnosticsPanel {
+                selected: 0,
+                expanded: false,
+            });
+        }
+    }
+
+    /// Index of the selected diagnostic, kept within the current list
+    pub fn selected_diagnostic(&self) -> Option<usize> {
+        let panel = self.diagnostics_panel.as_ref()?;
+        let count = self.diagnostics().len();
+        (count > 0).then(|| panel.selected.min(count - 1))
+    }
+
+    /// Select the previous or next diagnostic and scroll the output to it
+    pub fn diagnostics_select(&mut self, next: bool) {
+        let Some(selected) = self.selected_diagnostic() else {
+            return;
+        };
+        let count = self.diagnostics().len();
+        let selected = if next {
+            (selected + 1).min(count - 1)
+        } else {
+            selected.saturating_sub(1)
+        };
+        if let Some(ref mut panel) = self.diagnostics_panel {
+            panel.selected = selected;
+        }
+        self.scroll_to_line(self.diagnostics()[selected].line);
+    }
+
+    /// Show or hide the trace of the selected diagnostic
+    pub fn toggle_diagnostic_expanded(&mut self) {
+        if let Some(ref mut panel) = self.diagnostics_panel {
+            panel.expanded = !panel.expanded;
+        }
+    }
+
+    /// Get list of hosts as (name, connection) tuples, sorted by name
+    pub fn get_hosts(&self) -> Vec<(String, Connection)> {
+        let mut hosts: Vec<_> = self
+            .config
+            .hosts
+            .iter()
+            .map(|(name, config)| (name.clone(), config.connection.clone()))
+            .collect();
+        hosts.sort_by(|a, b| a.0.cmp(&b.0));
+        hosts
+    }
+
+    /// Rows of the host list: the hosts, or with grouping on, a header for
+    /// each group followed by its hosts unless it is collapsed
+    pub fn host_rows(&self) -> Vec<HostRow> {
+        if !self.grouped {
+            return self
+                .get_hosts()
+                .into_iter()
+                .map(|(name, connection)| HostRow::Host(name, connection))
+                .collect();
+        }
+
+        let mut groups: Vec<_> = self.config.groups().into_iter().collect();
+        // Untagged hosts go last
+        groups.sort_by_key(|(group, _)| group == UNTAGGED);
+
+        let mut rows = Vec::new();
+        for (group, hosts) in groups {
+            let collapsed = self.collapsed_groups.contains(&group);
+            rows.push(HostRow::Group(group));
+            if collapsed {
+                continue;
+            }
+            for name in hosts {
+                if let Some(host) = self.config.hosts.get(&name) {
+                    rows.push(HostRow::Host(name, host.connection.clone()));
+                }
+            }
+        }
+        rows
+    }
+
+    /// Get currently selected host name and connection
+    pub fn get_selected_host(&self) -> Option<(String, Connection)> {
+        match self.host_rows().get(self.selected_host_idx) {
+            Some(HostRow::Host(name, connection)) => Some((name.clone(), connection.clone())),
+            _ => None,
+        }
+    }
+
+    /// Group whose header is selected
+    pub fn selected_group(&self) -> Option<String> {
+        match self.host_rows().get(self.selected_host_idx) {
+            Some(HostRow::Group(group)) => Some(group.clone()),
+            _ => None,
+        }
+    }
+
+    /// Configured hosts of a group, sorted by name
+    pub fn group_hosts(&self, group: &str) -> Vec<String> {
+        let mut hosts = self.config.groups().remove(group).unwrap_or_default();
+        hosts.retain(|name| {
+            self.config
+                .hosts
+                .get(name)
+                .is_some_and(|h| h.connection.is_configured())
+        });
+        hosts
+    }
+
+    /// Switch between the flat and the grouped host list
+    pub fn toggle_grouped(&mut self) {
+        let selected = self.host_rows().get(self.selected_host_idx).cloned();
+        self.grouped = !self.grouped;
+        self.reselect(selected);
+    }
+
+    /// Collapse or expand the selected group
+    pub fn toggle_group_collapsed(&mut self) {
+        if let Some(group) = self.selected_group() {
+            if !self.collapsed_groups.remove(&group) {
+                self.collapsed_groups.insert(group);
+            }
+        }
+    }
+
+    /// Switch group operations between parallel and sequential
+    pub fn toggle_group_parallel(&mut self) {
+        self.group_parallel = !self.group_parallel;
+    }
+
+    /// Keep the selection on the same row after the rows changed
+    fn reselect(&mut self, row: Option<HostRow>) {
+        let rows = self.host_rows();
+        let idx = row.and_then(|row| match row {
+            HostRow::Host(name, _) => rows
+                .iter()
+                .position(|r| matches!(r, HostRow::Host(n, _) if *n == name)),
+            group => rows.iter().position(|r| *r == group),
+        });
+        let idx = idx.unwrap_or(0).min(rows.len().saturating_sub(1));
+        if idx != self.selected_host_idx {
+            self.selected_host_idx = idx;
+            self.on_selection_changed();
+        }
+    }
+
+    /// Move selection up in host list
+    pub fn select_prev_host(&mut self) {
+        if self.selected_host_idx > 0 {
+            self.selected_host_idx -= 1;
+            self.on_selection_changed();
+        }
+        self.quit_warned = false;
+    }
+
+    /// Move selection down in host list
+    pub fn select_next_host(&mut self) {
+        let rows = self.host_rows();
+        if self.selected_host_idx < rows.len().saturating_sub(1) {
+            self.selected_host_idx += 1;
+            self.on_selection_changed();
+        }
+        self.quit_warned = false;
+    }
+
+    /// Reset per-view state when a different host's output is shown
+    fn on_selection_changed(&mut self) {
+        self.output_scroll = 0;
+        self.input_mode = false;
+        if self.facts_panel {
+            self.load_facts(false);
+        }
+    }
+
+    /// Whether the facts pane or the diagnostics panel takes up a column
+    /// next to the output
+    pub fn has_side_panel(&self) -> bool {
+        self.facts_panel || self.diagnostics_panel.is_some()
+    }
+
+    /// Show or hide the facts pane, gathering the selected host's facts
+    /// the first time it is shown
+    pub fn toggle_facts(&mut self) {
+        self.facts_panel = !self.facts_panel;
+        if self.facts_panel {
+            self.load_facts(false);
+        }
+    }
+
+    /// Gather the selected host's facts, unless they are already there or
+    /// `refresh` asks for them again
+    pub fn load_facts(&mut self, refresh: bool) {
+        let Some((host, connection)) = self.get_selected_host() else {
+            return;
+        };
+        if !connection.is_configured() || (!refresh && self.host_facts.contains_key(&host)) {
+            return;
+        }
+        self.gather_facts(&host, connection);
+    }
+
+    /// Start gathering a host's facts, keeping the old ones shown meanwhile
+    fn gather_facts(&mut self, host: &str, connection: Connection) {
+        let entry = self.host_facts.entry(host.to_string()).or_default();
+        if entry.loading.is_none() {
+            entry.loading = Some(BackgroundTask::spawn(move || facts::gather(&connection)));
+        }
+    }
+
+    /// Facts of the selected host
+    pub fn selected_facts(&self) -> Option<&FactsEntry> {
+        let (host, _) = self.get_selected_host()?;
+        self.host_facts.get(&host)
+    }
+
+    /// Pick up gathered facts
+    fn poll_facts(&mut self) {
+        for entry in self.host_facts.values_mut() {
+            let Some(result) = entry.loading.as_ref().and_then(|task| task.try_take()) else {
+                continue;
+            };
+            entry.loading = None;
+            entry.fetched_at = Some(Instant::now());
+            match result {
+                Ok(facts) => {
+                    entry.facts = Some(facts);
+                    entry.error = None;
+                }
+                Err(e) => entry.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    /// Toggle whether the selected host is marked for a multi-host rebuild
+    pub fn toggle_host_mark(&mut self) {
+        if let Some((name, _)) = self.get_selected_host() {
+            if !self.marked_hosts.remove(&name) {
+                self.marked_hosts.insert(name);
+            }
+        }
+        self.quit_warned = false;
+    }
+
+    /// Open or close the flake inputs view
+    pub fn toggle_inputs(&mut self) {
+        if self.inputs_view.take().is_some() {
+            self.output_scroll = 0;
+            return;
+        }
+        let Some(flake_path) = self.config.flake_path.clone() else {
+            self.status_message = Some("Set a flake path to see its inputs".to_string());
+            return;
+        };
+
+        self.close_history();
+        self.close_generations();
+        self.input_mode = false;
+        self.output_scroll = 0;
+        self.inputs_view = Some(InputsView {
+            inputs: Vec::new(),
+            selected: 0,
+            loading: Some(BackgroundTask::spawn(move || {
+                flake::flake_inputs(&flake_path)
+            })),
+            error: None,
+            before: None,
+            changes: None,
+        });
+    }
+
+    /// Whether the flake inputs view is showing
+    pub fn is_browsing_inputs(&self) -> bool {
+        self.inputs_view.is_some()
+    }
+
+    /// Move the input selection up or down
+    pub fn inputs_select(&mut self, next: bool) {
+        if let Some(ref mut view) = self.inputs_view {
+            if next {
+                if view.selected + 1 < view.inputs.len() {
+                    view.selected += 1;
+                }
+            } else {
+                view.selected = view.selected.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Run `nix flake update` for the selected input, or for all of them
+    pub async fn update_inputs(&mut self, all: bool) -> Result<()> {
+        let Some(flake_path) = self.config.flake_path.clone() else {
+            return Ok(());
+        };
+        let Some(ref mut view) = self.inputs_view else {
+            return Ok(());
+        };
+        if self
+            .sessions
+            .get(FLAKE_SESSION)
+            .is_some_and(|s| s.is_active())
+        {
+            return Ok(());
+        }
+        let input = if all {
+            None
+        } else {
+            match view.inputs.get(view.selected) {
+                // Following inputs are updated through the one they follow
+                Some(input) if input.follows.is_none() => Some(input.name.clone()),
+                _ => return Ok(()),
+            }
+        };
+        view.before = Some(view.inputs.clone());
+        view.changes = None;
+
+        let cmd = flake::update_command(
+            &flake_path,
+            input.as_deref(),
+            self.terminal_cols,
+            self.terminal_rows,
+        );
+        let info = RunInfo {
+            host: FLAKE_SESSION.to_string(),
+            operation: "flake-update".to_string(),
+            extra_args: input.iter().cloned().collect(),
+            upgrade: false,
+            flake_path: Some(flake_path.clone()),
+            git_ref: None,
+        };
+        let banner = format!(
+            "Updating {} of {} ...\n",
+            input.as_deref().unwrap_or("all inputs"),
+            flake_path
+        );
+        self.queue_command(FLAKE_SESSION, cmd, info, &banner);
+        self.start_queued_builds().await;
+        Ok(())
+    }
+
+    /// Pick up the inputs once read, and read them again after an update
+    fn poll_inputs(&mut self) {
+        let update_status = self.host_status(FLAKE_SESSION);
+        let flake_path = self.config.flake_path.clone();
+        let Some(ref mut view) = self.inputs_view else {
+            return;
+        };
+
+        if let Some(result) = view.loading.as_ref().and_then(|task| task.try_take()) {
+            view.loading = None;
+            match result {
+                Ok(inputs) => {
+                    if let Some(before) = view.before.take() {
+                        view.changes = Some(flake::diff_inputs(&before, &inputs));
+                    }
+                    view.selected = view.selected.min(inputs.len().saturating_sub(1));
+                    view.inputs = inputs;
+                    view.error = None;
+                }
+                Err(e) => view.error = Some(e.to_string()),
+            }
+            return;
+        }
+
+        // The update has finished; read the lock it left behind
+        let waiting = view.before.is_some() && view.loading.is_none();
+        if waiting && !matches!(update_status, BuildStatus::Queued | BuildStatus::Running) {
+            match (update_status, flake_path) {
+                (BuildStatus::Succeeded, Some(flake_path)) => {
+                    view.loading = Some(BackgroundTask::spawn(move || {
+                        flake::flake_inputs(&flake_path)
+                    }));
+                    // What the hosts would build has changed
+                    self.refresh_drift();
+                }
+                _ => view.before = None,
+            }
+        }
+    }
+
+    /// Open or close the build history of the selected host
+    pub fn toggle_history(&mut self) {
+        if self.history_view.is_some() {
+            self.close_history();
+            return;
+        }
+
+        let Some((host, _)) = self.get_selected_host() else {
+            return;
+        };
+        self.generations_view = None;
+        self.inputs_view = None;
+        match history::load_for_host(&host) {
+            Ok(records) => {
+                self.history_view = Some(HistoryView {
+                    host,
+                    records,
+                    selected: 0,
+                });
+                self.input_mode = false;
+            }
+            Err(e) => {
+                let msg = format!("Error: Failed to load history: {}\n", e);
+                self.terminal_mut().feed_bytes(msg.as_bytes());
+            }
+        }
+    }
+
+    /// Close the history view and any replayed build
+    pub fn close_history(&mut self) {
+        self.history_view = None;
+        self.replay = None;
+        self.output_scroll = 0;
+    }
+
+    /// Whether the history list (rather than a replay) is showing
+    pub fn is_browsing_history(&self) -> bool {
+        self.history_view.is_some() && self.replay.is_none()
+    }
+
+    /// Move the history selection up
+    pub fn history_select_prev(&mut self) {
+        if let Some(ref mut view) = self.history_view {
+            view.selected = view.selected.saturating_sub(1);
+        }
+    }
+
+    /// Move the history selection down
+    pub fn history_select_next(&mut self) {
+        if let Some(ref mut view) = self.history_view {
+            if view.selected + 1 < view.records.len() {
+                view.selected += 1;
+            }
+        }
+    }
+
+    /// Replay the output of the selected history entry
+    pub fn open_history_entry(&mut self) {
+        let Some(record) = self
+            .history_view
+            .as_ref()
+            .and_then(|view| view.records.get(view.selected))
+            .cloned()
+        else {
+            return;
+        };
+
+        let mut terminal =
+            VirtualTerminal::new(self.terminal_cols as usize, self.terminal_rows as usize);
+        match history::read_log(&record) {
+            Ok(bytes) => terminal.feed_bytes(&bytes),
+            Err(e) => terminal.feed_bytes(format!("Error: {}\n", e).as_bytes()),
+        }
+        let mut diagnostics = DiagnosticsCache::default();
+        diagnostics.refresh(&terminal);
+        self.replay = Some(Replay {
+            record,
+            terminal,
+            diagnostics,
+        });
+        self.output_scroll = 0;
+    }
+
+    /// Leave the replayed build and return to the history list
+    pub fn close_replay(&mut self) {
+        self.replay = None;
+        self.output_scroll = 0;
+    }
+
+    /// Open or close the system generations of the selected host
+    /// The listing runs in the background; the view shows it once it arrives
+    pub fn toggle_generations(&mut self) {
+        if self.generations_view.is_some() {
+            self.close_generations();
+            return;
+        }
+
+        let Some((host, connection)) = self.get_selected_host() else {
+            return;
+        };
+        if !connection.is_configured() {
+            self.status_message = Some(format!("{} has no connection configured", host));
+            return;
+        }
+
+        self.close_history();
+        self.inputs_view = None;
+        self.input_mode = false;
+        self.generations_view = Some(GenerationsView {
+            host,
+            generations: Vec::new(),
+            selected: 0,
+            loading: Some(BackgroundTask::spawn(move || {
+                generations::list_generations(&connection)
+            })),
+            error: None,
+        });
+    }
+
+    /// Close the generations view
+    pub fn close_generations(&mut self) {
+        self.generations_view = None;
+        self.output_scroll = 0;
+    }
+
+    /// Whether the generations list is showing
+    pub fn is_browsing_generations(&self) -> bool {
+        self.generations_view.is_some()
+    }
+
+    /// Move the generation selection up
+    pub fn generations_select_prev(&mut self) {
+        if let Some(ref mut view) = self.generations_view {
+            view.selected = view.selected.saturating_sub(1);
+        }
+    }
+
+    /// Move the generation selection down
+    pub fn generations_select_next(&mut self) {
+        if let Some(ref mut view) = self.generations_view {
+            if view.selected + 1 < view.generations.len() {
+                view.selected += 1;
+            }
+        }
+    }
+
+    /// Pick up the generation listing once it has finished
+    fn poll_generations(&mut self) {
+        let Some(ref mut view) = self.generations_view else {
+            return;
+        };
+        let Some(result) = view.loading.as_ref().and_then(|task| task.try_take()) else {
+            return;
+        };
+
+        view.loading = None;
+        match result {
+            Ok(generations) => {
+                view.selected = generations.iter().position(|g| g.current).unwrap_or(0);
+                view.generations = generations;
+            }
+            Err(e) => view.error = Some(e.to_string()),
+        }
+    }
+
+    /// Ask to roll the generations view's host back to its previous generation
+    pub fn request_rollback(&mut self) {
+        let Some(ref view) = self.generations_view else {
+            return;
+        };
+        self.confirmation = Some(Confirmation {
+            prompt: format!(
+                "Roll {} back to the previous generation (switch --rollback)?",
+                view.host
+            ),
+            action: PendingAction::Rollback {
+                host: view.host.clone(),
+            },
+        });
+    }
+
+    /// Ask to switch the generations view's host to the selected generation
+    pub fn request_switch_generation(&mut self) {
+        let Some(ref view) = self.generations_view else {
+            return;
+        };
+        let Some(generation) = view.generations.get(view.selected) else {
+            return;
+        };
+        if generation.current {
+            self.status_message = Some(format!(
+                "Generation {} is already current on {}",
+                generation.number, view.host
+            ));
+            return;
+        }
+
+        self.confirmation = Some(Confirmation {
+            prompt: format!("Switch {} to generation {}?", view.host, generation.number),
+            action: PendingAction::SwitchGeneration {
+                host: view.host.clone(),
+                number: generation.number,
+            },
+        });
+    }
+
+    /// Run the pending action
+    pub async fn confirm(&mut self) -> Result<()> {
+        let Some(confirmation) = self.confirmation.take() else {
+            return Ok(());
+        };
+
+        let host = match confirmation.action {
+            PendingAction::Rollback { host } => {
+                self.queue_rollback(&host);
+                Some(host)
+            }
+            PendingAction::SwitchGeneration { host, number } => {
+                self.queue_generation_switch(&host, number);
+                Some(host)
+            }
+            PendingAction::DirtyDeploy { reviewed: false } => {
+                self.queue_target_rebuilds();
+                None
+            }
+            PendingAction::DirtyDeploy { reviewed: true } => {
+                self.queue_target_reviews()?;
+                None
+            }
+        };
+
+        // Show the activation output rather than the stale listing
+        if self
+            .generations_view
+            .as_ref()
+            .is_some_and(|view| host.as_ref() == Some(&view.host))
+        {
+            self.close_generations();
+        }
+        self.start_queued_builds().await;
+        Ok(())
+    }
+
+    /// Drop the pending action without running it
+    pub fn dismiss_confirmation(&mut self) {
+        self.confirmation = None;
+    }
+
+    /// Toggle focus between panels
+    pub fn toggle_panel(&mut self) {
+        self.focused_panel = match self.focused_panel {
+            FocusedPanel::Main => FocusedPanel::Settings,
+            FocusedPanel::Settings => FocusedPanel::Main,
+        };
+    }
+
+    /// Cycle to next rebuild operation
+    pub fn next_operation(&mut self) {
+        self.selected_operation = self.selected_operation.next();
+        self.quit_warned = false;
+    }
+
+    /// Cycle to previous rebuild operation
+    pub fn prev_operation(&mut self) {
+        self.selected_operation = self.selected_operation.prev();
+        self.quit_warned = false;
+    }
+
+    /// Toggle the --upgrade flag
+    pub fn toggle_upgrade(&mut self) {
+        self.use_upgrade = !self.use_upgrade;
+        self.quit_warned = false;
+    }
+
+    /// Toggle input mode for the selected host's PTY
+    pub fn toggle_input_mode(&mut self) {
+        if self
+            .selected_session()
+            .is_some_and(|s| s.is_running() && s.input_tx.is_some())
+        {
+            self.input_mode = !self.input_mode;
+        }
+    }
+
+    /// Send input to the selected host's PTY
+    pub fn send_input(&mut self, data: Vec<u8>) {
+        if let Some(session) = self.selected_session() {
+            session.send_input(data);
+        }
+    }
+
+    /// Cancel the selected host's build
+    /// Calling it again while cancelling escalates to the next signal
+    pub fn cancel_build(&mut self) {
+        self.input_mode = false;
+        self.quit_warned = false;
+
+        if let Some(name) = self.shown_session_name() {
+            self.build_queue.retain(|queued| *queued != name);
+            if let Some(session) = self.sessions.get_mut(&name) {
+                session.cancel();
+            }
+        }
+    }
+
+    /// Whether the selected host's build is being cancelled
+    pub fn is_cancelling(&self) -> bool {
+        self.selected_session()
+            .is_some_and(|s| s.cancel_state.is_some())
+    }
+
+    /// Kill every running build before exiting, blocking until they are gone
+    pub fn shutdown(&mut self) {
+        self.build_queue.clear();
+        let processes: Vec<_> = self
+            .sessions
+            .values_mut()
+            .filter_map(|s| s.abandon())
+            .collect();
+        ProcessHandle::terminate_all(&processes, QUIT_GRACE);
+
+        let deadline = Instant::now() + SAVE_GRACE;
+        while self.sessions.values().any(|s| s.is_saving()) && Instant::now() < deadline {
+            for session in self.sessions.values_mut() {
+                session.take_save_errors();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Attempt to quit - returns true if should quit, false if should warn
+    pub fn attempt_quit(&mut self) -> bool {
+        if self.quit_warned {
+            // Second press - quit (and kill any running builds)
+            self.shutdown();
+            true
+        } else {
+            // First press - warn user
+            self.quit_warned = true;
+            if self.is_building() {
+                let msg = format!(
+                    "\n⚠ {} build(s) in progress! Press 'q' again to kill them and quit, or Esc to cancel this host's build.\n",
+                    self.sessions.values().filter(|s| s.is_active()).count()
+                );
+                self.terminal_mut().feed_bytes(msg.as_bytes());
+            }
+            false
+        }
+    }
+
+    /// Hosts an action applies to: the selected group's hosts, the marked
+    /// hosts, or the selected host if none are marked
+    fn target_hosts(&self) -> Vec<String> {
+        if let Some(group) = self.selected_group() {
+            self.group_hosts(&group)
+        } else if self.marked_hosts.is_empty() {
+            self.get_selected_host()
+                .map(|(name, _)| vec![name])
+                .unwrap_or_default()
+        } else {
+            self.marked_hosts.iter().cloned().collect()
+        }
+    }
+
+    /// Queue a rebuild for the marked hosts, or the selected host if none are
+    /// marked, then start as many as the concurrency limit allows
+    pub async fn start_rebuild_async(&mut self) -> Result<()> {
+        self.quit_warned = false;
+
+        if self.refresh_git_ref(false) {
+            return Ok(());
+        }
+        if self.selected_operation.activates() && self.confirm_dirty_deploy(false) {
+            return Ok(());
+        }
+        self.queue_target_rebuilds();
+        self.start_queued_builds().await;
+        Ok(())
+    }
+
+    /// Queue the selected operation for the selected group or target hosts
+    fn queue_target_rebuilds(&mut self) {
+        if let Some(group) = self.selected_group() {
+            self.start_group_run(&group);
+            return;
+        }
+
+        for host in self.target_hosts() {
+            self.queue_rebuild(&host, self.selected_operation);
+        }
+    }
+
+    /// Resolve the chosen git ref again in the background, so a branch
+    /// deploys its latest commit, and deploy once it has
+    /// Returns whether the deploy waits on it
+    fn refresh_git_ref(&mut self, reviewed: bool) -> bool {
+        // A ref still resolving, e.g. just chosen, deploys once it has
+        if let Some((ref mut then, _)) = self.git_ref_task {
+            *then = AfterGitRef::Deploy { reviewed };
+            return true;
+        }
+        let (Some(git_ref), Some(flake_path)) = (&self.git_ref, &self.config.flake_path) else {
+            return false;
+        };
+        let (flake_path, name) = (flake_path.clone(), git_ref.name.clone());
+        self.resolve_git_ref(flake_path, name, AfterGitRef::Deploy { reviewed });
+        true
+    }
+
+    /// Resolve a git ref of the flake's repository in the background
+    fn resolve_git_ref(&mut self, flake_path: String, name: String, then: AfterGitRef) {
+        self.status_message = Some(format!("Resolving {} ...", name));
+        self.git_ref_task = Some((
+            then,
+            BackgroundTask::spawn(move || git::resolve_ref(&flake_path, &name)),
+        ));
+    }
+
+    /// Take a resolved git ref and carry on with what was waiting on it
+    /// A ref that no longer resolves stops a deploy, with the reason shown
+    fn poll_git_ref(&mut self) {
+        let Some(result) = self
+            .git_ref_task
+            .as_ref()
+            .and_then(|(_, task)| task.try_take())
+        else {
+            return;
+        };
+        let Some((then, _)) = self.git_ref_task.take() else {
+            return;
+        };
+        let git_ref = match result {
+            Ok(git_ref) => git_ref,
+            Err(e) => {
+                self.status_message = Some(e.to_string());
+                return;
+            }
+        };
+
+        self.status_message = None;
+        let description = git_ref.describe();
+        self.git_ref = Some(git_ref);
+        match then {
+            AfterGitRef::Select => {
+                self.status_message = Some(format!(
+                    "Deploying {} instead of the working tree",
+                    description
+                ));
+            }
+            AfterGitRef::Deploy { reviewed: false } => self.queue_target_rebuilds(),
+            AfterGitRef::Deploy { reviewed: true } => {
+                if let Err(e) = self.queue_target_reviews() {
+                    self.status_message = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Ask before deploying from a flake checkout with uncommitted changes,
+    /// since the result wouldn't match any commit
+    /// Returns whether a confirmation is now pending
+    fn confirm_dirty_deploy(&mut self, reviewed: bool) -> bool {
+        // A git ref is fetched from the repository, not the working tree
+        if self.git_ref.is_some() {
+            return false;
+        }
+        // Uses the status polled in the background, which can be a few
+        // seconds old, rather than holding up the UI on git
+        let Some(ref status) = self.git_status else {
+            return false;
+        };
+        if !status.is_dirty() {
+            if !status.untracked.is_empty() {
+                self.status_message = Some(format!(
+                    "{} untracked file{} in the flake checkout won't be deployed",
+                    status.untracked.len(),
+                    if status.untracked.len() == 1 { "" } else { "s" }
+                ));
+            }
+            return false;
         }
+
+        self.confirmation = Some(Confirmation {
+            prompt: format!(
+                "The flake checkout ({}) has {} uncommitted change{}. Deploy it anyway?",
+                status.describe_head(),
+                status.changed,
+                if status.changed == 1 { "" } else { "s" }
+            ),
+            action: PendingAction::DirtyDeploy { reviewed },
+        });
+        true
     }
 
-    /// Scroll output down
-    pub fn scroll_output_down(&mut self) {
-        if self.output_scroll > 0 {
-            self.output_scroll -= 1;
+    /// Run the selected operation against every configured host of a group
+    fn start_group_run(&mut self, group: &str) {
+        if self
+            .group_runs
+            .get(group)
+            .is_some_and(|run| run.summary.is_none())
+        {
+            self.status_message = Some(format!("{} is already running", group));
+            return;
+        }
+        let hosts = self.group_hosts(group);
+        if hosts.is_empty() {
+            self.status_message = Some(format!("{} has no configured hosts", group));
+            return;
         }
-    }
 
-    /// Get list of hosts as (name, connection) tuples, sorted by name
-    pub fn get_hosts(&self) -> Vec<(String, Connection)> {
-        let mut hosts: Vec<_> = self
-            .config
-            .hosts
+        let started = if self.group_parallel { hosts.len() } else { 1 };
+        let not_queued = hosts[..started]
             .iter()
-            .map(|(name, config)| (name.clone(), config.connection.clone()))
+            .filter(|host| !self.queue_rebuild(host, self.selected_operation))
+            .cloned()
             .collect();
-        hosts.sort_by(|a, b| a.0.cmp(&b.0));
-        hosts
+        self.group_runs.insert(
+            group.to_string(),
+            GroupRun {
+                operation: self.selected_operation,
+                parallel: self.group_parallel,
+                hosts,
+                started,
+                not_queued,
+                summary: None,
+            },
+        );
     }
 
-    /// Get currently selected host name and connection
-    pub fn get_selected_host(&self) -> Option<(String, Connection)> {
-        let hosts = self.get_hosts();
-        hosts.get(self.selected_host_idx).cloned()
-    }
+    /// Start the next host of sequential group runs, and summarize the runs
+    /// whose hosts have all finished
+    fn poll_group_runs(&mut self) {
+        let groups: Vec<String> = self.group_runs.keys().cloned().collect();
+        for group in groups {
+            let Some(run) = self.group_runs.get(&group) else {
+                continue;
+            };
+            let started = &run.hosts[..run.started];
+            if run.summary.is_some() || started.iter().any(|h| self.is_host_active(h)) {
+                continue;
+            }
 
-    /// Move selection up in host list
-    pub fn select_prev_host(&mut self) {
-        if self.selected_host_idx > 0 {
-            self.selected_host_idx -= 1;
+            // A host that wasn't queued still shows how it last ended
+            let statuses: Vec<BuildStatus> = started
+                .iter()
+                .filter(|h| !run.not_queued.contains(h))
+                .map(|h| self.host_status(h))
+                .collect();
+            let all_succeeded =
+                run.not_queued.is_empty() && statuses.iter().all(|s| *s == BuildStatus::Succeeded);
+            if all_succeeded && run.started < run.hosts.len() {
+                let (host, operation) = (run.hosts[run.started].clone(), run.operation);
+                let queued = self.queue_rebuild(&host, operation);
+                if let Some(run) = self.group_runs.get_mut(&group) {
+                    run.started += 1;
+                    if !queued {
+                        run.not_queued.push(host);
+                    }
+                }
+                continue;
+            }
+
+            let count = |status| statuses.iter().filter(|s| **s == status).count();
+            let succeeded = count(BuildStatus::Succeeded);
+            let cancelled = count(BuildStatus::Cancelled);
+            let mut parts = vec![format!("{} succeeded", succeeded)];
+            let failed = statuses.len() - succeeded - cancelled;
+            if failed > 0 {
+                parts.push(format!("{} failed", failed));
+            }
+            if cancelled > 0 {
+                parts.push(format!("{} cancelled", cancelled));
+            }
+            if !run.not_queued.is_empty() {
+                parts.push(format!("{} not started", run.not_queued.len()));
+            }
+            let skipped = run.hosts.len() - run.started;
+            if skipped > 0 {
+                parts.push(format!("{} skipped", skipped));
+            }
+            let summary = format!(
+                "{}: {} {} finished, {}",
+                group,
+                run.operation.as_str(),
+                if run.parallel {
+                    "in parallel"
+                } else {
+                    "in sequence"
+                },
+                parts.join(", ")
+            );
+
+            self.status_message = Some(summary.clone());
+            if let Some(run) = self.group_runs.get_mut(&group) {
+                run.summary = Some(summary);
+            }
         }
+    }
+
+    /// Build the target hosts for review; each is diffed against its running
+    /// system once built and waits for a switch or boot
+    pub async fn start_reviewed_deploy_async(&mut self) -> Result<()> {
         self.quit_warned = false;
+
+        if self.refresh_git_ref(true) || self.confirm_dirty_deploy(true) {
+            return Ok(());
+        }
+        self.queue_target_reviews()?;
+        self.start_queued_builds().await;
+        Ok(())
     }
 
-    /// Move selection down in host list
-    pub fn select_next_host(&mut self) {
-        let hosts = self.get_hosts();
-        if self.selected_host_idx < hosts.len().saturating_sub(1) {
-            self.selected_host_idx += 1;
+    /// Queue the build stage of a reviewed deploy for each target host
+    fn queue_target_reviews(&mut self) -> Result<()> {
+        for host in self.target_hosts() {
+            self.queue_review_build(&host)?;
         }
-        self.quit_warned = false;
+        Ok(())
     }
 
-    /// Toggle focus between panels
-    pub fn toggle_panel(&mut self) {
-        self.focused_panel = match self.focused_panel {
-            FocusedPanel::Main => FocusedPanel::Settings,
-            FocusedPanel::Settings => FocusedPanel::Main,
+    /// Queue the build stage of a reviewed deploy
+    fn queue_review_build(&mut self, config_name: &str) -> Result<()> {
+        if self.is_host_active(config_name) {
+            return Ok(());
+        }
+
+        let Some(mut cmd) = RebuildCommand::for_host(
+            &self.config,
+            config_name,
+            RebuildOperation::Build,
+            self.use_upgrade,
+            self.terminal_cols,
+            self.terminal_rows,
+        ) else {
+            self.session_mut(config_name)
+                .terminal
+                .feed_bytes(b"Error: Host is not configured\n");
+            return Ok(());
         };
-    }
+        cmd.git_ref = self.git_ref.clone();
 
-    /// Cycle to next rebuild operation
-    pub fn next_operation(&mut self) {
-        self.selected_operation = self.selected_operation.next();
-        self.quit_warned = false;
-    }
+        // nixos-rebuild build leaves its result link in the working directory
+        let build_dir = history::builds_dir()?.join(config_name);
+        fs::create_dir_all(&build_dir).wrap_err("Failed to create build directory")?;
+        let _ = fs::remove_file(build_dir.join("result"));
 
-    /// Cycle to previous rebuild operation
-    pub fn prev_operation(&mut self) {
-        self.selected_operation = self.selected_operation.prev();
-        self.quit_warned = false;
+        let mut pty = cmd.pty_command()?;
+        pty.cwd = Some(build_dir.clone());
+        let banner = format!(
+            "Building {} ({}){} for review{}{} ...\n",
+            config_name,
+            cmd.connection.display(),
+            cmd.git_ref_note(),
+            cmd.build_host_note(),
+            if self.use_upgrade {
+                " with --upgrade"
+            } else {
+                ""
+            }
+        );
+        self.queue_command(config_name, pty, cmd.run_info(), &banner);
+        self.reviews.insert(
+            config_name.to_string(),
+            DeployReview {
+                build_dir,
+                toplevel: None,
+                upgrade: self.use_upgrade,
+                git_ref: cmd.git_ref.clone(),
+                stage: ReviewStage::Building,
+                scroll: 0,
+            },
+        );
+        Ok(())
     }
 
-    /// Toggle the --upgrade flag
-    pub fn toggle_upgrade(&mut self) {
-        self.use_upgrade = !self.use_upgrade;
-        self.quit_warned = false;
-    }
+    /// Advance reviewed deploys whose build or diff has finished
+    fn poll_reviews(&mut self) {
+        let hosts: Vec<String> = self.reviews.keys().cloned().collect();
+        for host in hosts {
+            let status = self.host_status(&host);
+            let connection = self.config.hosts.get(&host).map(|h| h.connection.clone());
+            let Some(review) = self.reviews.get_mut(&host) else {
+                continue;
+            };
 
-    /// Toggle input mode for PTY
-    pub fn toggle_input_mode(&mut self) {
-        if self.is_building && self.input_sender.is_some() {
-            self.input_mode = !self.input_mode;
+            match review.stage {
+                ReviewStage::Building => match status {
+                    BuildStatus::Queued | BuildStatus::Running => {}
+                    BuildStatus::Succeeded => {
+                        let result = fs::read_link(review.build_dir.join("result"));
+                        match (result, connection) {
+                            (Ok(path), Some(connection)) => {
+                                let toplevel = path.to_string_lossy().into_owned();
+                                review.toplevel = Some(toplevel.clone());
+                                review.stage =
+                                    ReviewStage::Diffing(BackgroundTask::spawn(move || {
+                                        closure::diff_against_current(&connection, &toplevel)
+                                    }));
+                            }
+                            _ => {
+                                review.stage = ReviewStage::Failed(
+                                    "The build left no result link to review".to_string(),
+                                );
+                            }
+                        }
+                    }
+                    // The build output already says why; there is nothing to review
+                    _ => {
+                        self.reviews.remove(&host);
+                    }
+                },
+                ReviewStage::Diffing(ref task) => {
+                    if let Some(result) = task.try_take() {
+                        review.stage = match result {
+                            Ok(diff) => ReviewStage::Ready(diff),
+                            Err(e) => ReviewStage::Failed(e.to_string()),
+                        };
+                    }
+                }
+                ReviewStage::Ready(_) | ReviewStage::Failed(_) => {}
+            }
         }
     }
 
-    /// Send input to the PTY
-    pub fn send_input(&mut self, data: Vec<u8>) {
-        if let Some(ref tx) = self.input_sender {
-            let _ = tx.try_send(data);
-        }
+    /// Review of the selected host, once its build has finished
+    pub fn selected_review(&self) -> Option<&DeployReview> {
+        let (name, _) = self.get_selected_host()?;
+        self.reviews
+            .get(&name)
+            .filter(|review| !matches!(review.stage, ReviewStage::Building))
     }
 
-    /// Cancel the current build
-    pub fn cancel_build(&mut self) {
-        if self.is_building {
-            self.is_building = false;
-            self.output_receiver = None;
-            self.input_sender = None;
-            self.input_mode = false;
-            let msg = "\n✓ Build cancelled by user\n";
-            self.terminal.feed_bytes(msg.as_bytes());
-            self.quit_warned = false;
-        }
+    /// Whether the selected host's review is showing
+    pub fn is_reviewing(&self) -> bool {
+        self.selected_review().is_some()
     }
 
-    /// Attempt to quit - returns true if should quit, false if should warn
-    pub fn attempt_quit(&mut self) -> bool {
-        if self.quit_warned {
-            // Second press - quit (and cancel build if running)
-            if self.is_building {
-                self.cancel_build();
-            }
-            true
-        } else {
-            // First press - warn user
-            self.quit_warned = true;
-            if self.is_building {
-                let msg = "\n⚠ Build in progress! Press 'q' again to cancel and quit, or Esc to cancel build.\n";
-                self.terminal.feed_bytes(msg.as_bytes());
-            }
-            false
+    /// Scroll the selected host's review
+    pub fn scroll_review(&mut self, down: bool) {
+        let Some((name, _)) = self.get_selected_host() else {
+            return;
+        };
+        if let Some(review) = self.reviews.get_mut(&name) {
+            review.scroll = if down {
+                review.scroll.saturating_add(1)
+            } else {
+                review.scroll.saturating_sub(1)
+            };
         }
     }
 
-    /// Start a rebuild for the currently selected host (async streaming version)
-    pub async fn start_rebuild_async(&mut self) -> Result<()> {
-        self.quit_warned = false;
-        use crate::nix::RebuildCommand;
-
-        if self.is_building {
-            return Ok(()); // Already building
+    /// Drop the selected host's review without activating anything
+    pub fn abort_review(&mut self) {
+        if let Some((name, _)) = self.get_selected_host() {
+            if self.reviews.remove(&name).is_some() {
+                self.status_message = Some(format!(
+                    "Deploy of {} aborted; the new system was not activated",
+                    name
+                ));
+            }
         }
+    }
 
-        let (config_name, connection) = match self.get_selected_host() {
-            Some(host) => host,
-            None => return Ok(()), // No host selected
+    /// Activate the reviewed build of the selected host with switch or boot
+    pub async fn activate_review(&mut self, operation: RebuildOperation) -> Result<()> {
+        let Some((name, connection)) = self.get_selected_host() else {
+            return Ok(());
+        };
+        let (toplevel, upgrade, git_ref) = match self.reviews.get(&name) {
+            Some(DeployReview {
+                stage: ReviewStage::Ready(_),
+                toplevel: Some(toplevel),
+                upgrade,
+                git_ref,
+                ..
+            }) => (toplevel.clone(), *upgrade, git_ref.clone()),
+            Some(_) => {
+                self.status_message =
+                    Some("Nothing to activate until the diff is ready".to_string());
+                return Ok(());
+            }
+            None => return Ok(()),
         };
 
-        if !connection.is_configured() {
-            self.terminal.feed_bytes(b"Error: Host is not configured\n");
+        let Some(cmd) = generations::activate_command(
+            &connection,
+            &toplevel,
+            operation,
+            self.terminal_cols,
+            self.terminal_rows,
+        ) else {
             return Ok(());
+        };
+        let info = RunInfo {
+            host: name.clone(),
+            operation: operation.as_str().to_string(),
+            extra_args: Vec::new(),
+            upgrade,
+            flake_path: match git_ref {
+                Some(ref git_ref) => Some(git_ref.flake_ref()),
+                None => self.config.flake_path.clone(),
+            },
+            git_ref: git_ref.clone(),
+        };
+        let banner = format!(
+            "Activating reviewed build of {} ({}){} with {} ...\n{}\n",
+            name,
+            connection.display(),
+            git_ref
+                .map(|git_ref| format!(" at {}", git_ref.describe()))
+                .unwrap_or_default(),
+            operation.as_str(),
+            toplevel
+        );
+        self.queue_command(&name, cmd, info, &banner);
+        self.start_queued_builds().await;
+        Ok(())
+    }
+
+    /// Queue a rebuild of a single host
+    /// Returns whether it was queued
+    fn queue_rebuild(&mut self, config_name: &str, operation: RebuildOperation) -> bool {
+        if self.is_host_active(config_name) {
+            return false; // Already building
         }
 
-        self.is_building = true;
-        self.output_scroll = 0; // Reset scroll when starting new build
-        self.terminal.clear(); // Clear previous build output
+        let Some(mut cmd) = RebuildCommand::for_host(
+            &self.config,
+            config_name,
+            operation,
+            self.use_upgrade,
+            self.terminal_cols,
+            self.terminal_rows,
+        ) else {
+            self.session_mut(config_name)
+                .terminal
+                .feed_bytes(b"Error: Host is not configured\n");
+            return false;
+        };
+        cmd.git_ref = self.git_ref.clone();
 
         // Write initial message to terminal
-        let msg = format!(
-            "Starting {} for {} ({}){} ...\n",
-            self.selected_operation.as_str(),
+        let banner = format!(
+            "Starting {} for {} ({}){}{}{} ...\n",
+            operation.as_str(),
             config_name,
-            connection.display(),
+            cmd.connection.display(),
+            cmd.git_ref_note(),
+            cmd.build_host_note(),
             if self.use_upgrade {
                 " with --upgrade"
             } else {
                 ""
             }
         );
-        self.terminal.feed_bytes(msg.as_bytes());
 
-        // Get extra args for this host
-        let mut extra_args = self
-            .config
-            .hosts
-            .get(&config_name)
-            .map(|h| h.extra_args.clone())
-            .unwrap_or_default();
+        let pty = match cmd.pty_command() {
+            Ok(pty) => pty,
+            Err(e) => {
+                self.session_mut(config_name)
+                    .terminal
+                    .feed_bytes(format!("Error: {}\n", e).as_bytes());
+                return false;
+            }
+        };
+        self.queue_command(config_name, pty, cmd.run_info(), &banner);
+        true
+    }
 
-        // Add --upgrade if enabled
-        if self.use_upgrade {
-            extra_args.push("--upgrade".to_string());
+    /// Queue `switch --rollback` for a host
+    fn queue_rollback(&mut self, config_name: &str) {
+        if self.is_host_active(config_name) {
+            return;
         }
 
-        let cmd = RebuildCommand::new(
-            self.selected_operation,
-            self.config.flake_path.clone(),
+        let Some(mut cmd) = RebuildCommand::for_host(
+            &self.config,
             config_name,
-            connection,
-            extra_args,
+            RebuildOperation::Switch,
+            false,
             self.terminal_cols,
             self.terminal_rows,
+        ) else {
+            return;
+        };
+        // The previous generation is already built, so there is nothing to evaluate
+        cmd.flake_path = None;
+        cmd.extra_args.push("--rollback".to_string());
+
+        let banner = format!(
+            "Rolling back {} ({}) to the previous generation ...\n",
+            config_name,
+            cmd.connection.display()
         );
+        let pty = match cmd.pty_command() {
+            Ok(pty) => pty,
+            Err(e) => {
+                self.session_mut(config_name)
+                    .terminal
+                    .feed_bytes(format!("Error: {}\n", e).as_bytes());
+                return;
+            }
+        };
+        self.queue_command(config_name, pty, cmd.run_info(), &banner);
+    }
 
-        // Start async streaming with PTY
-        let channels = cmd.execute_streaming().await?;
-        self.output_receiver = Some(channels.output_rx);
-        self.input_sender = Some(channels.input_tx);
+    /// Queue activation of a specific system generation of a host
+    fn queue_generation_switch(&mut self, config_name: &str, number: u32) {
+        if self.is_host_active(config_name) {
+            return;
+        }
 
-        Ok(())
+        let Some(host_config) = self.config.hosts.get(config_name) else {
+            return;
+        };
+        let connection = host_config.connection.clone();
+        let Some(cmd) = generations::switch_generation_command(
+            &connection,
+            number,
+            self.terminal_cols,
+            self.terminal_rows,
+        ) else {
+            return;
+        };
+
+        let info = RunInfo {
+            host: config_name.to_string(),
+            operation: "switch-generation".to_string(),
+            extra_args: vec![number.to_string()],
+            upgrade: false,
+            flake_path: None,
+            git_ref: None,
+        };
+        let banner = format!(
+            "Switching {} ({}) to generation {} ...\n",
+            config_name,
+            connection.display(),
+            number
+        );
+        self.queue_command(config_name, cmd, info, &banner);
+    }
+
+    /// Whether a host has a build queued or running
+    fn is_host_active(&self, host: &str) -> bool {
+        self.sessions.get(host).is_some_and(|s| s.is_active())
+    }
+
+    /// Queue a command in a host's session and the build queue
+    fn queue_command(&mut self, config_name: &str, cmd: PtyCommand, info: RunInfo, banner: &str) {
+        // Whatever runs next supersedes a pending review
+        self.reviews.remove(config_name);
+        self.session_mut(config_name).queue(cmd, info, banner);
+        self.build_queue.push_back(config_name.to_string());
+
+        if self
+            .get_selected_host()
+            .is_some_and(|(name, _)| name == config_name)
+        {
+            self.output_scroll = 0; // Reset scroll when starting new build
+        }
+    }
+
+    /// Start queued builds while below the concurrency limit
+    /// A build that fails to start is marked failed without holding up the rest
+    pub async fn start_queued_builds(&mut self) {
+        let limit = self.config.max_parallel.max(1);
+        while self.running_count() < limit {
+            let Some(host) = self.build_queue.pop_front() else {
+                break;
+            };
+            let (cols, rows) = (self.terminal_cols, self.terminal_rows);
+            if let Some(session) = self.sessions.get_mut(&host) {
+                if session.status == BuildStatus::Queued {
+                    if let Err(e) = session.start(cols, rows).await {
+                        self.status_message = Some(format!("{}: {}", host, e));
+                    }
+                }
+            }
+        }
     }
 
-    /// Poll for new output from the rebuild process
+    /// Poll every session for new output from its rebuild process
     pub fn poll_output(&mut self) {
-        if let Some(ref mut rx) = self.output_receiver {
-            let mut bytes_received = false;
-            // Try to receive all available messages without blocking
-            while let Ok(bytes) = rx.try_recv() {
-                // Feed bytes to terminal
-                self.terminal.feed_bytes(&bytes);
-                bytes_received = true;
-
-                // Check if build finished (simple byte pattern matching)
-                let text = String::from_utf8_lossy(&bytes);
-                if text.contains("Build completed successfully!")
-                    || text.contains("Build failed with exit code")
-                    || text.contains("Process error:")
-                {
-                    self.is_building = false;
-                    self.output_receiver = None;
-                    self.input_sender = None;
-                    self.input_mode = false;
-                    self.quit_warned = false;
-                    break;
+        let mut activated = Vec::new();
+        for (host, session) in self.sessions.iter_mut() {
+            let finished = session.poll();
+            session.diagnostics.refresh(&session.terminal);
+            for error in session.take_save_errors() {
+                self.status_message = Some(format!(
+                    "⚠ Failed to save build history for {}: {}",
+                    host, error
+                ));
+            }
+
+            // What the host runs or boots into has changed
+            let operation = session.run.as_ref().map(|run| run.operation.as_str());
+            if finished
+                && session.status == BuildStatus::Succeeded
+                && matches!(
+                    operation,
+                    Some("switch" | "boot" | "test" | "switch-generation")
+                )
+            {
+                activated.push(host.clone());
+            }
+        }
+        for host in activated {
+            self.queue_drift_check(&host);
+            let connection = self.config.hosts.get(&host).map(|h| h.connection.clone());
+            if let (true, Some(connection)) = (self.host_facts.contains_key(&host), connection) {
+                self.gather_facts(&host, connection);
+            }
+        }
+        if let Some(ref mut replay) = self.replay {
+            replay.diagnostics.refresh(&replay.terminal);
+        }
+        self.poll_generations();
+        self.poll_reviews();
+        self.poll_group_runs();
+        self.poll_host_checks();
+        self.poll_facts();
+        self.poll_drift();
+        self.poll_inputs();
+        self.poll_git();
+        self.poll_git_ref();
+        self.refresh_search();
+
+        // The selected host's build may have ended while typing into it
+        if self.input_mode && !self.selected_is_building() {
+            self.input_mode = false;
+        }
+    }
+
+    /// Collect finished reachability checks and start those that are due
+    fn poll_host_checks(&mut self) {
+        let interval = self.config.check_interval_secs;
+        if interval == 0 {
+            return;
+        }
+
+        for (name, host) in &self.config.hosts {
+            if !host.connection.is_configured() {
+                self.host_checks.remove(name);
+                continue;
+            }
+            let check = self.host_checks.entry(name.clone()).or_default();
+            if let Some(result) = check.task.as_ref().and_then(|task| task.try_take()) {
+                check.result = Some(result);
+                check.checked_at = Some(Instant::now());
+                check.task = None;
+            }
+
+            let due = check
+                .checked_at
+                .is_none_or(|at| at.elapsed() >= Duration::from_secs(interval));
+            if check.task.is_none() && due {
+                let connection = host.connection.clone();
+                check.task = Some(BackgroundTask::spawn(move || {
+                    reachability::check(&connection)
+                }));
+            }
+        }
+    }
+
+    /// Collect the finished git status of the flake checkout and start the
+    /// next read when it is due
+    fn poll_git(&mut self) {
+        if let Some(result) = self.git_task.as_ref().and_then(|task| task.try_take()) {
+            self.git_status = result.ok().flatten();
+            self.git_checked_at = Some(Instant::now());
+            self.git_task = None;
+        }
+
+        let Some(flake_path) = self.config.flake_path.clone() else {
+            self.git_status = None;
+            return;
+        };
+        let due = self
+            .git_checked_at
+            .is_none_or(|at| at.elapsed() >= GIT_REFRESH);
+        if self.git_task.is_none() && due {
+            self.git_task = Some(BackgroundTask::spawn(move || {
+                git::status(&flake_path, true)
+            }));
+        }
+    }
+
+    /// Check every configured host for drift from the flake, in the background
+    pub fn refresh_drift(&mut self) {
+        if self.config.flake_path.is_none() {
+            self.status_message = Some("Set a flake path to check for drift".to_string());
+            return;
+        }
+        let hosts: Vec<String> = self
+            .get_hosts()
+            .into_iter()
+            .filter(|(_, connection)| connection.is_configured())
+            .map(|(name, _)| name)
+            .collect();
+        for host in hosts {
+            self.queue_drift_check(&host);
+        }
+    }
+
+    /// Queue a drift check of a host, unless one is already waiting
+    fn queue_drift_check(&mut self, host: &str) {
+        if self.config.flake_path.is_some() && !self.drift_queue.iter().any(|h| h == host) {
+            self.drift_queue.push_back(host.to_string());
+        }
+    }
+
+    /// Whether a host's drift check is queued or running
+    pub fn is_checking_drift(&self, host: &str) -> bool {
+        self.drift_task.as_ref().is_some_and(|(h, _)| h == host)
+            || self.drift_queue.iter().any(|h| h == host)
+    }
+
+    /// Collect the finished drift check and start the next one
+    fn poll_drift(&mut self) {
+        if let Some((host, task)) = self.drift_task.take() {
+            match task.try_take() {
+                Some(drift) => {
+                    self.drift.insert(host, drift);
+                }
+                None => {
+                    self.drift_task = Some((host, task));
+                    return;
                 }
             }
+        }
 
-            // Terminal handles scrollback internally, scroll position stays relative
-            if bytes_received && self.output_scroll > 0 {
-                // Keep scroll position stable - terminal manages this internally
+        while let Some(host) = self.drift_queue.pop_front() {
+            let connection = self.config.hosts.get(&host).map(|h| h.connection.clone());
+            let (Some(flake_path), Some(connection)) = (self.config.flake_path.clone(), connection)
+            else {
+                continue;
+            };
+            if !connection.is_configured() {
+                continue;
             }
+            let name = host.clone();
+            let task =
+                BackgroundTask::spawn(move || flake::check_drift(&flake_path, &name, &connection));
+            self.drift_task = Some((host, task));
+            break;
         }
     }
 
@@ -346,7 +2219,7 @@ impl App {
             self.edit_mode = EditMode::HostConnection;
             self.edit_buffer = match conn {
                 Connection::Local => "localhost".to_string(),
-                Connection::Remote(addr) => addr,
+                Connection::Remote(host) => host.target,
                 Connection::Unconfigured => String::new(),
             };
         }
@@ -362,6 +2235,30 @@ impl App {
         }
     }
 
+    /// Start choosing the git ref to deploy
+    pub fn start_edit_git_ref(&mut self) {
+        if self.config.flake_path.is_none() {
+            self.status_message = Some("Set a flake path to deploy a git ref".to_string());
+            return;
+        }
+        self.edit_mode = EditMode::GitRef;
+        self.edit_buffer = self
+            .git_ref
+            .as_ref()
+            .map(|git_ref| git_ref.name.clone())
+            .unwrap_or_default();
+    }
+
+    /// Start editing the build host of the selected host
+    pub fn start_edit_build_host(&mut self) {
+        if let Some((host_name, _)) = self.get_selected_host() {
+            self.edit_mode = EditMode::BuildHost;
+            if let Some(host_config) = self.config.hosts.get(&host_name) {
+                self.edit_buffer = host_config.build_host.clone().unwrap_or_default();
+            }
+        }
+    }
+
     /// Handle character input during edit mode
     pub fn edit_insert_char(&mut self, c: char) {
         self.edit_buffer.push(c);
@@ -395,21 +2292,38 @@ impl App {
                 // Rediscover configs if flake path changed
                 if flake_changed {
                     self.refresh_flake_configs()?;
+                    self.drift.clear();
+                    self.git_status = None;
+                    self.git_checked_at = None;
+                    self.git_task = None;
+                    self.git_ref = None;
+                    self.git_ref_task = None;
+                    if self.config.flake_path.is_some() {
+                        self.refresh_drift();
+                    }
                 }
             }
             EditMode::HostConnection => {
-                if let Some((host_name, _)) = self.get_selected_host() {
+                if let Some((host_name, connection)) = self.get_selected_host() {
                     let new_connection = if self.edit_buffer.is_empty() {
                         Connection::Unconfigured
                     } else if self.edit_buffer == "localhost" {
                         Connection::Local
+                    } else if let Connection::Remote(host) = connection {
+                        // Only the target is edited here; keep its ssh settings
+                        Connection::Remote(RemoteHost {
+                            target: self.edit_buffer.clone(),
+                            ..host
+                        })
                     } else {
-                        Connection::Remote(self.edit_buffer.clone())
+                        Connection::Remote(RemoteHost::new(&self.edit_buffer))
                     };
 
                     if let Some(host_config) = self.config.hosts.get_mut(&host_name) {
                         host_config.connection = new_connection;
                     }
+                    // The last check was of the old connection
+                    self.host_checks.remove(&host_name);
                     self.config.save()?;
                 }
             }
@@ -430,6 +2344,25 @@ impl App {
                     self.config.save()?;
                 }
             }
+            EditMode::BuildHost => {
+                if let Some((host_name, _)) = self.get_selected_host() {
+                    let build_host = self.edit_buffer.trim();
+                    if let Some(host_config) = self.config.hosts.get_mut(&host_name) {
+                        host_config.build_host =
+                            (!build_host.is_empty()).then(|| build_host.to_string());
+                    }
+                    self.config.save()?;
+                }
+            }
+            EditMode::GitRef => {
+                let name = self.edit_buffer.trim().to_string();
+                self.git_ref = None;
+                self.git_ref_task = None;
+                if let (false, Some(flake_path)) = (name.is_empty(), &self.config.flake_path) {
+                    let flake_path = flake_path.clone();
+                    self.resolve_git_ref(flake_path, name, AfterGitRef::Select);
+                }
+            }
             EditMode::None => {}
         }
 
@@ -459,3 +2392,112 @@ impl App {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HostConfig;
+
+    #[test]
+    fn test_grouped_host_rows() {
+        let mut config = Config::default();
+        for (name, tags) in [
+            ("web1", vec!["prod"]),
+            ("web2", vec!["prod", "edge"]),
+            ("lab", vec![]),
+        ] {
+            config.hosts.insert(
+                name.to_string(),
+                HostConfig {
+                    tags: tags.into_iter().map(String::from).collect(),
+                    ..HostConfig::local()
+                },
+            );
+        }
+        let mut app = App::new(config);
+        app.selected_host_idx = 1; // web1
+        app.toggle_grouped();
+
+        let group = |name: &str| HostRow::Group(name.to_string());
+        let host = |name: &str| HostRow::Host(name.to_string(), Connection::Local);
+        assert_eq!(
+            app.host_rows(),
+            vec![
+                group("edge"),
+                host("web2"),
+                group("prod"),
+                host("web1"),
+                host("web2"),
+                group(UNTAGGED),
+                host("lab"),
+            ]
+        );
+        assert_eq!(app.get_selected_host().unwrap().0, "web1");
+
+        app.selected_host_idx = 2;
+        assert_eq!(app.target_hosts(), vec!["web1", "web2"]);
+        app.toggle_group_collapsed();
+        assert_eq!(app.host_rows()[3], group(UNTAGGED));
+
+        app.toggle_grouped();
+        assert_eq!(app.host_rows().len(), 3);
+    }
+
+    #[test]
+    fn test_group_run_counts_busy_hosts_as_not_started() {
+        let mut config = Config::default();
+        for name in ["web1", "web2"] {
+            config.hosts.insert(
+                name.to_string(),
+                HostConfig {
+                    tags: vec!["prod".to_string()],
+                    ..HostConfig::local()
+                },
+            );
+        }
+        let mut app = App::new(config);
+        app.group_parallel = true;
+        assert!(app.queue_rebuild("web1", RebuildOperation::Build));
+
+        app.start_group_run("prod");
+        let run = &app.group_runs["prod"];
+        assert_eq!(run.not_queued, vec!["web1"]);
+        assert!(app.is_host_active("web2"));
+    }
+
+    #[test]
+    fn test_deploy_waits_for_a_ref_being_resolved() {
+        let mut config = Config {
+            flake_path: Some("/etc/nixos".to_string()),
+            ..Config::default()
+        };
+        config.hosts.insert("web".to_string(), HostConfig::local());
+        let mut app = App::new(config);
+        app.selected_operation = RebuildOperation::Build;
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        app.git_ref_task = Some((
+            AfterGitRef::Select,
+            BackgroundTask::spawn(move || {
+                let _ = rx.recv();
+                Ok(GitRef {
+                    name: "pr-42".to_string(),
+                    full_ref: Some("refs/heads/pr-42".to_string()),
+                    rev: "1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b".to_string(),
+                    repo: PathBuf::from("/etc/nixos"),
+                    subdir: String::new(),
+                })
+            }),
+        ));
+
+        // Deploying while the chosen ref resolves carries on once it has
+        assert!(app.refresh_git_ref(false));
+        assert!(!app.is_host_active("web"));
+        tx.send(()).unwrap();
+        while app.git_ref_task.is_some() {
+            app.poll_git_ref();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(app.git_ref.as_ref().unwrap().name, "pr-42");
+        assert!(app.is_host_active("web"));
+    }
+}
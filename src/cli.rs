@@ -0,0 +1,373 @@
+use crate::app::RebuildOperation;
+use crate::config::Config;
+use crate::git;
+use crate::history::BuildRecorder;
+use crate::nix::progress::ProgressParser;
+use crate::nix::rebuild::{BuildExit, ProcessHandle, RebuildEvent};
+use crate::nix::RebuildCommand;
+use crate::session::BuildStatus;
+use crate::util::format_duration;
+use color_eyre::{eyre::eyre, Result};
+use nix::sys::signal::Signal;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+/// Exit code for invalid command lines or unknown hosts
+pub const EXIT_USAGE: i32 = 2;
+
+/// Exit code when interrupted with Ctrl-C
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// PTY size used when stdout is not a terminal
+const DEFAULT_PTY_SIZE: (u16, u16) = (120, 40);
+
+/// How long Ctrl-C waits for builds to exit after SIGTERM before SIGKILL
+const INTERRUPT_GRACE: Duration = Duration::from_secs(2);
+
+/// How long Ctrl-C then waits for the killed builds to be recorded
+const RECORD_GRACE: Duration = Duration::from_secs(3);
+
+/// Options for `renix rebuild`
+#[derive(Debug)]
+pub struct RebuildArgs {
+    pub hosts: Vec<String>,
+    pub operation: RebuildOperation,
+    pub use_upgrade: bool,
+    pub parallel: Option<usize>,
+    /// Git ref of the flake's repository to deploy
+    pub git_ref: Option<String>,
+}
+
+impl RebuildArgs {
+    /// Parse the arguments following `renix rebuild`
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut hosts = Vec::new();
+        let mut operation = RebuildOperation::Switch;
+        let mut use_upgrade = false;
+        let mut parallel = None;
+        let mut git_ref = None;
+
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--op" => {
+                    let name = iter
+                        .next()
+                        .ok_or_else(|| eyre!("--op requires an operation"))?;
+                    operation = RebuildOperation::from_name(name)
+                        .ok_or_else(|| eyre!("Unknown operation: {}", name))?;
+                }
+                "--upgrade" => use_upgrade = true,
+                "--parallel" => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| eyre!("--parallel requires a number"))?;
+                    let n: usize = value
+                        .parse()
+                        .map_err(|_| eyre!("Invalid --parallel value: {}", value))?;
+                    parallel = Some(n.max(1));
+                }
+                "--ref" => {
+                    let value = iter
+                        .next()
+                        .ok_or_else(|| eyre!("--ref requires a git ref"))?;
+                    git_ref = Some(value.clone());
+                }
+                flag if flag.starts_with('-') => {
+                    return Err(eyre!("Unknown option: {}", flag));
+                }
+                host => hosts.push(host.to_string()),
+            }
+        }
+
+        if hosts.is_empty() {
+            return Err(eyre!("No hosts given"));
+        }
+
+        Ok(Self {
+            hosts,
+            operation,
+            use_upgrade,
+            parallel,
+            git_ref,
+        })
+    }
+}
+
+/// Run a headless subcommand, returning the process exit code
+pub async fn run(config: Config, args: &[String]) -> Result<i32> {
+    match args.first().map(String::as_str) {
+        Some("rebuild") => match RebuildArgs::parse(&args[1..]) {
+            Ok(rebuild_args) => rebuild(config, rebuild_args).await,
+            Err(e) => usage_error(&e.to_string()),
+        },
+        Some("hosts") => match args.get(1).map(String::as_str) {
+            Some("list") => {
+                list_hosts(&config);
+                Ok(0)
+            }
+            _ => usage_error("Expected 'renix hosts list'"),
+        },
+        _ => usage_error("Unknown command"),
+    }
+}
+
+fn usage_error(msg: &str) -> Result<i32> {
+    eprintln!("Error: {}", msg);
+    eprintln!("Try 'renix --help' for more information.");
+    Ok(EXIT_USAGE)
+}
+
+/// Print configured hosts, one per line: name, connection, tags, extra args
+fn list_hosts(config: &Config) {
+    let mut hosts: Vec<_> = config.hosts.iter().collect();
+    hosts.sort_by(|a, b| a.0.cmp(b.0));
+
+    let name_width = hosts.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, host) in hosts {
+        println!(
+            "{:<width$}  {:<24}  {:<16}  {}",
+            name,
+            host.connection.display(),
+            host.tags.join(","),
+            host.extra_args.join(" "),
+            width = name_width
+        );
+    }
+}
+
+/// Rebuild several hosts concurrently, streaming prefixed output to stdout
+async fn rebuild(config: Config, args: RebuildArgs) -> Result<i32> {
+    // Validate every host up front so nothing starts on a typo
+    for host in &args.hosts {
+        match config.hosts.get(host) {
+            None => return usage_error(&format!("Unknown host: {}", host)),
+            Some(h) if !h.connection.is_configured() => {
+                return usage_error(&format!("Host is not configured: {}", host))
+            }
+            Some(_) => {}
+        }
+    }
+
+    let git_ref = match (&args.git_ref, &config.flake_path) {
+        (None, _) => None,
+        (Some(_), None) => return usage_error("--ref needs a flake_path"),
+        (Some(name), Some(flake_path)) => match git::resolve_ref(flake_path, name) {
+            Ok(git_ref) => Some(git_ref),
+            Err(e) => return usage_error(&e.to_string()),
+        },
+    };
+
+    let (cols, rows) = crossterm::terminal::size().unwrap_or(DEFAULT_PTY_SIZE);
+    let name_width = args.hosts.iter().map(|h| h.len()).max().unwrap_or(0);
+    let parallel = args.parallel.unwrap_or(config.max_parallel).max(1);
+    let semaphore = Arc::new(Semaphore::new(parallel));
+    let processes: Arc<Mutex<Vec<ProcessHandle>>> = Arc::new(Mutex::new(Vec::new()));
+    let interrupted = Arc::new(AtomicBool::new(false));
+
+    let mut tasks = Vec::new();
+    for host in &args.hosts {
+        let mut cmd =
+            RebuildCommand::for_host(&config, host, args.operation, args.use_upgrade, cols, rows)
+                .ok_or_else(|| eyre!("Host is not configured: {}", host))?;
+        cmd.git_ref = git_ref.clone();
+        cmd.batch = true;
+        let prefix = format!("[{:<width$}] ", host, width = name_width);
+        let semaphore = semaphore.clone();
+        let processes = processes.clone();
+        let interrupted = interrupted.clone();
+
+        tasks.push(tokio::spawn(async move {
+            let _permit = semaphore.acquire_owned().await.ok()?;
+            run_host(cmd, &prefix, &processes, &interrupted).await
+        }));
+    }
+
+    let joined = join_in_order(tasks);
+    tokio::pin!(joined);
+    let results = tokio::select! {
+        results = &mut joined => results,
+        _ = tokio::signal::ctrl_c() => {
+            // Hosts still waiting for a slot never start
+            interrupted.store(true, Ordering::SeqCst);
+            semaphore.close();
+            let processes = processes.lock().unwrap().clone();
+            tokio::task::spawn_blocking(move || {
+                ProcessHandle::terminate_all(&processes, INTERRUPT_GRACE)
+            })
+            .await?;
+            // Let the killed builds record their exit in the history
+            let _ = tokio::time::timeout(RECORD_GRACE, joined).await;
+            eprintln!("Interrupted, builds killed");
+            return Ok(EXIT_INTERRUPTED);
+        }
+    };
+
+    // Summary, in the order the hosts were given
+    println!();
+    let mut all_succeeded = true;
+    for (host, result) in args.hosts.iter().zip(results) {
+        let line = match result {
+            Some(exit) => {
+                all_succeeded &= exit.success();
+                format!(
+                    "{} {} ({})",
+                    if exit.success() { "✓" } else { "✗" },
+                    exit.describe(),
+                    format_duration(exit.duration)
+                )
+            }
+            None => {
+                all_succeeded = false;
+                "✗ failed to start".to_string()
+            }
+        };
+        println!("{:<width$}  {}", host, line, width = name_width);
+    }
+
+    Ok(if all_succeeded { 0 } else { 1 })
+}
+
+/// Wait for every task, keeping their order
+async fn join_in_order(
+    tasks: Vec<tokio::task::JoinHandle<Option<BuildExit>>>,
+) -> Vec<Option<BuildExit>> {
+    let mut results = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        results.push(task.await.ok().flatten());
+    }
+    results
+}
+
+/// Run one host's rebuild, printing its output line by line with a prefix
+async fn run_host(
+    cmd: RebuildCommand,
+    prefix: &str,
+    processes: &Mutex<Vec<ProcessHandle>>,
+    interrupted: &AtomicBool,
+) -> Option<BuildExit> {
+    let mut recorder = BuildRecorder::start(&cmd.run_info());
+    let channels = match cmd.execute_streaming().await {
+        Ok(channels) => channels,
+        Err(e) => {
+            print_line(prefix, &format!("✗ {}", e));
+            let _ = record(recorder, BuildStatus::Failed, None).await;
+            return None;
+        }
+    };
+    if let Some(process) = channels.process {
+        // Ctrl-C may have come while this host was starting
+        if interrupted.load(Ordering::SeqCst) {
+            process.signal(Signal::SIGTERM);
+        }
+        processes.lock().unwrap().push(process);
+    }
+    // Nobody can answer prompts here. ssh runs in batch mode and sudo with -n
+    // so they fail rather than ask; a prompt with echo off would ignore the
+    // EOF that closing input sends
+    drop(channels.input_tx);
+
+    let mut rx = channels.event_rx;
+    let mut progress = ProgressParser::default();
+    let mut pending = Vec::new();
+    while let Some(event) = rx.recv().await {
+        match event {
+            RebuildEvent::Started { argv } => {
+                print_line(prefix, &format!("$ {}", argv.join(" ")));
+            }
+            RebuildEvent::Output(bytes) => {
+                let bytes = progress.feed(&bytes);
+                recorder.write_output(&bytes);
+                pending.extend_from_slice(&bytes);
+                while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=pos).collect();
+                    print_line(prefix, &visible_line(&line));
+                }
+            }
+            RebuildEvent::Exited(exit) => {
+                let rest = progress.finish();
+                recorder.write_output(&rest);
+                pending.extend(rest);
+                if !pending.is_empty() {
+                    print_line(prefix, &visible_line(&pending));
+                }
+                print_line(
+                    prefix,
+                    &format!(
+                        "{} {} ({})",
+                        if exit.success() { "✓" } else { "✗" },
+                        exit.describe(),
+                        format_duration(exit.duration)
+                    ),
+                );
+                let status = if interrupted.load(Ordering::SeqCst) {
+                    BuildStatus::Cancelled
+                } else if exit.success() {
+                    BuildStatus::Succeeded
+                } else {
+                    BuildStatus::Failed
+                };
+                if let Err(e) = record(recorder, status, Some(exit.clone())).await {
+                    print_line(prefix, &format!("⚠ Failed to save build history: {}", e));
+                }
+                return Some(exit);
+            }
+            RebuildEvent::SpawnFailed { error } => {
+                print_line(prefix, &format!("✗ {}", error));
+                let _ = record(recorder, BuildStatus::Failed, None).await;
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Append a finished build to the history, which can wait on its flake
+/// revision, without holding up the other hosts
+async fn record(
+    recorder: BuildRecorder,
+    status: BuildStatus,
+    exit: Option<BuildExit>,
+) -> Result<()> {
+    tokio::task::spawn_blocking(move || recorder.finish(status, exit.as_ref())).await??;
+    Ok(())
+}
+
+/// Text of a raw output line as a terminal would leave it: carriage returns
+/// overwrite the line, so only what follows the last one is kept
+fn visible_line(bytes: &[u8]) -> String {
+    let text = String::from_utf8_lossy(bytes);
+    let text = text.trim_end_matches(['\n', '\r']);
+    text.rsplit('\r').next().unwrap_or("").to_string()
+}
+
+fn print_line(prefix: &str, line: &str) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}{}", prefix, line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RebuildArgs> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        RebuildArgs::parse(&args)
+    }
+
+    #[test]
+    fn test_parse_rebuild_args() {
+        let args = parse(&["web1", "--op", "boot", "web2", "--parallel", "0"]).unwrap();
+        assert_eq!(args.hosts, vec!["web1", "web2"]);
+        assert_eq!(args.operation, RebuildOperation::Boot);
+        assert_eq!(args.parallel, Some(1));
+
+        let error = |args: &[&str]| parse(args).unwrap_err().to_string();
+        assert_eq!(error(&["web1", "--op"]), "--op requires an operation");
+        assert_eq!(error(&["web1", "--force"]), "Unknown option: --force");
+        assert_eq!(error(&["--upgrade"]), "No hosts given");
+    }
+}
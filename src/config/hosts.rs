@@ -1,17 +1,144 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{
+    de::{self, IgnoredAny},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 
 /// Connection info for a host
 /// Can be:
-/// - Some("localhost") for local rebuilds
-/// - Some("user@host") or Some("host") for remote rebuilds
-/// - None for unconfigured hosts (serialized as empty array [])
+/// - "localhost" for local rebuilds
+/// - "user@host" or "host" for remote rebuilds, or a table with the target
+///   and its ssh settings (see `RemoteHost`)
+/// - Unconfigured, serialized as an empty array []
 #[derive(Debug, Clone, PartialEq)]
 pub enum Connection {
     Local,
-    Remote(String),
+    Remote(RemoteHost),
     Unconfigured,
 }
 
+/// Where the control sockets of shared ssh connections live
+const CONTROL_PATH: &str = "~/.ssh/renix-%C";
+
+/// A remote host and how to reach it over ssh
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RemoteHost {
+    /// `user@host` or `host`, as passed to `--target-host`
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// Bastion to connect through, as for `ssh -J`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    /// Extra `ssh -o` options, e.g. "StrictHostKeyChecking=accept-new"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_options: Vec<String>,
+    /// Share one ssh connection between everything a rebuild runs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub control_master: bool,
+}
+
+impl RemoteHost {
+    pub fn new(target: &str) -> Self {
+        Self {
+            target: target.to_string(),
+            ..Self::default()
+        }
+    }
+
+    /// ssh arguments for these settings, to go before the target
+    pub fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(ref identity) = self.identity_file {
+            args.extend(["-i".to_string(), identity.clone()]);
+        }
+        if let Some(ref jump) = self.proxy_jump {
+            args.extend(["-J".to_string(), jump.clone()]);
+        }
+        for option in &self.ssh_options {
+            args.extend(["-o".to_string(), option.clone()]);
+        }
+        if self.control_master {
+            for option in control_options() {
+                args.extend(["-o".to_string(), option]);
+            }
+        }
+        args
+    }
+
+    /// A setting that nix would break up, since it splits `NIX_SSHOPTS` on
+    /// whitespace
+    fn setting_with_whitespace(&self) -> Option<&str> {
+        self.identity_file
+            .iter()
+            .chain(&self.proxy_jump)
+            .chain(&self.ssh_options)
+            .map(String::as_str)
+            .find(|value| value.contains(char::is_whitespace))
+    }
+
+    /// A `Host` block of an ssh config with these settings
+    pub fn ssh_config_block(&self) -> String {
+        let hostname = self
+            .target
+            .rsplit_once('@')
+            .map_or(self.target.as_str(), |(_, hostname)| hostname);
+        let mut lines = vec![format!("Host {}", hostname)];
+        if let Some(port) = self.port {
+            lines.push(format!("Port {}", port));
+        }
+        if let Some(ref identity) = self.identity_file {
+            lines.push(format!("IdentityFile {}", identity));
+        }
+        if let Some(ref jump) = self.proxy_jump {
+            lines.push(format!("ProxyJump {}", jump));
+        }
+        lines.extend(self.ssh_options.iter().cloned());
+        if self.control_master {
+            lines.extend(control_options());
+        }
+        lines.join("\n    ") + "\n"
+    }
+
+    /// `NIX_SSHOPTS` for nixos-rebuild and nix-copy-closure: the inherited
+    /// value, these settings and `extra`. nix splits it on whitespace
+    pub fn nix_sshopts(&self, extra: &[&str]) -> String {
+        nix_sshopts(self.ssh_args(), extra)
+    }
+}
+
+/// `NIX_SSHOPTS` with `args` and `extra` after the inherited value
+pub fn nix_sshopts(args: Vec<String>, extra: &[&str]) -> String {
+    let mut opts = vec![std::env::var("NIX_SSHOPTS").unwrap_or_default()];
+    opts.extend(args);
+    opts.extend(extra.iter().map(|s| s.to_string()));
+    opts.join(" ").trim().to_string()
+}
+
+/// An ssh config giving each host its own settings, for when one set of ssh
+/// arguments has to reach hosts that need different ones
+/// `-F` skips the user's and system's configs, so they are included after
+pub fn ssh_config(hosts: &[&RemoteHost]) -> String {
+    let mut config: Vec<String> = hosts.iter().map(|host| host.ssh_config_block()).collect();
+    config.push(
+        "Match all\n    Include ~/.ssh/config\n    Include /etc/ssh/ssh_config\n".to_string(),
+    );
+    config.join("\n")
+}
+
+/// Options that share one ssh connection between everything a rebuild runs
+fn control_options() -> [String; 3] {
+    [
+        "ControlMaster=auto".to_string(),
+        format!("ControlPath={}", CONTROL_PATH),
+        "ControlPersist=60".to_string(),
+    ]
+}
+
 impl Connection {
     pub fn is_configured(&self) -> bool {
         !matches!(self, Connection::Unconfigured)
@@ -20,7 +147,10 @@ impl Connection {
     pub fn display(&self) -> String {
         match self {
             Connection::Local => "localhost".to_string(),
-            Connection::Remote(s) => s.clone(),
+            Connection::Remote(host) => match host.port {
+                Some(port) => format!("{}:{}", host.target, port),
+                None => host.target.clone(),
+            },
             Connection::Unconfigured => "[]".to_string(),
         }
     }
@@ -33,7 +163,11 @@ impl Serialize for Connection {
     {
         match self {
             Connection::Local => serializer.serialize_str("localhost"),
-            Connection::Remote(s) => serializer.serialize_str(s),
+            // Hosts without ssh settings keep the plain string form
+            Connection::Remote(host) if *host == RemoteHost::new(&host.target) => {
+                serializer.serialize_str(&host.target)
+            }
+            Connection::Remote(host) => host.serialize(serializer),
             Connection::Unconfigured => {
                 use serde::ser::SerializeSeq;
                 let seq = serializer.serialize_seq(Some(0))?;
@@ -52,7 +186,9 @@ impl<'de> Deserialize<'de> for Connection {
         #[serde(untagged)]
         enum ConnectionHelper {
             String(String),
-            Array(()),
+            // `()` only accepts unit values, which TOML doesn't have
+            Array(Vec<IgnoredAny>),
+            Table(RemoteHost),
         }
 
         match ConnectionHelper::deserialize(deserializer)? {
@@ -60,10 +196,21 @@ impl<'de> Deserialize<'de> for Connection {
                 if s == "localhost" {
                     Ok(Connection::Local)
                 } else {
-                    Ok(Connection::Remote(s))
+                    Ok(Connection::Remote(RemoteHost::new(&s)))
                 }
             }
-            ConnectionHelper::Array(_) => Ok(Connection::Unconfigured),
+            ConnectionHelper::Array(items) if items.is_empty() => Ok(Connection::Unconfigured),
+            ConnectionHelper::Array(_) => Err(de::Error::custom(
+                "connection must be \"localhost\", an ssh target, a table or []",
+            )),
+            ConnectionHelper::Table(host) => match host.setting_with_whitespace() {
+                Some(value) => Err(de::Error::custom(format!(
+                    "ssh setting {:?} contains whitespace, which NIX_SSHOPTS can't carry; \
+                     set it in ~/.ssh/config instead",
+                    value
+                ))),
+                None => Ok(Connection::Remote(host)),
+            },
         }
     }
 }
@@ -73,6 +220,13 @@ pub struct HostConfig {
     pub connection: Connection,
     #[serde(default)]
     pub extra_args: Vec<String>,
+    /// Where to build: "localhost", another configured host or an ssh target
+    /// Without it nixos-rebuild builds locally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build_host: Option<String>,
+    /// Groups the host belongs to, e.g. "prod" or "lab"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
 }
 
 impl HostConfig {
@@ -80,6 +234,8 @@ impl HostConfig {
         Self {
             connection: Connection::Unconfigured,
             extra_args: Vec::new(),
+            build_host: None,
+            tags: Vec::new(),
         }
     }
 
@@ -87,6 +243,80 @@ impl HostConfig {
         Self {
             connection: Connection::Local,
             extra_args: Vec::new(),
+            build_host: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Wrapper {
+        connection: Connection,
+    }
+
+    fn parse(toml_str: &str) -> Connection {
+        toml::from_str::<Wrapper>(toml_str).unwrap().connection
+    }
+
+    #[test]
+    fn test_connection_forms() {
+        assert_eq!(parse(r#"connection = "localhost""#), Connection::Local);
+        assert_eq!(parse("connection = []"), Connection::Unconfigured);
+        assert!(toml::from_str::<Wrapper>(r#"connection = ["oops"]"#).is_err());
+        assert!(toml::from_str::<Wrapper>(
+            r#"connection = { target = "web", identity_file = "~/my keys/deploy" }"#
+        )
+        .is_err());
+        assert_eq!(
+            parse(r#"connection = "admin@web""#),
+            Connection::Remote(RemoteHost::new("admin@web"))
+        );
+
+        let host = RemoteHost {
+            target: "admin@web".to_string(),
+            port: Some(2222),
+            identity_file: Some("~/.ssh/deploy".to_string()),
+            proxy_jump: Some("bastion".to_string()),
+            ssh_options: vec!["StrictHostKeyChecking=accept-new".to_string()],
+            control_master: true,
+        };
+        let parsed = parse(
+            r#"connection = { target = "admin@web", port = 2222, identity_file = "~/.ssh/deploy", proxy_jump = "bastion", ssh_options = ["StrictHostKeyChecking=accept-new"], control_master = true }"#,
+        );
+        assert_eq!(parsed, Connection::Remote(host.clone()));
+        assert_eq!(
+            host.ssh_args()[..8],
+            [
+                "-p",
+                "2222",
+                "-i",
+                "~/.ssh/deploy",
+                "-J",
+                "bastion",
+                "-o",
+                "StrictHostKeyChecking=accept-new"
+            ]
+        );
+
+        // Plain hosts still serialize as a string, others as a table
+        for connection in [
+            Connection::Local,
+            Connection::Unconfigured,
+            Connection::Remote(RemoteHost::new("web")),
+            Connection::Remote(host),
+        ] {
+            let wrapper = Wrapper { connection };
+            let serialized = toml::to_string(&wrapper).unwrap();
+            assert_eq!(parse(&serialized), wrapper.connection);
         }
+        let serialized = toml::to_string(&Wrapper {
+            connection: Connection::Remote(RemoteHost::new("web")),
+        })
+        .unwrap();
+        assert_eq!(serialized.trim(), r#"connection = "web""#);
     }
 }
@@ -2,21 +2,66 @@ pub mod hosts;
 
 use color_eyre::{eyre::Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 
-pub use hosts::{Connection, HostConfig};
+pub use hosts::{Connection, HostConfig, RemoteHost};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// Group of the hosts without tags
+pub const UNTAGGED: &str = "(untagged)";
+
+/// Default number of hosts rebuilt at the same time
+const DEFAULT_MAX_PARALLEL: usize = 4;
+
+fn default_max_parallel() -> usize {
+    DEFAULT_MAX_PARALLEL
+}
+
+fn default_structured_progress() -> bool {
+    true
+}
+
+/// Default seconds between reachability checks of each host
+const DEFAULT_CHECK_INTERVAL: u64 = 60;
+
+fn default_check_interval() -> u64 {
+    DEFAULT_CHECK_INTERVAL
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub flake_path: Option<String>,
 
+    /// Maximum number of rebuilds running at once
+    #[serde(default = "default_max_parallel")]
+    pub max_parallel: usize,
+
+    /// Have nix report progress as JSON, for the progress header
+    #[serde(default = "default_structured_progress")]
+    pub structured_progress: bool,
+
+    /// Seconds between reachability checks of each host, 0 to turn them off
+    #[serde(default = "default_check_interval")]
+    pub check_interval_secs: u64,
+
     #[serde(default)]
     pub hosts: HashMap<String, HostConfig>,
 }
 
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            flake_path: None,
+            max_parallel: DEFAULT_MAX_PARALLEL,
+            structured_progress: true,
+            check_interval_secs: DEFAULT_CHECK_INTERVAL,
+            hosts: HashMap::new(),
+        }
+    }
+}
+
 impl Config {
     /// Get the XDG config directory path for renix
     pub fn config_dir() -> Result<PathBuf> {
@@ -72,6 +117,42 @@ impl Config {
         Ok(())
     }
 
+    /// Resolve a host's `build_host` to a connection
+    /// Names of other configured hosts use their connection, anything else
+    /// is taken as an ssh target
+    pub fn build_host(&self, config_name: &str) -> Option<Connection> {
+        let build_host = self.hosts.get(config_name)?.build_host.as_deref()?;
+        if build_host == "localhost" {
+            return Some(Connection::Local);
+        }
+        Some(match self.hosts.get(build_host) {
+            Some(host) if host.connection.is_configured() => host.connection.clone(),
+            _ => Connection::Remote(RemoteHost::new(build_host)),
+        })
+    }
+
+    /// Host names by tag, sorted, with untagged hosts under `UNTAGGED`
+    /// A host with several tags is in each of their groups
+    pub fn groups(&self) -> BTreeMap<String, Vec<String>> {
+        let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (name, host) in &self.hosts {
+            if host.tags.is_empty() {
+                groups
+                    .entry(UNTAGGED.to_string())
+                    .or_default()
+                    .push(name.clone());
+            }
+            for tag in &host.tags {
+                groups.entry(tag.clone()).or_default().push(name.clone());
+            }
+        }
+        for hosts in groups.values_mut() {
+            hosts.sort();
+            hosts.dedup();
+        }
+        groups
+    }
+
     /// Merge discovered configurations from a flake with existing config
     /// - Keeps existing connection info for known hosts
     /// - Auto-assigns localhost to configs matching current hostname
@@ -0,0 +1,361 @@
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// State of the git checkout a flake is built from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GitStatus {
+    /// None when HEAD is detached
+    pub branch: Option<String>,
+    /// None before the first commit
+    pub head: Option<String>,
+    pub upstream: Option<String>,
+    pub ahead: u32,
+    pub behind: u32,
+    /// Tracked files with staged or unstaged changes
+    pub changed: usize,
+    /// New files git doesn't track, which flakes silently leave out
+    pub untracked: Vec<String>,
+}
+
+impl GitStatus {
+    /// Whether tracked files differ from HEAD, so a build wouldn't match any
+    /// commit
+    pub fn is_dirty(&self) -> bool {
+        self.changed > 0
+    }
+
+    pub fn short_head(&self) -> &str {
+        self.head
+            .as_deref()
+            .map_or("(no commits)", |head| head.get(..7).unwrap_or(head))
+    }
+
+    /// e.g. "main@1a2b3c4" or "detached@1a2b3c4"
+    pub fn describe_head(&self) -> String {
+        format!(
+            "{}@{}",
+            self.branch.as_deref().unwrap_or("detached"),
+            self.short_head()
+        )
+    }
+}
+
+/// A commit of the flake's repository to deploy instead of its working tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitRef {
+    /// The ref as given: a branch, tag, commit or worktree path
+    pub name: String,
+    /// Full name of the branch or tag it resolved through, if any
+    pub full_ref: Option<String>,
+    pub rev: String,
+    /// Top of the repository
+    pub repo: PathBuf,
+    /// Directory of the flake within the repository, empty at the top
+    pub subdir: String,
+}
+
+impl GitRef {
+    pub fn short_rev(&self) -> &str {
+        self.rev.get(..7).unwrap_or(&self.rev)
+    }
+
+    /// e.g. "pr-42 (1a2b3c4)"
+    pub fn describe(&self) -> String {
+        format!("{} ({})", self.name, self.short_rev())
+    }
+
+    /// The flake reference that makes nix fetch exactly this commit from the
+    /// repository, whatever is checked out
+    pub fn flake_ref(&self) -> String {
+        let mut params = match self.full_ref {
+            Some(ref full_ref) => vec![format!("ref={}", percent_encode(full_ref))],
+            // A bare commit may not be on the default branch
+            None => vec!["allRefs=1".to_string()],
+        };
+        params.push(format!("rev={}", self.rev));
+        if !self.subdir.is_empty() {
+            params.push(format!("dir={}", percent_encode(&self.subdir)));
+        }
+        format!(
+            "git+file://{}?{}",
+            percent_encode(&self.repo.to_string_lossy()),
+            params.join("&")
+        )
+    }
+}
+
+/// Resolve a branch, tag or commit of the flake's repository, or the HEAD
+/// of a worktree given by its path, to the commit it points at now
+pub fn resolve_ref(flake_path: &str, name: &str) -> Result<GitRef> {
+    let dir = checkout_dir(flake_path)
+        .filter(|dir| dir.is_dir())
+        .ok_or_else(|| eyre!("The flake is not a local git checkout"))?;
+
+    // Worktrees share the refs, but their HEAD is their own
+    let is_path = name.starts_with('/') || name.starts_with("~/") || name.starts_with('.');
+    let (repo_dir, spec) = match checkout_dir(name).filter(|path| is_path && path.is_dir()) {
+        Some(worktree) => (worktree, "HEAD"),
+        None => (dir.clone(), name),
+    };
+
+    let rev = git(
+        &repo_dir,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", spec)],
+    )
+    .map_err(|_| eyre!("Unknown git ref: {}", name))?;
+    let full_ref = git(&repo_dir, &["rev-parse", "--symbolic-full-name", spec])?;
+
+    Ok(GitRef {
+        name: name.to_string(),
+        full_ref: (!full_ref.is_empty()).then_some(full_ref),
+        rev,
+        repo: PathBuf::from(git(&repo_dir, &["rev-parse", "--show-toplevel"])?),
+        subdir: git(&dir, &["rev-parse", "--show-prefix"])?
+            .trim_end_matches('/')
+            .to_string(),
+    })
+}
+
+/// Run git in a directory and return its trimmed stdout
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("Failed to run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("git {} failed: {}", args[0], stderr.trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The directory of a flake reference that nix reads through git, if it is a
+/// local one; `path:` flakes copy the tree as is and remote ones aren't ours
+/// A `dir` parameter is kept, so the flake's own directory is returned
+pub fn checkout_dir(flake_path: &str) -> Option<PathBuf> {
+    let (path, subdir) = match flake_path.strip_prefix("git+file://") {
+        Some(rest) => {
+            let rest = rest.split('#').next().unwrap_or(rest);
+            let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+            let subdir = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("dir="));
+            (percent_decode(path), subdir.map(percent_decode))
+        }
+        None if flake_path.contains(':') => return None,
+        None => (
+            flake_path
+                .split('#')
+                .next()
+                .unwrap_or(flake_path)
+                .to_string(),
+            None,
+        ),
+    };
+
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(std::env::var("HOME").ok()?).join(rest),
+        None => PathBuf::from(path),
+    };
+    Some(match subdir {
+        Some(subdir) => path.join(subdir),
+        None => path,
+    })
+}
+
+/// Escape everything but unreserved characters and `/` for a flake URL
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Undo `percent_encode`, leaving malformed escapes as they are
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| s.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Read the status of the checkout a flake lives in
+/// Returns None if the flake isn't a local git checkout; listing untracked
+/// files walks the tree, so callers that only need HEAD can skip it
+pub fn status(flake_path: &str, untracked: bool) -> Result<Option<GitStatus>> {
+    let Some(dir) = checkout_dir(flake_path) else {
+        return Ok(None);
+    };
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(&dir)
+        .args([
+            "status",
+            "--porcelain=v2",
+            "--branch",
+            if untracked {
+                "--untracked-files=normal"
+            } else {
+                "--untracked-files=no"
+            },
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("Failed to run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("not a git repository") {
+            return Ok(None);
+        }
+        return Err(eyre!("git status failed: {}", stderr.trim()));
+    }
+
+    Ok(Some(parse_status(&String::from_utf8_lossy(&output.stdout))))
+}
+
+/// Parse `git status --porcelain=v2 --branch`
+fn parse_status(output: &str) -> GitStatus {
+    let mut status = GitStatus::default();
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix("# ") {
+            let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+            match key {
+                "branch.oid" if value != "(initial)" => status.head = Some(value.to_string()),
+                "branch.head" if value != "(detached)" => status.branch = Some(value.to_string()),
+                "branch.upstream" => status.upstream = Some(value.to_string()),
+                "branch.ab" => {
+                    for count in value.split_whitespace() {
+                        if let Some(n) = count.strip_prefix('+') {
+                            status.ahead = n.parse().unwrap_or(0);
+                        } else if let Some(n) = count.strip_prefix('-') {
+                            status.behind = n.parse().unwrap_or(0);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        } else if let Some(path) = line.strip_prefix("? ") {
+            status.untracked.push(path.to_string());
+        } else if matches!(line.chars().next(), Some('1' | '2' | 'u')) {
+            status.changed += 1;
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let output = "# branch.oid 1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b\n\
+                      # branch.head main\n\
+                      # branch.upstream origin/main\n\
+                      # branch.ab +2 -1\n\
+                      1 .M N... 100644 100644 100644 aaa bbb hosts/web.nix\n\
+                      2 R. N... 100644 100644 100644 aaa bbb R100 new.nix\told.nix\n\
+                      ? hosts/db.nix\n";
+        let status = parse_status(output);
+
+        assert_eq!(status.describe_head(), "main@1a2b3c4");
+        assert_eq!(status.upstream.as_deref(), Some("origin/main"));
+        assert_eq!((status.ahead, status.behind), (2, 1));
+        assert_eq!(status.changed, 2);
+        assert!(status.is_dirty());
+        assert_eq!(status.untracked, vec!["hosts/db.nix"]);
+
+        let status = parse_status("# branch.oid (initial)\n# branch.head (detached)\n");
+        assert_eq!(status.describe_head(), "detached@(no commits)");
+        assert!(!status.is_dirty());
+    }
+
+    #[test]
+    fn test_checkout_dir() {
+        assert_eq!(checkout_dir("github:me/nixos"), None);
+        assert_eq!(checkout_dir("path:/etc/nixos"), None);
+        assert_eq!(
+            checkout_dir("git+file:///etc/nixos?ref=main"),
+            Some(PathBuf::from("/etc/nixos"))
+        );
+        assert_eq!(
+            checkout_dir("git+file:///home/me/my%20nixos?ref=main&dir=hosts/web#pi"),
+            Some(PathBuf::from("/home/me/my nixos/hosts/web"))
+        );
+        assert_eq!(
+            checkout_dir("/etc/nixos"),
+            Some(PathBuf::from("/etc/nixos"))
+        );
+    }
+
+    #[test]
+    fn test_flake_ref() {
+        let mut git_ref = GitRef {
+            name: "pr-42".to_string(),
+            full_ref: Some("refs/heads/pr-42".to_string()),
+            rev: "1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b".to_string(),
+            repo: PathBuf::from("/home/me/nixos"),
+            subdir: String::new(),
+        };
+        assert_eq!(
+            git_ref.flake_ref(),
+            "git+file:///home/me/nixos?ref=refs/heads/pr-42&rev=1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b"
+        );
+        assert_eq!(git_ref.describe(), "pr-42 (1a2b3c4)");
+
+        git_ref.full_ref = None;
+        git_ref.subdir = "hosts".to_string();
+        assert_eq!(
+            git_ref.flake_ref(),
+            "git+file:///home/me/nixos?allRefs=1&rev=1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b&dir=hosts"
+        );
+
+        // Characters that mean something in a URL are escaped
+        git_ref.repo = PathBuf::from("/home/me/my nixos");
+        git_ref.full_ref = Some("refs/heads/fix#1&more".to_string());
+        git_ref.subdir = "hosts/a+b".to_string();
+        let flake_ref = git_ref.flake_ref();
+        assert_eq!(
+            flake_ref,
+            "git+file:///home/me/my%20nixos?ref=refs/heads/fix%231%26more&rev=1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b&dir=hosts/a%2Bb"
+        );
+        assert_eq!(
+            checkout_dir(&flake_ref),
+            Some(PathBuf::from("/home/me/my nixos/hosts/a+b"))
+        );
+    }
+}
@@ -0,0 +1,273 @@
+use crate::git::{self, GitRef, GitStatus};
+use crate::nix::flake::flake_revision;
+use crate::nix::rebuild::BuildExit;
+use crate::session::BuildStatus;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long a finished build's record waits for a flake revision that is
+/// still being resolved, before it is written without one
+const REVISION_WAIT: Duration = Duration::from_secs(5);
+
+/// A finished rebuild, as stored in the history file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildRecord {
+    pub id: String,
+    pub host: String,
+    pub operation: String,
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+    #[serde(default)]
+    pub upgrade: bool,
+    pub flake_path: Option<String>,
+    pub flake_revision: Option<String>,
+    /// Branch, tag, commit or worktree deployed instead of the working tree
+    #[serde(default)]
+    pub git_ref: Option<String>,
+    /// Commit that was built: the git ref's, or HEAD of the flake's checkout
+    /// when the build started
+    #[serde(default)]
+    pub git_commit: Option<String>,
+    /// Whether tracked files had uncommitted changes, so the build didn't
+    /// match the commit
+    #[serde(default)]
+    pub git_dirty: bool,
+    /// Unix timestamp (seconds) the build started at
+    pub started_at: u64,
+    pub duration_secs: f64,
+    pub status: BuildStatus,
+    pub exit_code: Option<u32>,
+    pub signal: Option<String>,
+    pub log_path: Option<PathBuf>,
+}
+
+/// What a build ran, as recorded in its history entry
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    pub host: String,
+    pub operation: String,
+    pub extra_args: Vec<String>,
+    pub upgrade: bool,
+    pub flake_path: Option<String>,
+    pub git_ref: Option<GitRef>,
+}
+
+/// Get the XDG state directory path for renix
+pub fn state_dir() -> Result<PathBuf> {
+    let state_home = match std::env::var("XDG_STATE_HOME") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            let home =
+                std::env::var("HOME").map_err(|_| eyre!("HOME environment variable not set"))?;
+            PathBuf::from(home).join(".local").join("state")
+        }
+    };
+
+    Ok(state_home.join("renix"))
+}
+
+/// Get the history file path
+pub fn history_path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("history.jsonl"))
+}
+
+/// Get the directory build logs are written to
+pub fn logs_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("logs"))
+}
+
+/// Append a record to the history file
+pub fn append(record: &BuildRecord) -> Result<()> {
+    fs::create_dir_all(state_dir()?).wrap_err("Failed to create state directory")?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(history_path()?)
+        .wrap_err("Failed to open history file")?;
+
+    let line = serde_json::to_string(record).wrap_err("Failed to serialize build record")?;
+    writeln!(file, "{}", line).wrap_err("Failed to write history file")?;
+
+    Ok(())
+}
+
+/// Load the history of a host, newest first
+/// Lines that fail to parse are skipped rather than failing the whole file
+pub fn load_for_host(host: &str) -> Result<Vec<BuildRecord>> {
+    let path = history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = File::open(&path).wrap_err("Failed to open history file")?;
+    let mut records: Vec<BuildRecord> = BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str::<BuildRecord>(&line).ok())
+        .filter(|record| record.host == host)
+        .collect();
+
+    records.reverse();
+    Ok(records)
+}
+
+/// Get the directory exported buffers are written to
+pub fn exports_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("exports"))
+}
+
+/// Get the directory reviewed deploys are built in, one subdirectory per host
+/// Each keeps the `result` link of its last build, which also roots it
+pub fn builds_dir() -> Result<PathBuf> {
+    Ok(state_dir()?.join("builds"))
+}
+
+/// Read the raw output of a recorded build
+pub fn read_log(record: &BuildRecord) -> Result<Vec<u8>> {
+    let path = record
+        .log_path
+        .as_ref()
+        .ok_or_else(|| eyre!("No log was saved for this build"))?;
+    fs::read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))
+}
+
+/// Records a running build: tees its output to a log file and appends a
+/// history record once it finishes
+pub struct BuildRecorder {
+    record: BuildRecord,
+    started: SystemTime,
+    log: Option<BufWriter<File>>,
+    revision_rx: Option<mpsc::Receiver<Option<String>>>,
+    checkout_rx: Option<mpsc::Receiver<Option<GitStatus>>>,
+}
+
+impl BuildRecorder {
+    /// Start recording a build that is about to run
+    pub fn start(info: &RunInfo) -> Self {
+        let started = SystemTime::now();
+        let started_at = started
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let id = format!("{}-{}-{}", started_at, info.host, std::process::id());
+
+        // A missing log only costs the replay, so don't fail the build over it
+        let log_path = logs_dir().ok().map(|dir| dir.join(format!("{}.log", id)));
+        let log = log_path.as_ref().and_then(|path| {
+            fs::create_dir_all(path.parent()?).ok()?;
+            File::create(path).ok().map(BufWriter::new)
+        });
+
+        // Resolving the revision evaluates the flake, so keep it off the UI thread
+        let revision_rx = info.flake_path.clone().map(|flake_path| {
+            let (tx, rx) = mpsc::channel();
+            std::thread::spawn(move || {
+                let _ = tx.send(flake_revision(&flake_path).ok().flatten());
+            });
+            rx
+        });
+
+        // A git ref is recorded as given; otherwise note what the checkout is
+        // at. Untracked files aren't built, so skip walking the tree for them
+        let checkout_rx = match info.git_ref {
+            Some(_) => None,
+            None => info.flake_path.clone().map(|flake_path| {
+                let (tx, rx) = mpsc::channel();
+                std::thread::spawn(move || {
+                    let _ = tx.send(git::status(&flake_path, false).ok().flatten());
+                });
+                rx
+            }),
+        };
+
+        Self {
+            record: BuildRecord {
+                id,
+                host: info.host.clone(),
+                operation: info.operation.clone(),
+                extra_args: info.extra_args.clone(),
+                upgrade: info.upgrade,
+                flake_path: info.flake_path.clone(),
+                flake_revision: None,
+                git_ref: info.git_ref.as_ref().map(|git_ref| git_ref.name.clone()),
+                git_commit: info.git_ref.as_ref().map(|git_ref| git_ref.rev.clone()),
+                git_dirty: false,
+                started_at,
+                duration_secs: 0.0,
+                status: BuildStatus::Running,
+                exit_code: None,
+                signal: None,
+                log_path: log.as_ref().and(log_path),
+            },
+            started,
+            log,
+            revision_rx,
+            checkout_rx,
+        }
+    }
+
+    /// Path of the log the output is written to, if it could be created
+    pub fn log_path(&self) -> Option<&Path> {
+        self.record.log_path.as_deref()
+    }
+
+    /// Write raw build output to the log
+    /// Flushed per chunk so the log is complete even while the build runs
+    pub fn write_output(&mut self, bytes: &[u8]) {
+        if let Some(ref mut log) = self.log {
+            if log.write_all(bytes).and_then(|_| log.flush()).is_err() {
+                self.log = None;
+            }
+        }
+    }
+
+    /// Finish the record and append it to the history file
+    /// Blocks for up to REVISION_WAIT if the flake revision or the state of
+    /// its checkout isn't known yet
+    pub fn finish(mut self, status: BuildStatus, exit: Option<&BuildExit>) -> Result<BuildRecord> {
+        if let Some(mut log) = self.log.take() {
+            let _ = log.flush();
+        }
+
+        self.record.status = status;
+        self.record.duration_secs = match exit {
+            Some(exit) => exit.duration.as_secs_f64(),
+            None => self
+                .started
+                .elapsed()
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+        };
+        if let Some(exit) = exit {
+            self.record.exit_code = exit.code;
+            self.record.signal = exit.signal.clone();
+        }
+        // Builds that fail fast finish before the evaluation does
+        let deadline = Instant::now() + REVISION_WAIT;
+        let wait = || deadline.saturating_duration_since(Instant::now());
+        self.record.flake_revision = self
+            .revision_rx
+            .and_then(|rx| rx.recv_timeout(wait()).ok())
+            .flatten();
+        if let Some(checkout) = self
+            .checkout_rx
+            .and_then(|rx| rx.recv_timeout(wait()).ok())
+            .flatten()
+        {
+            self.record.git_dirty = checkout.is_dirty();
+            self.record.git_commit = checkout.head;
+        }
+
+        append(&self.record)?;
+        Ok(self.record)
+    }
+}
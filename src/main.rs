@@ -1,10 +1,15 @@
 mod app;
+mod cli;
 mod config;
+mod git;
+mod history;
 mod nix;
+mod session;
 mod terminal;
 mod ui;
+mod util;
 
-use app::App;
+use app::{App, ExportFormat, RebuildOperation};
 use color_eyre::Result;
 use config::Config;
 use crossterm::{
@@ -13,7 +18,7 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use nix::{discover_configurations, flake::get_hostname};
-use ratatui::{backend::CrosstermBackend, Terminal};
+use ratatui::{backend::CrosstermBackend, layout::Rect, Terminal};
 use std::io;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -32,10 +37,16 @@ async fn main() -> Result<()> {
                 print_help();
                 return Ok(());
             }
+            "rebuild" | "hosts" => {
+                color_eyre::install()?;
+                let config = load_config()?;
+                let code = cli::run(config, &args[1..]).await?;
+                std::process::exit(code);
+            }
             _ => {
                 eprintln!("Unknown argument: {}", args[1]);
                 eprintln!("Try 'renix --help' for more information.");
-                std::process::exit(1);
+                std::process::exit(cli::EXIT_USAGE);
             }
         }
     }
@@ -43,21 +54,13 @@ async fn main() -> Result<()> {
     // Setup color-eyre for better error messages
     color_eyre::install()?;
 
-    // Load config (creates default if missing)
-    let mut config = Config::load()?;
-
-    // If flake path is set, discover configurations and merge
-    if let Some(ref flake_path) = config.flake_path {
-        if let Ok(discovered) = discover_configurations(flake_path) {
-            if let Ok(hostname) = get_hostname() {
-                config.merge_discovered_configs(discovered, &hostname)?;
-                config.save()?;
-            }
-        }
-    }
+    let config = load_config()?;
 
     // Create app state
     let mut app = App::new(config);
+    if app.config.flake_path.is_some() {
+        app.refresh_drift();
+    }
 
     // Setup terminal
     enable_raw_mode()?;
@@ -69,6 +72,9 @@ async fn main() -> Result<()> {
     // Run the app
     let res = run_app(&mut terminal, &mut app).await;
 
+    // Never leave a rebuild running behind us, however the loop exited
+    app.shutdown();
+
     // Restore terminal
     disable_raw_mode()?;
     execute!(
@@ -86,33 +92,84 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+/// Load config (creating a default if missing) and merge in configurations
+/// discovered from the flake, if one is set
+fn load_config() -> Result<Config> {
+    let mut config = Config::load()?;
+
+    if let Some(ref flake_path) = config.flake_path {
+        if let Ok(discovered) = discover_configurations(flake_path) {
+            if let Ok(hostname) = get_hostname() {
+                config.merge_discovered_configs(discovered, &hostname)?;
+                config.save()?;
+            }
+        }
+    }
+
+    Ok(config)
+}
+
 fn print_help() {
     println!("renix {} - NixOS Rebuild Manager TUI", VERSION);
     println!();
     println!("USAGE:");
     println!("    renix [OPTIONS]");
+    println!("    renix rebuild <HOST>... [--op OPERATION] [--upgrade] [--parallel N]");
+    println!("                        [--ref REF]");
+    println!("    renix hosts list");
+    println!();
+    println!("COMMANDS:");
+    println!("    rebuild          Rebuild hosts without the TUI, prefixing output lines");
+    println!("                     with the host name. OPERATION is one of switch, boot,");
+    println!("                     test, build, dry-build, dry-activate (default: switch).");
+    println!("                     REF deploys a branch, tag or commit of the flake's");
+    println!("                     repository instead of its working tree.");
+    println!("                     Exits 0 if every host succeeded, 1 if any failed,");
+    println!("                     2 for usage errors and 130 if interrupted with Ctrl-C,");
+    println!("                     in which case no summary is printed.");
+    println!("    hosts list       List configured hosts and their connections");
     println!();
     println!("OPTIONS:");
     println!("    -h, --help       Print help information");
     println!("    -v, --version    Print version information");
     println!();
     println!("KEYBINDINGS:");
-    println!("    q                Quit (press twice during build to force)");
+    println!("    q                Quit (press twice during build to kill it)");
     println!("    Tab              Toggle between main and settings panel");
     println!("    ↑/↓, j/k         Navigate hosts / scroll output");
     println!("    ←/→, h/l         Change rebuild operation");
     println!("    u                Toggle --upgrade flag");
     println!("    i                Enter input mode (for passwords)");
-    println!("    Enter            Start rebuild");
-    println!("    Esc              Cancel running build / Exit input mode");
+    println!("    Space            Mark host for a multi-host rebuild");
+    println!("    Enter            Start rebuild (on all marked hosts, if any)");
+    println!("    t                Group the host list by tag");
+    println!("                     (Space on a group: collapse, Enter: run on the group)");
+    println!("    p                Run group operations in parallel / in sequence");
+    println!("    d                Build, review the closure diff, then s: switch / b: boot");
+    println!("    Esc              Cancel running build (again to escalate) / Exit input mode");
+    println!("    H                Show build history of the selected host");
+    println!("    G                Show system generations of the selected host");
+    println!("                     (Enter: switch to generation, r: roll back)");
+    println!("    e / E            Export output as plain text / with ANSI colors");
     println!("    f                Edit flake path");
     println!("    c                Edit host connection");
     println!("    a                Edit extra args for host");
+    println!("    b                Edit build host for host");
+    println!("    g                Deploy a git ref (branch, tag, commit or worktree path)");
+    println!("                     of the flake's repository instead of its working tree");
     println!("    PageUp/PageDown  Scroll output (10 lines)");
     println!("    Home/End         Jump to top/bottom of output");
+    println!("    D                Show errors and warnings found in the output");
+    println!("                     (j/k: select and jump to it, Enter: show trace)");
+    println!("    /                Search output (regex); n/N: next/previous match");
+    println!("    F                Show the selected host's system facts (r: refresh)");
+    println!("    R                Check every host for drift from the flake");
+    println!("    U                Show the flake's inputs (u: update the selected input,");
+    println!("                     a: update all, then shows what changed in the lock)");
     println!();
     println!("CONFIGURATION:");
     println!("    Config file: ~/.config/renix/config.toml");
+    println!("    Build history and logs: ~/.local/state/renix/");
     println!();
     println!("For more information, visit: https://github.com/sarahlament/renix");
 }
@@ -125,13 +182,13 @@ async fn run_app<B: ratatui::backend::Backend>(
         // Resize virtual terminal to match output area FIRST
         // This ensures terminal_cols and terminal_rows are correct when starting builds
         let term_size = terminal.size()?;
-        // Output area is 75% of width, minus 2 for borders
-        let output_width = (term_size.width * 75 / 100).saturating_sub(2) as usize;
-        let output_height = term_size.height.saturating_sub(2) as usize;
-        app.resize_terminal(output_width, output_height);
+        let output =
+            ui::layout::output_area(Rect::new(0, 0, term_size.width, term_size.height), app);
+        app.resize_terminal(output.width, output.height);
 
-        // Poll for output from async rebuild process
+        // Poll for output from async rebuild processes, then fill free build slots
         app.poll_output();
+        app.start_queued_builds().await;
 
         terminal.draw(|f| {
             ui::render(f, app);
@@ -153,6 +210,9 @@ async fn run_app<B: ratatui::backend::Backend>(
                     }
                 }
                 Event::Key(key) => {
+                    // Status messages last until the next key press
+                    app.status_message = None;
+
                     // Handle edit mode input
                     if app.is_editing() {
                         match key.code {
@@ -170,6 +230,33 @@ async fn run_app<B: ratatui::backend::Backend>(
                             }
                             _ => {}
                         }
+                    } else if app.is_typing_search() {
+                        match key.code {
+                            KeyCode::Enter => {
+                                app.commit_search();
+                            }
+                            KeyCode::Esc => {
+                                app.cancel_search();
+                            }
+                            KeyCode::Char(c) => {
+                                app.search_insert_char(c);
+                            }
+                            KeyCode::Backspace => {
+                                app.search_backspace();
+                            }
+                            _ => {}
+                        }
+                    } else if app.confirmation.is_some() {
+                        // A pending confirmation swallows every other key
+                        match key.code {
+                            KeyCode::Char('y') | KeyCode::Char('Y') => {
+                                app.confirm().await?;
+                            }
+                            KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                                app.dismiss_confirmation();
+                            }
+                            _ => {}
+                        }
                     } else if app.input_mode {
                         // Input mode - send keystrokes to PTY
                         match key.code {
@@ -191,13 +278,129 @@ async fn run_app<B: ratatui::backend::Backend>(
                         // Normal mode input
                         match key.code {
                             KeyCode::Char('q') => {
-                                if app.attempt_quit() {
+                                // Warns on the first press, quits on the second
+                                let quit = app.attempt_quit();
+                                if quit {
                                     return Ok(());
                                 }
                             }
+                            KeyCode::Esc if app.replay.is_some() => {
+                                app.close_replay();
+                            }
+                            KeyCode::Esc if app.history_view.is_some() => {
+                                app.close_history();
+                            }
+                            KeyCode::Esc if app.generations_view.is_some() => {
+                                app.close_generations();
+                            }
+                            KeyCode::Esc
+                                if app.is_browsing_inputs() && !app.selected_is_building() =>
+                            {
+                                app.toggle_inputs();
+                            }
+                            KeyCode::Esc if app.is_reviewing() => {
+                                app.abort_review();
+                            }
+                            KeyCode::Esc if app.search.is_some() => {
+                                app.cancel_search();
+                            }
                             KeyCode::Esc => {
                                 app.cancel_build();
                             }
+                            KeyCode::Char('H') => {
+                                app.toggle_history();
+                            }
+                            KeyCode::Char('G') => {
+                                app.toggle_generations();
+                            }
+                            KeyCode::Char('e') => {
+                                app.export_output(ExportFormat::PlainText);
+                            }
+                            KeyCode::Char('E') => {
+                                app.export_output(ExportFormat::Ansi);
+                            }
+                            KeyCode::Up | KeyCode::Char('k') if app.is_browsing_history() => {
+                                app.history_select_prev();
+                            }
+                            KeyCode::Down | KeyCode::Char('j') if app.is_browsing_history() => {
+                                app.history_select_next();
+                            }
+                            KeyCode::Enter if app.is_browsing_history() => {
+                                app.open_history_entry();
+                            }
+                            KeyCode::Enter if app.replay.is_some() => {}
+                            KeyCode::Up | KeyCode::Char('k') if app.is_browsing_generations() => {
+                                app.generations_select_prev();
+                            }
+                            KeyCode::Down | KeyCode::Char('j') if app.is_browsing_generations() => {
+                                app.generations_select_next();
+                            }
+                            KeyCode::Enter if app.is_browsing_generations() => {
+                                app.request_switch_generation();
+                            }
+                            KeyCode::Char('r') if app.is_browsing_generations() => {
+                                app.request_rollback();
+                            }
+                            KeyCode::Char('s') if app.is_reviewing() => {
+                                app.activate_review(RebuildOperation::Switch).await?;
+                            }
+                            KeyCode::Char('b') if app.is_reviewing() => {
+                                app.activate_review(RebuildOperation::Boot).await?;
+                            }
+                            KeyCode::Char('k') if app.is_reviewing() => {
+                                app.scroll_review(false);
+                            }
+                            KeyCode::Char('j') if app.is_reviewing() => {
+                                app.scroll_review(true);
+                            }
+                            KeyCode::Char('U') => {
+                                app.toggle_inputs();
+                            }
+                            KeyCode::Up | KeyCode::Char('k') if app.is_browsing_inputs() => {
+                                app.inputs_select(false);
+                            }
+                            KeyCode::Down | KeyCode::Char('j') if app.is_browsing_inputs() => {
+                                app.inputs_select(true);
+                            }
+                            KeyCode::Char('u') if app.is_browsing_inputs() => {
+                                app.update_inputs(false).await?;
+                            }
+                            KeyCode::Char('a') if app.is_browsing_inputs() => {
+                                app.update_inputs(true).await?;
+                            }
+                            KeyCode::Char('D') => {
+                                app.toggle_diagnostics();
+                            }
+                            KeyCode::Char('k') if app.diagnostics_panel.is_some() => {
+                                app.diagnostics_select(false);
+                            }
+                            KeyCode::Char('j') if app.diagnostics_panel.is_some() => {
+                                app.diagnostics_select(true);
+                            }
+                            KeyCode::Enter if app.diagnostics_panel.is_some() => {
+                                app.toggle_diagnostic_expanded();
+                            }
+                            KeyCode::Char('R') => {
+                                app.refresh_drift();
+                            }
+                            KeyCode::Char('F') => {
+                                app.toggle_facts();
+                            }
+                            KeyCode::Char('r') if app.facts_panel => {
+                                app.load_facts(true);
+                            }
+                            KeyCode::Char('/') => {
+                                app.start_search();
+                            }
+                            KeyCode::Char('n') if app.search.is_some() => {
+                                app.search_next(true);
+                            }
+                            KeyCode::Char('N') if app.search.is_some() => {
+                                app.search_next(false);
+                            }
+                            KeyCode::Char('d') => {
+                                app.start_reviewed_deploy_async().await?;
+                            }
                             KeyCode::Tab => {
                                 app.toggle_panel();
                             }
@@ -210,12 +413,30 @@ async fn run_app<B: ratatui::backend::Backend>(
                             KeyCode::Char('a') => {
                                 app.start_edit_extra_args();
                             }
+                            KeyCode::Char('b') => {
+                                app.start_edit_build_host();
+                            }
+                            KeyCode::Char('g') => {
+                                app.start_edit_git_ref();
+                            }
                             KeyCode::Char('u') => {
                                 app.toggle_upgrade();
                             }
                             KeyCode::Char('i') => {
                                 app.toggle_input_mode();
                             }
+                            KeyCode::Char(' ') if app.selected_group().is_some() => {
+                                app.toggle_group_collapsed();
+                            }
+                            KeyCode::Char(' ') => {
+                                app.toggle_host_mark();
+                            }
+                            KeyCode::Char('t') => {
+                                app.toggle_grouped();
+                            }
+                            KeyCode::Char('p') => {
+                                app.toggle_group_parallel();
+                            }
                             KeyCode::Up => {
                                 app.select_prev_host();
                             }
@@ -242,8 +463,8 @@ async fn run_app<B: ratatui::backend::Backend>(
                             }
                             KeyCode::Home => {
                                 // Jump to top of output
-                                let total_lines = app.terminal.get_scrollback().len()
-                                    + app.terminal.get_screen().len();
+                                let total_lines = app.terminal().get_scrollback().len()
+                                    + app.terminal().get_screen().len();
                                 app.output_scroll = total_lines.saturating_sub(1);
                             }
                             KeyCode::End => {
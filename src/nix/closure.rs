@@ -0,0 +1,191 @@
+use crate::config::{Connection, RemoteHost};
+use crate::nix::remote::{self, shell_quote};
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use std::process::{Command, Stdio};
+
+/// Sizes both closures, then diffs them package by package
+/// `{new}` is replaced with the quoted store path of the new system
+const DIFF_SCRIPT: &str = r#"
+set -e
+current=$(readlink -f /run/current-system)
+size_current=$(nix --extra-experimental-features nix-command path-info -S "$current")
+size_new=$(nix --extra-experimental-features nix-command path-info -S {new})
+echo "size-current $size_current"
+echo "size-new $size_new"
+nix --extra-experimental-features nix-command store diff-closures "$current" {new}
+"#;
+
+/// A package whose presence or version differs between two closures
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageChange {
+    pub name: String,
+    /// Versions before and after, "∅" if the package was absent
+    pub before: String,
+    pub after: String,
+    /// Size change as printed by nix, e.g. "+1234.5 KiB"
+    pub size_delta: Option<String>,
+}
+
+/// Differences between a host's running system and a newly built one
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClosureDiff {
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub changed: Vec<PackageChange>,
+    /// Packages that only changed in size
+    pub resized: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl ClosureDiff {
+    /// Change in total closure size, in bytes
+    pub fn size_delta(&self) -> i64 {
+        self.size_after as i64 - self.size_before as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Diff a built system against what a host is currently running
+/// Remote hosts get the new closure copied to them first, which the switch
+/// would do anyway. Blocks for as long as the copy takes
+pub fn diff_against_current(connection: &Connection, toplevel: &str) -> Result<ClosureDiff> {
+    if let Connection::Remote(host) = connection {
+        copy_closure(host, toplevel)?;
+    }
+
+    let script = DIFF_SCRIPT.replace("{new}", &shell_quote(toplevel));
+    let output = remote::run_script(connection, &script)?;
+    Ok(parse_diff(&output))
+}
+
+/// Copy a store path and its closure to a remote host
+fn copy_closure(host: &RemoteHost, path: &str) -> Result<()> {
+    // Nobody can answer an ssh prompt from here
+    let output = Command::new("nix-copy-closure")
+        .args(["--to", &host.target, path])
+        .env("NIX_SSHOPTS", host.nix_sshopts(&["-o", "BatchMode=yes"]))
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("Failed to run nix-copy-closure")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!(
+            "Failed to copy closure to {}: {}",
+            host.target,
+            stderr.trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Parse the output of the diff script
+fn parse_diff(output: &str) -> ClosureDiff {
+    let mut diff = ClosureDiff::default();
+
+    for line in output.lines() {
+        let line = strip_ansi(line);
+        let line = line.trim();
+
+        if let Some(rest) = line.strip_prefix("size-current ") {
+            diff.size_before = last_number(rest);
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("size-new ") {
+            diff.size_after = last_number(rest);
+            continue;
+        }
+
+        // "name: 1.0 → 1.1, +12.3 KiB", "name: ∅ → 1.0" or "name: +0.5 KiB"
+        let Some((name, rest)) = line.split_once(": ") else {
+            continue;
+        };
+        let (versions, size_delta) = match rest.rsplit_once(", ") {
+            Some((versions, size)) if size.starts_with(['+', '-']) => {
+                (versions, Some(size.to_string()))
+            }
+            _ if rest.starts_with(['+', '-']) => ("", Some(rest.to_string())),
+            _ => (rest, None),
+        };
+
+        let Some((before, after)) = versions.split_once(" → ") else {
+            diff.resized += 1;
+            continue;
+        };
+        let change = PackageChange {
+            name: name.to_string(),
+            before: before.to_string(),
+            after: after.to_string(),
+            size_delta,
+        };
+        if before == "∅" {
+            diff.added.push(change);
+        } else if after == "∅" {
+            diff.removed.push(change);
+        } else {
+            diff.changed.push(change);
+        }
+    }
+
+    diff
+}
+
+/// Last whitespace separated field of a line, as a number
+fn last_number(line: &str) -> u64 {
+    line.split_whitespace()
+        .last()
+        .and_then(|field| field.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Remove ANSI escape sequences, which nix prints even when piped
+fn strip_ansi(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip up to and including the final byte of the CSI sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_diff() {
+        let output = "size-current /nix/store/aaa-nixos-system\t1000\n\
+                      size-new /nix/store/bbb-nixos-system\t1500\n\
+                      firefox: 120.0 → 121.0, \x1b[31;1m+1234.5 KiB\x1b[0m\n\
+                      htop: ∅ → 3.3.0, +200.0 KiB\n\
+                      nano: 7.2 → ∅, -900.0 KiB\n\
+                      glibc: +0.1 KiB\n\
+                      python3: 3.11.6, 3.12.0 → 3.12.1\n";
+        let diff = parse_diff(output);
+
+        assert_eq!(diff.size_delta(), 500);
+        assert_eq!(diff.added[0].name, "htop");
+        assert_eq!(diff.removed[0].name, "nano");
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(diff.changed[0].after, "121.0");
+        assert_eq!(diff.changed[0].size_delta.as_deref(), Some("+1234.5 KiB"));
+        assert_eq!(diff.changed[1].before, "3.11.6, 3.12.0");
+        assert_eq!(diff.resized, 1);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::terminal::VirtualTerminal;
+
+/// What a diagnostic reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    Error,
+    Warning,
+    /// A derivation's builder exited with an error
+    BuilderFailed,
+}
+
+/// An error or warning found in build output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    /// `/path:line:col` of the innermost `at` frame
+    pub location: Option<String>,
+    /// `… while evaluating` frames, outermost first
+    pub trace: Vec<String>,
+    /// Output line the diagnostic starts on, as used for scrolling
+    pub line: usize,
+}
+
+/// Diagnostics of a terminal, re-extracted only when its output changed
+#[derive(Default)]
+pub struct DiagnosticsCache {
+    revision: Option<u64>,
+    entries: Vec<Diagnostic>,
+}
+
+impl DiagnosticsCache {
+    pub fn refresh(&mut self, terminal: &VirtualTerminal) {
+        if self.revision != Some(terminal.revision()) {
+            self.entries = extract(terminal);
+            self.revision = Some(terminal.revision());
+        }
+    }
+
+    pub fn entries(&self) -> &[Diagnostic] {
+        &self.entries
+    }
+}
+
+/// Find the errors, warnings and failed builders in a terminal's output
+pub fn extract(terminal: &VirtualTerminal) -> Vec<Diagnostic> {
+    parse(&terminal.logical_lines())
+}
+
+/// Parse lines of nix output, each with the output line it starts on
+/// An `error:` block lasts for as long as the lines after it are indented
+fn parse(lines: &[(usize, String)]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut block: Option<Diagnostic> = None;
+
+    for (line, text) in lines {
+        let trimmed = text.trim_start();
+        let indented = trimmed.len() < text.len();
+
+        if let Some(ref mut diagnostic) = block {
+            if indented || trimmed.is_empty() {
+                if let Some(frame) = trimmed.strip_prefix("… ") {
+                    diagnostic.trace.push(frame.to_string());
+                } else if let Some(location) = trimmed.strip_prefix("at ") {
+                    diagnostic.location = Some(location.trim_end_matches(':').to_string());
+                } else if let Some(message) = trimmed.strip_prefix("error: ") {
+                    // With a trace, the actual message comes last
+                    diagnostic.message = message.to_string();
+                }
+                continue;
+            }
+            diagnostics.extend(block.take());
+        }
+
+        let start = |kind, message: &str| Diagnostic {
+            kind,
+            message: message.trim().trim_end_matches([';', '.']).to_string(),
+            location: None,
+            trace: Vec::new(),
+            line: *line,
+        };
+        if let Some(message) = text.strip_prefix("error:") {
+            let kind = if is_builder_failure(message.trim_start()) {
+                DiagnosticKind::BuilderFailed
+            } else {
+                DiagnosticKind::Error
+            };
+            block = Some(start(kind, message));
+        } else if is_builder_failure(text) {
+            // Older nix reports failed builders without the error prefix
+            diagnostics.push(start(DiagnosticKind::BuilderFailed, text));
+        } else if let Some(message) = text
+            .strip_prefix("evaluation warning:")
+            .or_else(|| text.strip_prefix("trace: warning:"))
+        {
+            diagnostics.push(start(DiagnosticKind::Warning, message));
+        }
+    }
+
+    diagnostics.extend(block);
+    diagnostics
+}
+
+/// "builder for '…drv' failed with exit code 1" or "Cannot build '…drv'."
+fn is_builder_failure(message: &str) -> bool {
+    (message.starts_with("builder for '") && message.contains("' failed"))
+        || message.starts_with("Cannot build '")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nix_output() {
+        let output = "building the system configuration...\n\
+                      evaluation warning: The option `foo' is deprecated\n\
+                      error:\n       \
+                      … while calling the 'head' builtin\n\n         \
+                      at /nix/store/abc-source/lib/lists.nix:12:5:\n\n         \
+                      11|\n       \
+                      … while evaluating the attribute 'value'\n\n         \
+                      at /etc/nixos/hosts/web.nix:40:3:\n\n       \
+                      error: undefined variable 'pkgz'\n\
+                      error: builder for '/nix/store/xyz-hello.drv' failed with exit code 2;\n       \
+                      last 10 log lines:\n       \
+                      > make: *** [Makefile:3: all] Error 2\n\
+                      trace: warning: old style\n";
+        let lines: Vec<(usize, String)> = output
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx, line.to_string()))
+            .collect();
+        let diagnostics = parse(&lines);
+
+        assert_eq!(diagnostics.len(), 4);
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::Warning);
+        assert_eq!(diagnostics[0].message, "The option `foo' is deprecated");
+
+        let error = &diagnostics[1];
+        assert_eq!(error.kind, DiagnosticKind::Error);
+        assert_eq!(error.message, "undefined variable 'pkgz'");
+        assert_eq!(
+            error.location.as_deref(),
+            Some("/etc/nixos/hosts/web.nix:40:3")
+        );
+        assert_eq!(error.trace.len(), 2);
+        assert_eq!(error.line, 2);
+
+        assert_eq!(diagnostics[2].kind, DiagnosticKind::BuilderFailed);
+        assert_eq!(
+            diagnostics[2].message,
+            "builder for '/nix/store/xyz-hello.drv' failed with exit code 2"
+        );
+        assert_eq!(diagnostics[3].message, "old style");
+    }
+}
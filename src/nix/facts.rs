@@ -0,0 +1,102 @@
+use crate::config::Connection;
+use crate::nix::remote;
+use color_eyre::Result;
+use std::time::Duration;
+
+/// Prints one `key value` line per fact, plus a `changed <part>` line for
+/// each boot part of the booted system that differs from the current one
+const FACTS_SCRIPT: &str = r#"
+current=$(readlink -f /run/current-system)
+booted=$(readlink -f /run/booted-system)
+echo "version $(nixos-version 2>/dev/null || cat "$current/nixos-version" 2>/dev/null)"
+echo "current $current"
+echo "booted $booted"
+echo "kernel $(uname -r)"
+echo "uptime $(cut -d ' ' -f 1 /proc/uptime)"
+for part in kernel initrd kernel-modules; do
+    if [ "$(readlink -f "$booted/$part")" != "$(readlink -f "$current/$part")" ]; then
+        echo "changed $part"
+    fi
+done
+"#;
+
+/// What a host is running right now
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostFacts {
+    pub nixos_version: String,
+    /// Store path of `/run/current-system`
+    pub current_system: String,
+    /// Store path of `/run/booted-system`
+    pub booted_system: String,
+    /// Release of the running kernel
+    pub kernel: String,
+    pub uptime: Duration,
+    /// Kernel, initrd or modules of the current system that aren't the
+    /// booted ones
+    pub changed_boot_parts: Vec<String>,
+}
+
+impl HostFacts {
+    /// The current system only takes full effect after a reboot
+    pub fn reboot_required(&self) -> bool {
+        !self.changed_boot_parts.is_empty()
+    }
+}
+
+/// Gather the facts of a host
+/// Blocks on the host (and the network for remote ones)
+pub fn gather(connection: &Connection) -> Result<HostFacts> {
+    let output = remote::run_script(connection, FACTS_SCRIPT)?;
+    Ok(parse_facts(&output))
+}
+
+/// Parse the output of the facts script, ignoring unknown lines
+fn parse_facts(output: &str) -> HostFacts {
+    let mut facts = HostFacts::default();
+    for line in output.lines() {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let value = value.trim().to_string();
+        match key {
+            "version" => facts.nixos_version = value,
+            "current" => facts.current_system = value,
+            "booted" => facts.booted_system = value,
+            "kernel" => facts.kernel = value,
+            "uptime" => {
+                let secs = value.parse::<f64>().unwrap_or(0.0);
+                facts.uptime = Duration::from_secs(secs as u64);
+            }
+            "changed" => facts.changed_boot_parts.push(value),
+            _ => {}
+        }
+    }
+    facts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_facts() {
+        let output = "version 24.05.5678.123456 (Uakari)\n\
+                      current /nix/store/aaa-nixos-system-web-24.05\n\
+                      booted /nix/store/bbb-nixos-system-web-24.05\n\
+                      kernel 6.6.30\n\
+                      uptime 93784.52\n\
+                      changed kernel\n\
+                      changed kernel-modules\n";
+        let facts = parse_facts(output);
+
+        assert_eq!(facts.nixos_version, "24.05.5678.123456 (Uakari)");
+        assert_eq!(
+            facts.current_system,
+            "/nix/store/aaa-nixos-system-web-24.05"
+        );
+        assert_eq!(facts.kernel, "6.6.30");
+        assert_eq!(facts.uptime, Duration::from_secs(93784));
+        assert!(facts.reboot_required());
+        assert_eq!(facts.changed_boot_parts, vec!["kernel", "kernel-modules"]);
+
+        assert!(!parse_facts("version 24.05\nuptime 12\n").reboot_required());
+    }
+}
@@ -1,7 +1,42 @@
+use crate::config::Connection;
+use crate::nix::rebuild::PtyCommand;
+use crate::nix::remote;
 use color_eyre::{eyre::Context, Result};
 use serde_json::Value;
 use std::collections::HashSet;
-use std::process::Command;
+use std::process::{Command, Stdio};
+
+/// Whether a host runs the system its flake configuration evaluates to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    UpToDate,
+    /// The host runs another system than the flake would build
+    Drifted {
+        expected: String,
+        running: String,
+    },
+    /// Evaluating the configuration or reaching the host failed
+    Unknown(String),
+}
+
+impl Drift {
+    /// Single-character badge for the host list
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::UpToDate => "=",
+            Self::Drifted { .. } => "≠",
+            Self::Unknown(_) => "?",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::UpToDate => "up to date".to_string(),
+            Self::Drifted { .. } => "drifted from the flake".to_string(),
+            Self::Unknown(reason) => format!("drift unknown: {}", reason),
+        }
+    }
+}
 
 /// Discover NixOS configurations from a flake
 /// Returns a set of configuration names found under nixosConfigurations
@@ -37,6 +72,221 @@ pub fn discover_configurations(flake_path: &str) -> Result<HashSet<String>> {
     Ok(configs)
 }
 
+/// A direct input of a flake, as locked in its flake.lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlakeInput {
+    pub name: String,
+    /// Where the input comes from, e.g. "github:NixOS/nixpkgs/nixos-24.05"
+    pub source: String,
+    /// Locked git revision, or the NAR hash of inputs without one
+    pub rev: Option<String>,
+    /// Unix timestamp of the locked revision
+    pub last_modified: Option<u64>,
+    /// Input this one follows instead of being locked itself
+    pub follows: Option<String>,
+}
+
+/// An input whose lock changed between two reads of flake.lock
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputChange {
+    pub name: String,
+    /// Revision before and after; None if the input was added or removed
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Run `nix flake metadata` on a flake
+fn flake_metadata(flake_path: &str) -> Result<Value> {
+    let output = Command::new("nix")
+        .args(["flake", "metadata", "--json", flake_path])
+        .output()
+        .wrap_err("Failed to execute nix flake metadata")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(color_eyre::eyre::eyre!(
+            "nix flake metadata failed: {}",
+            stderr
+        ));
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .wrap_err("Failed to parse nix flake metadata JSON output")
+}
+
+/// Get the git revision a flake is locked to
+/// Returns the `dirtyRevision` for trees with uncommitted changes, or None if
+/// the flake is not in a git repository
+pub fn flake_revision(flake_path: &str) -> Result<Option<String>> {
+    let json = flake_metadata(flake_path)?;
+    Ok(json
+        .get("revision")
+        .or_else(|| json.get("dirtyRevision"))
+        .and_then(Value::as_str)
+        .map(str::to_string))
+}
+
+/// List the direct inputs of a flake, sorted by name
+pub fn flake_inputs(flake_path: &str) -> Result<Vec<FlakeInput>> {
+    Ok(parse_inputs(&flake_metadata(flake_path)?))
+}
+
+/// Read the root's inputs from the `locks` of `nix flake metadata --json`
+fn parse_inputs(metadata: &Value) -> Vec<FlakeInput> {
+    let nodes = &metadata["locks"]["nodes"];
+    let root = metadata["locks"]["root"].as_str().unwrap_or("root");
+    let Some(inputs) = nodes[root]["inputs"].as_object() else {
+        return Vec::new();
+    };
+
+    let mut parsed: Vec<FlakeInput> = inputs
+        .iter()
+        .map(|(name, node)| {
+            // A list is the path of the input this one follows
+            if let Some(path) = node.as_array() {
+                let path: Vec<&str> = path.iter().filter_map(Value::as_str).collect();
+                return FlakeInput {
+                    name: name.clone(),
+                    source: String::new(),
+                    rev: None,
+                    last_modified: None,
+                    follows: Some(path.join("/")),
+                };
+            }
+            let node = &nodes[node.as_str().unwrap_or(name)];
+            let locked = &node["locked"];
+            FlakeInput {
+                name: name.clone(),
+                source: describe_source(&node["original"]),
+                rev: locked["rev"]
+                    .as_str()
+                    .or_else(|| locked["narHash"].as_str())
+                    .map(str::to_string),
+                last_modified: locked["lastModified"].as_u64(),
+                follows: None,
+            }
+        })
+        .collect();
+    parsed.sort_by(|a, b| a.name.cmp(&b.name));
+    parsed
+}
+
+/// Flake reference of an input's `original` attributes
+fn describe_source(original: &Value) -> String {
+    let field = |key: &str| original[key].as_str().unwrap_or("");
+    match field("type") {
+        kind @ ("github" | "gitlab" | "sourcehut") => {
+            let mut source = format!("{}:{}/{}", kind, field("owner"), field("repo"));
+            if let Some(reference) = original["ref"].as_str() {
+                source = format!("{}/{}", source, reference);
+            }
+            source
+        }
+        "indirect" => field("id").to_string(),
+        "path" => field("path").to_string(),
+        _ => field("url").to_string(),
+    }
+}
+
+/// PTY command updating one input of a flake, or all of them
+pub fn update_command(
+    flake_path: &str,
+    input: Option<&str>,
+    pty_cols: u16,
+    pty_rows: u16,
+) -> PtyCommand {
+    let mut args = vec!["flake".to_string(), "update".to_string()];
+    args.extend(input.map(str::to_string));
+    args.extend(["--flake".to_string(), flake_path.to_string()]);
+    PtyCommand::new("nix", args, pty_cols, pty_rows)
+}
+
+/// Inputs whose locked revision differs, including added and removed ones
+pub fn diff_inputs(before: &[FlakeInput], after: &[FlakeInput]) -> Vec<InputChange> {
+    let rev = |inputs: &[FlakeInput], name: &str| {
+        inputs
+            .iter()
+            .find(|input| input.name == name)
+            .map(|input| input.rev.clone().or_else(|| input.follows.clone()))
+    };
+
+    let mut names: Vec<&str> = before
+        .iter()
+        .chain(after)
+        .map(|input| input.name.as_str())
+        .collect();
+    names.sort();
+    names.dedup();
+
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let (old, new) = (rev(before, name), rev(after, name));
+            (old != new).then(|| InputChange {
+                name: name.to_string(),
+                before: old.flatten(),
+                after: new.flatten(),
+            })
+        })
+        .collect()
+}
+
+/// Flake attribute of a configuration's system toplevel store path
+fn toplevel_attr(flake_path: &str, config_name: &str) -> String {
+    format!(
+        "{}#nixosConfigurations.\"{}\".config.system.build.toplevel.outPath",
+        flake_path, config_name
+    )
+}
+
+/// Evaluate the store path a configuration would build, without building it
+pub fn toplevel_path(flake_path: &str, config_name: &str) -> Result<String> {
+    let output = Command::new("nix")
+        .args(["eval", "--raw", &toplevel_attr(flake_path, config_name)])
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("Failed to execute nix eval")?;
+
+    if !output.status.success() {
+        // The last line of nix's error is the actual message
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.lines().rev().find(|l| !l.trim().is_empty());
+        return Err(color_eyre::eyre::eyre!(
+            "nix eval failed: {}",
+            message.unwrap_or("no output").trim()
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Compare what a configuration evaluates to with the host's
+/// `/run/current-system`
+/// Blocks on the evaluation and the host (and the network for remote ones)
+pub fn check_drift(flake_path: &str, config_name: &str, connection: &Connection) -> Drift {
+    let expected = match toplevel_path(flake_path, config_name) {
+        Ok(path) => path,
+        Err(e) => return Drift::Unknown(e.to_string()),
+    };
+    match remote::run_script(connection, "readlink -f /run/current-system\n") {
+        Ok(running) => compare_systems(expected, running.trim()),
+        Err(e) => Drift::Unknown(e.to_string()),
+    }
+}
+
+fn compare_systems(expected: String, running: &str) -> Drift {
+    if running.is_empty() {
+        Drift::Unknown("host has no /run/current-system".to_string())
+    } else if expected == running {
+        Drift::UpToDate
+    } else {
+        Drift::Drifted {
+            expected,
+            running: running.to_string(),
+        }
+    }
+}
+
 /// Get the current hostname
 pub fn get_hostname() -> Result<String> {
     let output = Command::new("hostname")
@@ -83,4 +333,79 @@ mod tests {
         assert!(configs.contains("remote-server"));
         assert_eq!(configs.len(), 2);
     }
+
+    #[test]
+    fn test_parse_inputs() {
+        let metadata = serde_json::json!({
+            "locks": {
+                "root": "root",
+                "nodes": {
+                    "root": {
+                        "inputs": {
+                            "nixpkgs": "nixpkgs_2",
+                            "home-manager": "home-manager",
+                            "utils": ["home-manager", "utils"]
+                        }
+                    },
+                    "nixpkgs_2": {
+                        "locked": { "lastModified": 1700000000, "rev": "abc123", "type": "github" },
+                        "original": { "owner": "NixOS", "repo": "nixpkgs", "ref": "nixos-24.05", "type": "github" }
+                    },
+                    "home-manager": {
+                        "locked": { "narHash": "sha256-xyz", "type": "path" },
+                        "original": { "id": "home-manager", "type": "indirect" }
+                    }
+                }
+            }
+        });
+        let inputs = parse_inputs(&metadata);
+
+        assert_eq!(
+            inputs.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(),
+            vec!["home-manager", "nixpkgs", "utils"]
+        );
+        assert_eq!(inputs[1].source, "github:NixOS/nixpkgs/nixos-24.05");
+        assert_eq!(inputs[1].rev.as_deref(), Some("abc123"));
+        assert_eq!(inputs[1].last_modified, Some(1700000000));
+        assert_eq!(inputs[0].rev.as_deref(), Some("sha256-xyz"));
+        assert_eq!(inputs[2].follows.as_deref(), Some("home-manager/utils"));
+
+        let mut updated = inputs.clone();
+        updated[1].rev = Some("def456".to_string());
+        updated.remove(0);
+        assert_eq!(
+            diff_inputs(&inputs, &updated),
+            vec![
+                InputChange {
+                    name: "home-manager".to_string(),
+                    before: Some("sha256-xyz".to_string()),
+                    after: None,
+                },
+                InputChange {
+                    name: "nixpkgs".to_string(),
+                    before: Some("abc123".to_string()),
+                    after: Some("def456".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        assert_eq!(
+            toplevel_attr("/etc/nixos", "web.example"),
+            "/etc/nixos#nixosConfigurations.\"web.example\".config.system.build.toplevel.outPath"
+        );
+
+        let expected = "/nix/store/aaa-nixos-system-web".to_string();
+        assert_eq!(
+            compare_systems(expected.clone(), "/nix/store/aaa-nixos-system-web"),
+            Drift::UpToDate
+        );
+        assert!(matches!(
+            compare_systems(expected.clone(), "/nix/store/bbb-nixos-system-web"),
+            Drift::Drifted { .. }
+        ));
+        assert!(matches!(compare_systems(expected, ""), Drift::Unknown(_)));
+    }
 }
@@ -0,0 +1,131 @@
+use crate::app::RebuildOperation;
+use crate::config::Connection;
+use crate::nix::rebuild::PtyCommand;
+use crate::nix::remote::{self, shell_quote};
+use color_eyre::Result;
+
+/// The system profile every generation link belongs to
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Lists the system generations, one tab separated line each:
+/// number, link mtime, NixOS version, kernel version, current, booted
+const LIST_SCRIPT: &str = r#"
+profile=/nix/var/nix/profiles/system
+current=$(readlink -f "$profile")
+booted=$(readlink -f /run/booted-system)
+for link in "$profile"-*-link; do
+    [ -e "$link" ] || continue
+    number=${link#"$profile"-}
+    number=${number%-link}
+    path=$(readlink -f "$link")
+    date=$(stat -c %Y "$link")
+    version=$(cat "$path/nixos-version" 2>/dev/null)
+    kernel=$(ls "$path/kernel-modules/lib/modules" 2>/dev/null | head -n 1)
+    is_current=0; [ "$path" = "$current" ] && is_current=1
+    is_booted=0; [ "$path" = "$booted" ] && is_booted=1
+    printf '%s\t%s\t%s\t%s\t%s\t%s\n' "$number" "$date" "$version" "$kernel" "$is_current" "$is_booted"
+done
+"#;
+
+/// A system generation of a host
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub number: u32,
+    /// Unix timestamp the generation was created at
+    pub created_at: u64,
+    pub nixos_version: String,
+    pub kernel: String,
+    /// The system profile points at this generation
+    pub current: bool,
+    /// The host is running the kernel and initrd of this generation
+    pub booted: bool,
+}
+
+/// List the system generations of a host, newest first
+/// Blocks on the host (and the network for remote ones)
+pub fn list_generations(connection: &Connection) -> Result<Vec<Generation>> {
+    let output = remote::run_script(connection, LIST_SCRIPT)?;
+    Ok(parse_generations(&output))
+}
+
+/// Parse the output of the listing script, skipping malformed lines
+fn parse_generations(output: &str) -> Vec<Generation> {
+    let mut generations: Vec<Generation> = output
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [number, date, version, kernel, current, booted] = fields[..] else {
+                return None;
+            };
+            Some(Generation {
+                number: number.parse().ok()?,
+                created_at: date.parse().unwrap_or(0),
+                nixos_version: version.to_string(),
+                kernel: kernel.to_string(),
+                current: current == "1",
+                booted: booted == "1",
+            })
+        })
+        .collect();
+
+    generations.sort_by_key(|g| std::cmp::Reverse(g.number));
+    generations
+}
+
+/// PTY command that makes a generation current and activates it, the way
+/// `switch --rollback` does for the previous one
+pub fn switch_generation_command(
+    connection: &Connection,
+    number: u32,
+    pty_cols: u16,
+    pty_rows: u16,
+) -> Option<PtyCommand> {
+    let command = format!(
+        "nix-env -p {profile} --switch-generation {number} && {profile}/bin/switch-to-configuration switch",
+        profile = SYSTEM_PROFILE,
+        number = number
+    );
+    remote::root_shell_command(connection, &command, pty_cols, pty_rows)
+}
+
+/// PTY command that points the system profile at an already built toplevel
+/// and activates it, like the tail end of `nixos-rebuild switch` or `boot`
+pub fn activate_command(
+    connection: &Connection,
+    toplevel: &str,
+    operation: RebuildOperation,
+    pty_cols: u16,
+    pty_rows: u16,
+) -> Option<PtyCommand> {
+    let toplevel = shell_quote(toplevel);
+    let command = format!(
+        "nix-env -p {profile} --set {toplevel} && {toplevel}/bin/switch-to-configuration {action}",
+        profile = SYSTEM_PROFILE,
+        toplevel = toplevel,
+        action = operation.as_str()
+    );
+    remote::root_shell_command(connection, &command, pty_cols, pty_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_generations() {
+        let output = "41\t1700000000\t23.11.1234.abcdef\t6.1.62\t0\t1\n\
+                      42\t1700100000\t24.05.5678.123456\t6.6.30\t1\t0\n\
+                      garbage line\n\
+                      43\t1700200000\t\t\t0\t0\n";
+        let generations = parse_generations(output);
+
+        assert_eq!(
+            generations.iter().map(|g| g.number).collect::<Vec<_>>(),
+            vec![43, 42, 41]
+        );
+        assert!(generations[1].current && !generations[1].booted);
+        assert!(generations[2].booted);
+        assert_eq!(generations[2].kernel, "6.1.62");
+        assert_eq!(generations[0].nixos_version, "");
+    }
+}
@@ -1,5 +1,12 @@
+pub mod closure;
+pub mod diagnostics;
+pub mod facts;
 pub mod flake;
+pub mod generations;
+pub mod progress;
+pub mod reachability;
 pub mod rebuild;
+pub mod remote;
 
 pub use flake::discover_configurations;
 pub use rebuild::RebuildCommand;
@@ -0,0 +1,258 @@
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// Prefix of the lines nix writes with `--log-format internal-json`
+const JSON_PREFIX: &[u8] = b"@nix ";
+
+// Activity and result types, from nix's logging.hh
+const ACT_COPY_PATH: u64 = 100;
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_SET_PHASE: u64 = 104;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+const RES_POST_BUILD_LOG_LINE: u64 = 107;
+
+/// Most verbose message level shown in the output: info
+const MAX_LEVEL: u64 = 3;
+
+/// A derivation being built right now
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningBuild {
+    /// Derivation name without store hash and `.drv`
+    pub name: String,
+    pub phase: Option<String>,
+}
+
+/// Counters gathered from nix's activity messages
+#[derive(Debug, Default)]
+pub struct BuildProgress {
+    pub builds_done: u64,
+    pub builds_expected: u64,
+    pub builds_failed: u64,
+    /// Store paths copied or substituted
+    pub paths_done: u64,
+    pub paths_expected: u64,
+    /// Builders running now, by activity id
+    pub running: BTreeMap<u64, RunningBuild>,
+    /// Bytes done and expected of each download or copy, by activity id
+    transfers: HashMap<u64, (u64, u64)>,
+    /// Paths each activity announced it will copy
+    expected_paths: HashMap<u64, u64>,
+}
+
+impl BuildProgress {
+    /// Bytes downloaded or copied so far, and the total expected
+    pub fn bytes(&self) -> (u64, u64) {
+        self.transfers
+            .values()
+            .fold((0, 0), |(done, expected), (d, e)| (done + d, expected + e))
+    }
+}
+
+/// Where the parser is within the current line
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum LineState {
+    /// At the start of a line, possibly reading the JSON prefix
+    #[default]
+    Start,
+    /// In a plain line, which is passed through as it arrives
+    Plain,
+    /// In a JSON line, which is held until it is complete
+    Json,
+}
+
+/// Splits nix's internal-json messages out of build output
+/// Plain output passes through untouched, so prompts still show up before
+/// their line ends. JSON lines update the progress and are turned back into
+/// the log lines nix would have printed
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+    pub progress: BuildProgress,
+    /// Some structured message has been seen
+    pub active: bool,
+    state: LineState,
+    pending: Vec<u8>,
+    /// Type and derivation name of each started activity
+    activities: HashMap<u64, (u64, Option<String>)>,
+}
+
+impl ProgressParser {
+    /// Feed raw output, returning what should be shown of it
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            match self.state {
+                LineState::Start => {
+                    self.pending.push(byte);
+                    if !JSON_PREFIX.starts_with(&self.pending) {
+                        out.append(&mut self.pending);
+                        if byte != b'\n' {
+                            self.state = LineState::Plain;
+                        }
+                    } else if self.pending.len() == JSON_PREFIX.len() {
+                        self.state = LineState::Json;
+                    }
+                }
+                LineState::Plain => {
+                    out.push(byte);
+                    if byte == b'\n' {
+                        self.state = LineState::Start;
+                    }
+                }
+                LineState::Json if byte == b'\n' => {
+                    let line = std::mem::take(&mut self.pending);
+                    self.handle_line(&line, &mut out);
+                    self.state = LineState::Start;
+                }
+                LineState::Json => self.pending.push(byte),
+            }
+        }
+        out
+    }
+
+    /// Return an unfinished last line once the output has ended
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.state = LineState::Start;
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Apply a complete `@nix` line, passing it through if it isn't valid
+    fn handle_line(&mut self, line: &[u8], out: &mut Vec<u8>) {
+        let json = line[JSON_PREFIX.len()..].trim_ascii_end();
+        let Ok(message) = serde_json::from_slice::<Value>(json) else {
+            out.extend_from_slice(line);
+            out.push(b'\n');
+            return;
+        };
+        self.active = true;
+
+        let number = |key: &str| message[key].as_u64().unwrap_or(0);
+        let fields = message["fields"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or(&[]);
+        let field = |idx: usize| fields.get(idx).and_then(Value::as_u64).unwrap_or(0);
+        let id = number("id");
+
+        match message["action"].as_str() {
+            Some("msg") if number("level") <= MAX_LEVEL => {
+                push_line(out, None, message["msg"].as_str().unwrap_or(""));
+            }
+            Some("start") => {
+                let kind = number("type");
+                let name = (kind == ACT_BUILD)
+                    .then(|| fields.first().and_then(Value::as_str).map(derivation_name))
+                    .flatten();
+                if let Some(ref name) = name {
+                    self.progress.running.insert(
+                        id,
+                        RunningBuild {
+                            name: name.clone(),
+                            phase: None,
+                        },
+                    );
+                }
+                self.activities.insert(id, (kind, name));
+
+                let text = message["text"].as_str().unwrap_or("");
+                if number("level") <= MAX_LEVEL && !text.is_empty() {
+                    push_line(out, None, text);
+                }
+            }
+            Some("stop") => {
+                self.progress.running.remove(&id);
+                if let Some((ACT_COPY_PATH, _)) = self.activities.remove(&id) {
+                    self.progress.paths_done += 1;
+                }
+            }
+            Some("result") => {
+                let (kind, name) = self.activities.get(&id).cloned().unwrap_or((0, None));
+                match number("type") {
+                    RES_BUILD_LOG_LINE | RES_POST_BUILD_LOG_LINE => {
+                        let text = fields.first().and_then(Value::as_str).unwrap_or("");
+                        push_line(out, name.as_deref(), text);
+                    }
+                    RES_SET_PHASE => {
+                        if let Some(build) = self.progress.running.get_mut(&id) {
+                            build.phase = fields.first().and_then(Value::as_str).map(String::from);
+                        }
+                    }
+                    RES_PROGRESS if kind == ACT_BUILDS => {
+                        self.progress.builds_done = field(0);
+                        self.progress.builds_expected = field(1);
+                        self.progress.builds_failed = field(3);
+                    }
+                    RES_PROGRESS if kind == ACT_COPY_PATH || kind == ACT_FILE_TRANSFER => {
+                        self.progress.transfers.insert(id, (field(0), field(1)));
+                    }
+                    RES_SET_EXPECTED if field(0) == ACT_COPY_PATH => {
+                        self.progress.expected_paths.insert(id, field(1));
+                        self.progress.paths_expected = self.progress.expected_paths.values().sum();
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Append a line of text, prefixed with the derivation it came from
+fn push_line(out: &mut Vec<u8>, name: Option<&str>, text: &str) {
+    for line in text.lines() {
+        if let Some(name) = name {
+            out.extend_from_slice(format!("{}> ", name).as_bytes());
+        }
+        out.extend_from_slice(line.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// `hello-2.12` from `/nix/store/<hash>-hello-2.12.drv`
+fn derivation_name(path: &str) -> String {
+    let file = path.rsplit('/').next().unwrap_or(path);
+    let name = file.split_once('-').map_or(file, |(_, name)| name);
+    name.trim_end_matches(".drv").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_internal_json() {
+        let mut parser = ProgressParser::default();
+        let mut out = parser.feed(b"building the system configuration...\r\n@nix {\"action\":\"start\",\"id\":1,\"level\":3,\"type\":104,\"text\":\"\",\"fields\":[]}\r\n");
+        out.extend(parser.feed(b"@nix {\"action\":\"result\",\"id\":1,\"type\":105,\"fields\":[1,3,1,0]}\r\n@nix {\"action\":\"start\",\"id\":2,\"level\":3,\"type\":105,"));
+        out.extend(parser.feed(b"\"text\":\"building '/nix/store/abc-hello-2.12.drv'\",\"fields\":[\"/nix/store/abc-hello-2.12.drv\",\"\",1,1]}\r\n"));
+        out.extend(parser.feed(
+            b"@nix {\"action\":\"result\",\"id\":2,\"type\":104,\"fields\":[\"buildPhase\"]}\r\n",
+        ));
+        out.extend(parser.feed(
+            b"@nix {\"action\":\"result\",\"id\":2,\"type\":101,\"fields\":[\"make all\"]}\r\n",
+        ));
+        out.extend(parser.feed(b"@nix {\"action\":\"start\",\"id\":3,\"level\":4,\"type\":100,\"text\":\"copying\",\"fields\":[]}\r\n"));
+        out.extend(parser.feed(
+            b"@nix {\"action\":\"result\",\"id\":3,\"type\":105,\"fields\":[512,1024,0,0]}\r\n",
+        ));
+        out.extend(parser.feed(b"@nix {\"action\":\"stop\",\"id\":3}\r\n[sudo] password: "));
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "building the system configuration...\r\n\
+             building '/nix/store/abc-hello-2.12.drv'\r\n\
+             hello-2.12> make all\r\n\
+             [sudo] password: "
+        );
+
+        let progress = &parser.progress;
+        assert_eq!((progress.builds_done, progress.builds_expected), (1, 3));
+        assert_eq!(progress.running[&2].name, "hello-2.12");
+        assert_eq!(progress.running[&2].phase.as_deref(), Some("buildPhase"));
+        assert_eq!(progress.paths_done, 1);
+        assert_eq!(progress.bytes(), (512, 1024));
+    }
+}
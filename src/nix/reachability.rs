@@ -0,0 +1,151 @@
+use crate::config::Connection;
+use std::process::{Command, Output, Stdio};
+
+/// How long ssh may take to connect before a host counts as offline
+const CONNECT_TIMEOUT_SECS: u32 = 5;
+
+/// ssh's exit code for its own errors, as opposed to the remote command's
+const SSH_ERROR: i32 = 255;
+
+/// Whether a host can be deployed to right now
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reachability {
+    Online,
+    /// Unreachable, or the local nix daemon isn't responding
+    Offline(String),
+    /// The host answered but wouldn't let us in without a prompt
+    AuthFailed(String),
+}
+
+impl Reachability {
+    /// Single-character badge for the host list
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Online => "●",
+            Self::Offline(_) => "○",
+            Self::AuthFailed(_) => "⚷",
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Online => "online".to_string(),
+            Self::Offline(reason) => format!("offline: {}", reason),
+            Self::AuthFailed(reason) => format!("auth failed: {}", reason),
+        }
+    }
+}
+
+/// Check a host: remote hosts over ssh in batch mode with a short connect
+/// timeout, the local machine by pinging its nix store
+/// Blocks for up to the connect timeout
+pub fn check(connection: &Connection) -> Reachability {
+    let output = match connection {
+        Connection::Local => Command::new("nix")
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "store",
+                "ping",
+            ])
+            .stdin(Stdio::null())
+            .output(),
+        Connection::Remote(host) => Command::new("ssh")
+            .args(host.ssh_args())
+            .args([
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                &format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS),
+                &host.target,
+                "true",
+            ])
+            .stdin(Stdio::null())
+            .output(),
+        Connection::Unconfigured => {
+            return Reachability::Offline("not configured".to_string());
+        }
+    };
+
+    match output {
+        Ok(output) => classify(connection, &output),
+        Err(e) => Reachability::Offline(e.to_string()),
+    }
+}
+
+/// Tell a refused login apart from a host that can't be reached
+fn classify(connection: &Connection, output: &Output) -> Reachability {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .unwrap_or("no response")
+        .to_string();
+
+    match connection {
+        Connection::Remote(_) if output.status.code() == Some(SSH_ERROR) => {
+            if reason.contains("Permission denied")
+                || reason.contains("Host key verification failed")
+                || reason.contains("Too many authentication failures")
+            {
+                Reachability::AuthFailed(reason)
+            } else {
+                Reachability::Offline(reason)
+            }
+        }
+        // ssh got in; only `true` itself could have failed
+        Connection::Remote(_) => Reachability::Online,
+        _ if output.status.success() => Reachability::Online,
+        _ => Reachability::Offline(reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RemoteHost;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    fn output(code: i32, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_classify_ssh_errors() {
+        let remote = Connection::Remote(RemoteHost::new("admin@web"));
+        assert_eq!(classify(&remote, &output(0, "")), Reachability::Online);
+        assert_eq!(
+            classify(
+                &remote,
+                &output(
+                    255,
+                    "Warning: ...\nadmin@web: Permission denied (publickey).\n"
+                )
+            ),
+            Reachability::AuthFailed("admin@web: Permission denied (publickey).".to_string())
+        );
+        assert_eq!(
+            classify(
+                &remote,
+                &output(
+                    255,
+                    "ssh: connect to host web port 22: Connection timed out\n"
+                )
+            ),
+            Reachability::Offline(
+                "ssh: connect to host web port 22: Connection timed out".to_string()
+            )
+        );
+        assert!(matches!(
+            classify(&Connection::Local, &output(1, "cannot connect to socket")),
+            Reachability::Offline(_)
+        ));
+    }
+}
@@ -1,24 +1,197 @@
-use color_eyre::Result;
-use portable_pty::{CommandBuilder, NativePtySystem, PtySize, PtySystem};
+use color_eyre::{eyre::eyre, Result};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use portable_pty::{CommandBuilder, MasterPty, NativePtySystem, PtySize, PtySystem};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+/// How long to wait for the PTY to reach EOF after the process has exited,
+/// before Exited is sent regardless
+const READER_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 use crate::app::RebuildOperation;
-use crate::config::Connection;
+use crate::config::hosts::{nix_sshopts, ssh_config};
+use crate::config::{Config, Connection, RemoteHost};
+use crate::git::GitRef;
+use crate::history::{self, RunInfo};
 
 pub struct RebuildCommand {
     pub operation: RebuildOperation,
     pub flake_path: Option<String>,
     pub config_name: String,
     pub connection: Connection,
+    /// Where to build, if not on the machine running nixos-rebuild
+    pub build_host: Option<Connection>,
+    /// Commit of the flake's repository to build instead of its working tree
+    pub git_ref: Option<GitRef>,
     pub extra_args: Vec<String>,
+    pub use_upgrade: bool,
+    /// Pass `--log-format internal-json` so progress can be followed
+    pub structured_log: bool,
+    /// Make ssh and sudo fail instead of prompting, for runs nobody can
+    /// answer
+    pub batch: bool,
+    pub pty_cols: u16,
+    pub pty_rows: u16,
+}
+
+/// A program to run in a PTY, with its output streamed as rebuild events
+#[derive(Debug, Clone)]
+pub struct PtyCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Working directory; portable-pty defaults to the home directory
+    pub cwd: Option<PathBuf>,
+    /// Environment variables set on top of the inherited ones
+    pub env: Vec<(String, String)>,
+    /// Files the arguments or environment point at, written before it starts
+    pub files: Vec<(PathBuf, String)>,
     pub pty_cols: u16,
     pub pty_rows: u16,
 }
 
+/// Lifecycle events of a rebuild, in the order they are emitted
+/// Output can still follow Exited if something the process left running
+/// holds the PTY open past the drain timeout; consumers may ignore it
+#[derive(Debug)]
+pub enum RebuildEvent {
+    /// The process was spawned with this argv
+    Started { argv: Vec<String> },
+    /// Raw bytes read from the PTY
+    Output(Vec<u8>),
+    /// The process exited; everything it wrote before the PTY closed, or
+    /// within the drain timeout, has been delivered
+    Exited(BuildExit),
+    /// The process could not be started, or waiting on it failed
+    SpawnFailed { error: String },
+}
+
+/// How a rebuild process ended
+#[derive(Debug, Clone)]
+pub struct BuildExit {
+    /// Exit code, if the process exited normally
+    pub code: Option<u32>,
+    /// Name of the signal that terminated the process, if any
+    pub signal: Option<String>,
+    /// Wall-clock time from spawn to exit
+    pub duration: Duration,
+}
+
+impl BuildExit {
+    pub fn success(&self) -> bool {
+        self.code == Some(0) && self.signal.is_none()
+    }
+
+    /// Short human readable description of the outcome
+    pub fn describe(&self) -> String {
+        match (&self.signal, self.code) {
+            (Some(signal), _) => format!("terminated by {}", signal),
+            (None, Some(0)) => "succeeded".to_string(),
+            (None, Some(code)) => format!("failed with exit code {}", code),
+            (None, None) => "exited with unknown status".to_string(),
+        }
+    }
+
+    fn from_status(status: &portable_pty::ExitStatus, duration: Duration) -> Self {
+        // portable-pty only exposes the signal through its Display impl
+        let display = status.to_string();
+        match display.strip_prefix("Terminated by ") {
+            Some(signal) if !status.success() => Self {
+                code: None,
+                signal: Some(signal.to_string()),
+                duration,
+            },
+            _ => Self {
+                code: Some(status.exit_code()),
+                signal: None,
+                duration,
+            },
+        }
+    }
+}
+
 pub struct RebuildChannels {
-    pub output_rx: mpsc::Receiver<Vec<u8>>,
+    pub event_rx: mpsc::Receiver<RebuildEvent>,
     pub input_tx: mpsc::Sender<Vec<u8>>,
+    pub process: Option<ProcessHandle>,
+    pub pty: Option<PtyMaster>,
+}
+
+/// Handle to the master side of a running command's PTY
+#[derive(Clone)]
+pub struct PtyMaster {
+    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
+}
+
+impl PtyMaster {
+    /// Resize the PTY; the kernel sends SIGWINCH to the foreground process group
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<()> {
+        let master = self
+            .master
+            .lock()
+            .map_err(|_| eyre!("PTY master lock poisoned"))?;
+        master
+            .resize(PtySize {
+                rows,
+                cols,
+                pixel_width: 0,
+                pixel_height: 0,
+            })
+            .map_err(|e| eyre!("Failed to resize PTY: {}", e))
+    }
+}
+
+/// Handle to the process group of a spawned rebuild
+///
+/// The PTY child is started as a session leader, so its pid doubles as the
+/// process group id. Signalling the group reaches nixos-rebuild along with the
+/// ssh and nix clients it spawned.
+#[derive(Debug, Clone)]
+pub struct ProcessHandle {
+    pgid: Pid,
+    exited: Arc<AtomicBool>,
+}
+
+impl ProcessHandle {
+    fn new(pid: u32) -> Self {
+        Self {
+            pgid: Pid::from_raw(pid as i32),
+            exited: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the child process has been reaped
+    pub fn has_exited(&self) -> bool {
+        self.exited.load(Ordering::SeqCst)
+    }
+
+    /// Send a signal to the whole process group
+    /// Returns false if the group no longer exists
+    pub fn signal(&self, signal: Signal) -> bool {
+        killpg(self.pgid, signal).is_ok()
+    }
+
+    /// Terminate several process groups, blocking for at most `grace` before
+    /// escalating from SIGTERM to SIGKILL
+    pub fn terminate_all(processes: &[ProcessHandle], grace: Duration) {
+        for process in processes {
+            process.signal(Signal::SIGTERM);
+        }
+
+        let deadline = Instant::now() + grace;
+        while !processes.iter().all(|p| p.has_exited()) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // Sweep up anything left in the groups, even if the leaders are gone
+        for process in processes {
+            process.signal(Signal::SIGKILL);
+        }
+    }
 }
 
 impl RebuildCommand {
@@ -36,27 +209,72 @@ impl RebuildCommand {
             flake_path,
             config_name,
             connection,
+            build_host: None,
+            git_ref: None,
             extra_args,
+            use_upgrade: false,
+            structured_log: false,
+            batch: false,
             pty_cols,
             pty_rows,
         }
     }
 
+    /// Create the rebuild command for a configured host
+    /// Returns None if the host is unknown or has no connection configured
+    pub fn for_host(
+        config: &Config,
+        config_name: &str,
+        operation: RebuildOperation,
+        use_upgrade: bool,
+        pty_cols: u16,
+        pty_rows: u16,
+    ) -> Option<Self> {
+        let host_config = config.hosts.get(config_name)?;
+        if !host_config.connection.is_configured() {
+            return None;
+        }
+
+        let mut cmd = Self::new(
+            operation,
+            config.flake_path.clone(),
+            config_name.to_string(),
+            host_config.connection.clone(),
+            host_config.extra_args.clone(),
+            pty_cols,
+            pty_rows,
+        );
+        cmd.build_host = config.build_host(config_name);
+        cmd.use_upgrade = use_upgrade;
+        cmd.structured_log = config.structured_progress;
+        Some(cmd)
+    }
+
+    /// The flake to build from: the chosen git ref of the flake's repository,
+    /// or the flake path as is
+    fn flake(&self) -> Option<String> {
+        let flake_path = self.flake_path.as_ref()?;
+        Some(match self.git_ref {
+            Some(ref git_ref) => git_ref.flake_ref(),
+            None => flake_path.clone(),
+        })
+    }
+
     /// Build the command arguments for nixos-rebuild
     fn build_args(&self) -> Vec<String> {
         let mut args = vec![self.operation.as_str().to_string()];
 
         // Add flake reference if available
-        if let Some(ref flake_path) = self.flake_path {
+        if let Some(flake) = self.flake() {
             args.push("--flake".to_string());
-            args.push(format!("{}#{}", flake_path, self.config_name));
+            args.push(format!("{}#{}", flake, self.config_name));
         }
 
         // Add remote target if not local, and use appropriate sudo flag
         match &self.connection {
-            Connection::Remote(addr) => {
+            Connection::Remote(host) => {
                 args.push("--target-host".to_string());
-                args.push(addr.clone());
+                args.push(host.target.clone());
                 args.push("--use-remote-sudo".to_string());
             }
             Connection::Local => {
@@ -68,100 +286,287 @@ impl RebuildCommand {
             }
         }
 
+        match &self.build_host {
+            Some(Connection::Remote(host)) => {
+                args.push("--build-host".to_string());
+                args.push(host.target.clone());
+            }
+            Some(Connection::Local) => {
+                args.push("--build-host".to_string());
+                args.push("localhost".to_string());
+            }
+            Some(Connection::Unconfigured) | None => {}
+        }
+
+        // Add --upgrade if enabled
+        if self.use_upgrade {
+            args.push("--upgrade".to_string());
+        }
+
+        if self.structured_log {
+            args.push("--log-format".to_string());
+            args.push("internal-json".to_string());
+        }
+
         // Add extra args
         args.extend(self.extra_args.clone());
 
         args
     }
 
+    /// The nixos-rebuild invocation as a PTY command
+    /// ssh settings reach nixos-rebuild through NIX_SSHOPTS, which applies to
+    /// every ssh connection. When the target and build host need different
+    /// ones, it points ssh at a generated config under `<state>/ssh/` with a
+    /// `Host` block for each instead
+    pub fn pty_command(&self) -> Result<PtyCommand> {
+        let mut cmd = PtyCommand::new(
+            "nixos-rebuild",
+            self.build_args(),
+            self.pty_cols,
+            self.pty_rows,
+        );
+        let batch_opts: &[&str] = if self.batch {
+            &["-o", "BatchMode=yes"]
+        } else {
+            &[]
+        };
+        let remotes: Vec<&RemoteHost> = [Some(&self.connection), self.build_host.as_ref()]
+            .into_iter()
+            .filter_map(|connection| match connection {
+                Some(Connection::Remote(host)) => Some(host),
+                _ => None,
+            })
+            .collect();
+        match remotes[..] {
+            // NIX_SSHOPTS goes to ssh for both hosts, so their own settings
+            // have to come from a config with a block for each
+            [target, builder] if target.ssh_args() != builder.ssh_args() => {
+                let path = history::state_dir()?
+                    .join("ssh")
+                    .join(format!("{}.config", self.config_name));
+                let args = vec!["-F".to_string(), path.display().to_string()];
+                cmd.env
+                    .push(("NIX_SSHOPTS".to_string(), nix_sshopts(args, batch_opts)));
+                cmd.files.push((path, ssh_config(&remotes)));
+            }
+            [host, ..] if self.batch || !host.ssh_args().is_empty() => {
+                cmd.env
+                    .push(("NIX_SSHOPTS".to_string(), host.nix_sshopts(batch_opts)));
+            }
+            _ => {}
+        }
+        if self.batch {
+            // nixos-rebuild passes NIX_SUDOOPTS to every sudo it runs
+            let sudo_opts = std::env::var("NIX_SUDOOPTS").unwrap_or_default();
+            cmd.env.push((
+                "NIX_SUDOOPTS".to_string(),
+                format!("{} -n", sudo_opts).trim().to_string(),
+            ));
+        }
+        Ok(cmd)
+    }
+
+    /// ", building on <host>" for banners, if a build host is set
+    /// Between two remote hosts nixos-rebuild copies the system from the build
+    /// host itself, where none of the target's ssh settings apply, so that is
+    /// pointed out
+    pub fn build_host_note(&self) -> String {
+        match (&self.build_host, &self.connection) {
+            (Some(build_host @ Connection::Remote(_)), Connection::Remote(target))
+                if !target.ssh_args().is_empty() =>
+            {
+                format!(
+                    ", building on {} (which copies to {} without its ssh settings)",
+                    build_host.display(),
+                    target.target
+                )
+            }
+            (Some(build_host), _) => format!(", building on {}", build_host.display()),
+            (None, _) => String::new(),
+        }
+    }
+
+    /// " at <ref> (<rev>)" for banners, if a git ref is deployed
+    pub fn git_ref_note(&self) -> String {
+        match (&self.git_ref, &self.flake_path) {
+            (Some(git_ref), Some(_)) => format!(" at {}", git_ref.describe()),
+            _ => String::new(),
+        }
+    }
+
+    /// What to record in the build history for this rebuild
+    pub fn run_info(&self) -> RunInfo {
+        RunInfo {
+            host: self.config_name.clone(),
+            operation: self.operation.as_str().to_string(),
+            extra_args: self.extra_args.clone(),
+            upgrade: self.use_upgrade,
+            flake_path: self.flake(),
+            git_ref: self.git_ref.clone().filter(|_| self.flake_path.is_some()),
+        }
+    }
+
     /// Execute the rebuild command asynchronously with PTY support for interactive prompts
     /// Returns channels for both output (receiving) and input (sending)
     pub async fn execute_streaming(self) -> Result<RebuildChannels> {
-        let (output_tx, output_rx) = mpsc::channel::<Vec<u8>>(100);
-        let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(100);
+        self.pty_command()?.execute_streaming().await
+    }
+}
 
-        tokio::task::spawn_blocking(move || {
-            let pty_system = NativePtySystem::default();
+impl PtyCommand {
+    pub fn new(program: &str, args: Vec<String>, pty_cols: u16, pty_rows: u16) -> Self {
+        Self {
+            program: program.to_string(),
+            args,
+            cwd: None,
+            env: Vec::new(),
+            files: Vec::new(),
+            pty_cols,
+            pty_rows,
+        }
+    }
 
-            // Create a PTY with the requested size
-            let pty_pair = match pty_system.openpty(PtySize {
-                rows: self.pty_rows,
-                cols: self.pty_cols,
-                pixel_width: 0,
-                pixel_height: 0,
-            }) {
-                Ok(pair) => pair,
-                Err(e) => {
-                    let msg = format!("Failed to create PTY: {}\n", e);
-                    let _ = output_tx.blocking_send(msg.into_bytes());
-                    return;
-                }
-            };
+    /// Execute the command asynchronously with PTY support for interactive prompts
+    /// Returns channels for both output (receiving) and input (sending)
+    pub async fn execute_streaming(self) -> Result<RebuildChannels> {
+        let (event_tx, event_rx) = mpsc::channel::<RebuildEvent>(100);
+        let (input_tx, mut input_rx) = mpsc::channel::<Vec<u8>>(100);
 
-            // Set PTY to raw mode to disable line buffering
-            #[cfg(unix)]
-            {
-                use nix::sys::termios::{self, LocalFlags};
-                use std::os::unix::io::BorrowedFd;
-
-                if let Some(raw_fd) = pty_pair.master.as_raw_fd() {
-                    // SAFETY: We know the fd is valid as we just created the PTY
-                    let fd = unsafe { BorrowedFd::borrow_raw(raw_fd) };
-                    if let Ok(mut termios) = termios::tcgetattr(fd) {
-                        termios.local_flags.remove(LocalFlags::ICANON);
-                        termios.local_flags.remove(LocalFlags::ECHO);
-                        termios.local_flags.remove(LocalFlags::ISIG);
-                        let _ = termios::tcsetattr(fd, termios::SetArg::TCSANOW, &termios);
-                    }
-                }
+        for (path, contents) in &self.files {
+            if let Err(e) = write_file(path, contents) {
+                let _ = event_tx.try_send(RebuildEvent::SpawnFailed {
+                    error: format!("Failed to write {}: {}", path.display(), e),
+                });
+                return Ok(RebuildChannels {
+                    event_rx,
+                    input_tx,
+                    process: None,
+                    pty: None,
+                });
             }
+        }
+
+        let pty_system = NativePtySystem::default();
 
-            // Build the command
-            let args = self.build_args();
-            let mut cmd = CommandBuilder::new("nixos-rebuild");
-            for arg in args {
-                cmd.arg(arg);
+        // Create a PTY with the requested size
+        let pty_pair = match pty_system.openpty(PtySize {
+            rows: self.pty_rows,
+            cols: self.pty_cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }) {
+            Ok(pair) => pair,
+            Err(e) => {
+                let _ = event_tx.try_send(RebuildEvent::SpawnFailed {
+                    error: format!("Failed to create PTY: {}", e),
+                });
+                return Ok(RebuildChannels {
+                    event_rx,
+                    input_tx,
+                    process: None,
+                    pty: None,
+                });
             }
+        };
 
-            // Set TERM environment variable so programs know they're in a terminal
-            cmd.env(
-                "TERM",
-                std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string()),
-            );
-
-            // Spawn the command in the PTY
-            let mut child = match pty_pair.slave.spawn_command(cmd) {
-                Ok(child) => child,
-                Err(e) => {
-                    let msg = format!("Failed to spawn nixos-rebuild: {}\n", e);
-                    let _ = output_tx.blocking_send(msg.into_bytes());
-                    return;
+        // Set PTY to raw mode to disable line buffering
+        #[cfg(unix)]
+        {
+            use nix::sys::termios::{self, LocalFlags};
+            use std::os::unix::io::BorrowedFd;
+
+            if let Some(raw_fd) = pty_pair.master.as_raw_fd() {
+                // SAFETY: We know the fd is valid as we just created the PTY
+                let fd = unsafe { BorrowedFd::borrow_raw(raw_fd) };
+                if let Ok(mut termios) = termios::tcgetattr(fd) {
+                    termios.local_flags.remove(LocalFlags::ICANON);
+                    termios.local_flags.remove(LocalFlags::ECHO);
+                    termios.local_flags.remove(LocalFlags::ISIG);
+                    let _ = termios::tcsetattr(fd, termios::SetArg::TCSANOW, &termios);
                 }
-            };
+            }
+        }
 
-            // Drop the slave end - only keep the master
-            drop(pty_pair.slave);
+        // Build the command
+        let mut cmd = CommandBuilder::new(&self.program);
+        for arg in &self.args {
+            cmd.arg(arg);
+        }
+        if let Some(ref cwd) = self.cwd {
+            cmd.cwd(cwd);
+        }
+        for (key, value) in &self.env {
+            cmd.env(key, value);
+        }
+        let mut argv = vec![self.program.clone()];
+        argv.extend(self.args);
 
+        // Set TERM environment variable so programs know they're in a terminal
+        cmd.env(
+            "TERM",
+            std::env::var("TERM").unwrap_or_else(|_| "xterm-256color".to_string()),
+        );
+
+        // Spawn the command in the PTY before handing off to the blocking task,
+        // so the caller gets a handle it can cancel the build with
+        let started_at = Instant::now();
+        let mut child = match pty_pair.slave.spawn_command(cmd) {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = event_tx.try_send(RebuildEvent::SpawnFailed {
+                    error: format!("Failed to spawn {}: {}", self.program, e),
+                });
+                return Ok(RebuildChannels {
+                    event_rx,
+                    input_tx,
+                    process: None,
+                    pty: None,
+                });
+            }
+        };
+        let _ = event_tx.try_send(RebuildEvent::Started { argv });
+        let process = child.process_id().map(ProcessHandle::new);
+        let exited = process.as_ref().map(|p| p.exited.clone());
+
+        // Drop the slave end - only keep the master, shared with the caller
+        // so it can resize the PTY while the command runs
+        drop(pty_pair.slave);
+        let pty = PtyMaster {
+            master: Arc::new(Mutex::new(pty_pair.master)),
+        };
+
+        let program = self.program;
+        let task_pty = pty.clone();
+        tokio::task::spawn_blocking(move || {
             // Get the master reader and writer
-            let mut reader = pty_pair.master.try_clone_reader().unwrap();
-            let mut writer = pty_pair.master.take_writer().unwrap();
+            let (mut reader, mut writer) = {
+                let master = task_pty.master.lock().unwrap();
+                (
+                    master.try_clone_reader().unwrap(),
+                    master.take_writer().unwrap(),
+                )
+            };
 
             // Spawn a thread to read from PTY and send to output channel
-            let output_tx_clone = output_tx.clone();
-            let reader_handle = std::thread::spawn(move || {
+            let output_tx = event_tx.clone();
+            let (reader_done_tx, reader_done_rx) = std::sync::mpsc::channel::<()>();
+            std::thread::spawn(move || {
                 use std::io::Read;
                 let mut buffer = [0u8; 8192];
                 loop {
                     match reader.read(&mut buffer) {
                         Ok(0) => break,
                         Ok(n) => {
-                            if output_tx_clone.blocking_send(buffer[..n].to_vec()).is_err() {
+                            let event = RebuildEvent::Output(buffer[..n].to_vec());
+                            if output_tx.blocking_send(event).is_err() {
                                 break;
                             }
                         }
                         Err(_) => break,
                     }
                 }
+                let _ = reader_done_tx.send(());
             });
 
             // Handle input from the input channel and write to PTY
@@ -177,35 +582,137 @@ impl RebuildCommand {
             });
 
             // Wait for the child process to complete
-            let exit_status = match child.wait() {
-                Ok(status) => status,
-                Err(e) => {
-                    let msg = format!("\n✗ Process error: {}\n", e);
-                    let _ = output_tx.blocking_send(msg.into_bytes());
-                    return;
-                }
-            };
-
-            // Send completion message
-            if exit_status.success() {
-                let _ = output_tx
-                    .blocking_send(b"\n\xE2\x9C\x93 Build completed successfully!\n".to_vec());
-            } else {
-                let msg = format!(
-                    "\n✗ Build failed with exit code: {:?}\n",
-                    exit_status.exit_code()
-                );
-                let _ = output_tx.blocking_send(msg.into_bytes());
+            let exit_status = child.wait();
+            if let Some(exited) = exited {
+                exited.store(true, Ordering::SeqCst);
             }
+            let duration = started_at.elapsed();
 
-            // Wait for threads to finish
-            let _ = reader_handle.join();
+            // Let the reader drain what the process wrote before it exited, so
+            // Exited normally comes last. Anything it left running may keep the
+            // PTY open, so don't wait on EOF forever; the reader keeps
+            // forwarding what arrives after that.
+            let _ = reader_done_rx.recv_timeout(READER_DRAIN_TIMEOUT);
             drop(writer_handle); // Input thread will exit when channel closes
+
+            let event = match exit_status {
+                Ok(status) => RebuildEvent::Exited(BuildExit::from_status(&status, duration)),
+                Err(e) => RebuildEvent::SpawnFailed {
+                    error: format!("Failed to wait for {}: {}", program, e),
+                },
+            };
+            let _ = event_tx.blocking_send(event);
         });
 
         Ok(RebuildChannels {
-            output_rx,
+            event_rx,
             input_tx,
+            process,
+            pty: Some(pty),
         })
     }
 }
+
+/// Write a file, creating the directory it goes in
+fn write_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HostConfig, RemoteHost};
+
+    #[test]
+    fn test_build_host_args() {
+        let mut config = Config {
+            flake_path: Some("/etc/nixos".to_string()),
+            ..Config::default()
+        };
+        config.hosts.insert(
+            "builder".to_string(),
+            HostConfig {
+                connection: Connection::Remote(RemoteHost {
+                    port: Some(2222),
+                    ..RemoteHost::new("root@builder")
+                }),
+                ..HostConfig::unconfigured()
+            },
+        );
+        config.hosts.insert(
+            "pi".to_string(),
+            HostConfig {
+                connection: Connection::Remote(RemoteHost::new("admin@pi")),
+                build_host: Some("builder".to_string()),
+                ..HostConfig::unconfigured()
+            },
+        );
+
+        let cmd = RebuildCommand::for_host(&config, "pi", RebuildOperation::Switch, false, 80, 24)
+            .unwrap();
+        let args = cmd.build_args();
+        let idx = args.iter().position(|arg| arg == "--build-host").unwrap();
+        assert_eq!(args[idx + 1], "root@builder");
+        assert!(args.contains(&"--target-host".to_string()));
+        assert_eq!(cmd.build_host_note(), ", building on root@builder:2222");
+
+        let sshopts = |cmd: &RebuildCommand| {
+            let env = cmd.pty_command().unwrap().env;
+            env.into_iter()
+                .find(|(key, _)| key == "NIX_SSHOPTS")
+                .map(|(_, value)| value)
+        };
+
+        // Both remote with different settings: each gets its own Host block
+        let pty = cmd.pty_command().unwrap();
+        let (path, contents) = &pty.files[0];
+        assert!(path.ends_with("ssh/pi.config"));
+        assert_eq!(sshopts(&cmd), Some(format!("-F {}", path.display())));
+        assert!(contents.starts_with("Host pi\n\nHost builder\n    Port 2222\n"));
+
+        // Only the builder is remote
+        let mut builder_only =
+            RebuildCommand::for_host(&config, "pi", RebuildOperation::Switch, false, 80, 24)
+                .unwrap();
+        builder_only.connection = Connection::Local;
+        assert_eq!(sshopts(&builder_only), Some("-p 2222".to_string()));
+        assert!(builder_only.pty_command().unwrap().files.is_empty());
+
+        // Only the target is remote
+        let mut target_only =
+            RebuildCommand::for_host(&config, "builder", RebuildOperation::Switch, false, 80, 24)
+                .unwrap();
+        assert_eq!(sshopts(&target_only), Some("-p 2222".to_string()));
+        target_only.connection = Connection::Remote(RemoteHost::new("root@builder"));
+        assert_eq!(sshopts(&target_only), None);
+
+        // The copy from a remote build host doesn't get the target's settings
+        let mut remote_to_remote =
+            RebuildCommand::for_host(&config, "builder", RebuildOperation::Switch, false, 80, 24)
+                .unwrap();
+        remote_to_remote.build_host = Some(Connection::Remote(RemoteHost::new("big")));
+        assert_eq!(
+            remote_to_remote.build_host_note(),
+            ", building on big (which copies to root@builder without its ssh settings)"
+        );
+
+        let mut batch = cmd;
+        batch.batch = true;
+        let env = batch.pty_command().unwrap().env;
+        assert!(env.contains(&("NIX_SUDOOPTS".to_string(), "-n".to_string())));
+        assert!(env
+            .iter()
+            .any(|(k, v)| k == "NIX_SSHOPTS" && v.ends_with("-o BatchMode=yes")));
+
+        config.hosts.get_mut("pi").unwrap().build_host = Some("big.example.com".to_string());
+        assert_eq!(
+            config.build_host("pi"),
+            Some(Connection::Remote(RemoteHost::new("big.example.com")))
+        );
+        config.hosts.get_mut("pi").unwrap().build_host = Some("localhost".to_string());
+        assert_eq!(config.build_host("pi"), Some(Connection::Local));
+    }
+}
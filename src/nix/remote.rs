@@ -0,0 +1,116 @@
+use crate::config::Connection;
+use crate::nix::rebuild::PtyCommand;
+use color_eyre::{
+    eyre::{eyre, Context},
+    Result,
+};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Run a POSIX shell script on a host and return its stdout
+/// Remote hosts are reached over ssh in batch mode, so this never prompts
+pub fn run_script(connection: &Connection, script: &str) -> Result<String> {
+    let mut command = match connection {
+        Connection::Local => {
+            let mut command = Command::new("sh");
+            command.arg("-s");
+            command
+        }
+        Connection::Remote(host) => {
+            let mut command = Command::new("ssh");
+            command
+                .args(host.ssh_args())
+                .args(["-o", "BatchMode=yes", &host.target, "sh", "-s"]);
+            command
+        }
+        Connection::Unconfigured => return Err(eyre!("Host is not configured")),
+    };
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .wrap_err("Failed to start shell")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(script.as_bytes())
+            .wrap_err("Failed to send script")?;
+    }
+
+    let output = child.wait_with_output().wrap_err("Failed to run script")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(eyre!("{}", stderr.trim()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A PTY command running a shell command as root on a host
+/// sudo gets a terminal, so password prompts can be answered in input mode
+pub fn root_shell_command(
+    connection: &Connection,
+    command: &str,
+    pty_cols: u16,
+    pty_rows: u16,
+) -> Option<PtyCommand> {
+    match connection {
+        Connection::Local => Some(PtyCommand::new(
+            "sudo",
+            vec!["sh".to_string(), "-c".to_string(), command.to_string()],
+            pty_cols,
+            pty_rows,
+        )),
+        Connection::Remote(host) => {
+            // ssh joins its arguments into a single remote command line
+            let remote = format!("sudo sh -c {}", shell_quote(command));
+            let mut args = vec!["-t".to_string()];
+            args.extend(host.ssh_args());
+            args.extend([host.target.clone(), remote]);
+            Some(PtyCommand::new("ssh", args, pty_cols, pty_rows))
+        }
+        Connection::Unconfigured => None,
+    }
+}
+
+/// Quote a string for a POSIX shell
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RemoteHost;
+
+    #[test]
+    fn test_root_shell_command_quotes_for_ssh() {
+        let cmd = root_shell_command(
+            &Connection::Remote(RemoteHost {
+                port: Some(2222),
+                ..RemoteHost::new("admin@web")
+            }),
+            "echo 'hi'",
+            80,
+            24,
+        )
+        .unwrap();
+        assert_eq!(cmd.program, "ssh");
+        assert_eq!(
+            cmd.args,
+            vec![
+                "-t",
+                "-p",
+                "2222",
+                "admin@web",
+                r"sudo sh -c 'echo '\''hi'\'''"
+            ]
+        );
+
+        let cmd = root_shell_command(&Connection::Local, "echo 'hi'", 80, 24).unwrap();
+        assert_eq!(cmd.program, "sudo");
+        assert_eq!(cmd.args, vec!["sh", "-c", "echo 'hi'"]);
+    }
+}
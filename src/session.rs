@@ -0,0 +1,386 @@
+use crate::history::{BuildRecord, BuildRecorder, RunInfo};
+use crate::nix::diagnostics::DiagnosticsCache;
+use crate::nix::progress::ProgressParser;
+use crate::nix::rebuild::{BuildExit, ProcessHandle, PtyCommand, PtyMaster, RebuildEvent};
+use crate::terminal::VirtualTerminal;
+use crate::util::format_duration;
+use color_eyre::Result;
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How long to wait at each cancellation stage before escalating
+const CANCEL_ESCALATION: Duration = Duration::from_secs(3);
+
+/// Stages of cancelling a running build, in escalation order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CancelStage {
+    Interrupt,
+    Terminate,
+    Kill,
+}
+
+impl CancelStage {
+    pub fn signal(&self) -> Signal {
+        match self {
+            Self::Interrupt => Signal::SIGINT,
+            Self::Terminate => Signal::SIGTERM,
+            Self::Kill => Signal::SIGKILL,
+        }
+    }
+
+    pub fn next(&self) -> Option<Self> {
+        match self {
+            Self::Interrupt => Some(Self::Terminate),
+            Self::Terminate => Some(Self::Kill),
+            Self::Kill => None,
+        }
+    }
+}
+
+/// State of an in-progress cancellation
+#[derive(Debug, Clone, Copy)]
+pub struct CancelState {
+    pub stage: CancelStage,
+    pub signalled_at: Instant,
+}
+
+/// Where a host's build currently stands
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildStatus {
+    Idle,
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl BuildStatus {
+    /// Single-character badge for the host list
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Self::Idle => " ",
+            Self::Queued => "…",
+            Self::Running => "⟳",
+            Self::Succeeded => "✓",
+            Self::Failed => "✗",
+            Self::Cancelled => "⊘",
+        }
+    }
+}
+
+/// A host's build output and the process (if any) producing it
+pub struct BuildSession {
+    pub terminal: VirtualTerminal,
+    pub status: BuildStatus,
+    pub event_rx: Option<mpsc::Receiver<RebuildEvent>>,
+    pub input_tx: Option<mpsc::Sender<Vec<u8>>>,
+    pub process: Option<ProcessHandle>,
+    pty: Option<PtyMaster>,
+    pub cancel_state: Option<CancelState>,
+    pub argv: Vec<String>,
+    pub last_exit: Option<BuildExit>,
+    pub log_path: Option<PathBuf>,
+    /// What the last started command ran
+    pub run: Option<RunInfo>,
+    pub diagnostics: DiagnosticsCache,
+    /// Structured progress nix reports alongside the output
+    pub progress: ProgressParser,
+    pending: Option<(PtyCommand, RunInfo)>,
+    recorder: Option<BuildRecorder>,
+    /// Appending history records of finished builds, which can wait on the
+    /// flake revision, so it runs off the UI thread
+    saving: Vec<JoinHandle<Result<BuildRecord>>>,
+}
+
+impl BuildSession {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            terminal: VirtualTerminal::new(width, height),
+            status: BuildStatus::Idle,
+            event_rx: None,
+            input_tx: None,
+            process: None,
+            pty: None,
+            cancel_state: None,
+            argv: Vec::new(),
+            last_exit: None,
+            log_path: None,
+            run: None,
+            diagnostics: DiagnosticsCache::default(),
+            progress: ProgressParser::default(),
+            pending: None,
+            recorder: None,
+            saving: Vec::new(),
+        }
+    }
+
+    /// Whether the session is waiting for a slot or running
+    pub fn is_active(&self) -> bool {
+        matches!(self.status, BuildStatus::Queued | BuildStatus::Running)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.status == BuildStatus::Running
+    }
+
+    /// Queue a command, replacing the previous output
+    pub fn queue(&mut self, cmd: PtyCommand, info: RunInfo, banner: &str) {
+        self.terminal.clear();
+        self.terminal.feed_bytes(banner.as_bytes());
+        self.status = BuildStatus::Queued;
+        self.last_exit = None;
+        self.log_path = None;
+        self.argv.clear();
+        self.progress = ProgressParser::default();
+        self.pending = Some((cmd, info));
+    }
+
+    /// Start the queued command with a PTY of the given size
+    /// If it can't be started the session is marked failed and the error
+    /// returned
+    pub async fn start(&mut self, cols: u16, rows: u16) -> Result<()> {
+        let Some((mut cmd, info)) = self.pending.take() else {
+            return Ok(());
+        };
+        cmd.pty_cols = cols;
+        cmd.pty_rows = rows;
+
+        self.status = BuildStatus::Running;
+        let recorder = BuildRecorder::start(&info);
+        self.run = Some(info);
+        self.log_path = recorder.log_path().map(Path::to_path_buf);
+        self.recorder = Some(recorder);
+        let channels = match cmd.execute_streaming().await {
+            Ok(channels) => channels,
+            Err(e) => {
+                let msg = format!("\n✗ Failed to start: {}\n", e);
+                self.terminal.feed_bytes(msg.as_bytes());
+                self.finish(BuildStatus::Failed);
+                return Err(e);
+            }
+        };
+        self.event_rx = Some(channels.event_rx);
+        self.input_tx = Some(channels.input_tx);
+        self.process = channels.process;
+        self.pty = channels.pty;
+        Ok(())
+    }
+
+    /// Resize the output terminal and, while a build runs, its PTY
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        self.terminal.resize(cols as usize, rows as usize);
+        if let Some(ref pty) = self.pty {
+            // A failed resize only costs the build its layout
+            let _ = pty.resize(cols, rows);
+        }
+    }
+
+    /// Show build output and record it in the log
+    fn show_output(&mut self, bytes: &[u8]) {
+        self.terminal.feed_bytes(bytes);
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.write_output(bytes);
+        }
+    }
+
+    /// Send input to the PTY
+    pub fn send_input(&self, data: Vec<u8>) {
+        if let Some(ref tx) = self.input_tx {
+            let _ = tx.try_send(data);
+        }
+    }
+
+    /// Process pending events; returns true if the build finished
+    pub fn poll(&mut self) -> bool {
+        self.tick_cancel();
+
+        // Try to receive all available events without blocking
+        let mut events = Vec::new();
+        if let Some(ref mut rx) = self.event_rx {
+            while let Ok(event) = rx.try_recv() {
+                events.push(event);
+            }
+        }
+
+        let mut finished = false;
+        for event in events {
+            finished |= self.handle_event(event);
+        }
+        finished
+    }
+
+    /// Apply a single rebuild lifecycle event; returns true if the build finished
+    fn handle_event(&mut self, event: RebuildEvent) -> bool {
+        match event {
+            RebuildEvent::Started { argv } => {
+                let msg = format!("$ {}\n", argv.join(" "));
+                self.terminal.feed_bytes(msg.as_bytes());
+                self.argv = argv;
+                false
+            }
+            RebuildEvent::Output(bytes) => {
+                let output = self.progress.feed(&bytes);
+                self.show_output(&output);
+                false
+            }
+            RebuildEvent::Exited(exit) => {
+                let rest = self.progress.finish();
+                self.show_output(&rest);
+                let (msg, status) = if self.cancel_state.is_some() {
+                    (
+                        format!(
+                            "\n✓ Build cancelled by user ({}, after {})\n",
+                            exit.describe(),
+                            format_duration(exit.duration)
+                        ),
+                        BuildStatus::Cancelled,
+                    )
+                } else if exit.success() {
+                    (
+                        format!(
+                            "\n✓ Build completed successfully! ({})\n",
+                            format_duration(exit.duration)
+                        ),
+                        BuildStatus::Succeeded,
+                    )
+                } else {
+                    (
+                        format!(
+                            "\n✗ Build {} ({})\n",
+                            exit.describe(),
+                            format_duration(exit.duration)
+                        ),
+                        BuildStatus::Failed,
+                    )
+                };
+                self.terminal.feed_bytes(msg.as_bytes());
+                self.last_exit = Some(exit);
+                self.finish(status);
+                true
+            }
+            RebuildEvent::SpawnFailed { error } => {
+                let msg = format!("\n✗ {}\n", error);
+                self.terminal.feed_bytes(msg.as_bytes());
+                self.finish(BuildStatus::Failed);
+                true
+            }
+        }
+    }
+
+    /// Cancel the build
+    /// Queued builds are dropped. For running builds the first call sends SIGINT to
+    /// the process group; calling it again escalates to the next signal immediately
+    pub fn cancel(&mut self) {
+        match self.status {
+            BuildStatus::Queued => {
+                self.pending = None;
+                self.terminal
+                    .feed_bytes("✓ Queued build cancelled by user\n".as_bytes());
+                self.status = BuildStatus::Cancelled;
+            }
+            BuildStatus::Running => {
+                let stage = match self.cancel_state {
+                    None => CancelStage::Interrupt,
+                    Some(state) => match state.stage.next() {
+                        Some(stage) => stage,
+                        None => return,
+                    },
+                };
+                self.signal(stage);
+            }
+            _ => {}
+        }
+    }
+
+    /// Send the signal for a cancel stage to the running build
+    fn signal(&mut self, stage: CancelStage) {
+        let Some(ref process) = self.process else {
+            // Nothing to signal, so there is nothing to wait for
+            self.terminal
+                .feed_bytes("\n✓ Build cancelled by user\n".as_bytes());
+            self.finish(BuildStatus::Cancelled);
+            return;
+        };
+        // If the group is already gone the Exited event is on its way
+        process.signal(stage.signal());
+
+        self.cancel_state = Some(CancelState {
+            stage,
+            signalled_at: Instant::now(),
+        });
+        let msg = format!("\n⚠ Cancelling build ({})...\n", stage.signal().as_str());
+        self.terminal.feed_bytes(msg.as_bytes());
+    }
+
+    /// Escalate a pending cancellation if the build ignored the last signal
+    fn tick_cancel(&mut self) {
+        if let Some(state) = self.cancel_state {
+            if state.signalled_at.elapsed() >= CANCEL_ESCALATION {
+                if let Some(stage) = state.stage.next() {
+                    self.signal(stage);
+                }
+            }
+        }
+    }
+
+    /// Reset build state once the build process has finished
+    fn finish(&mut self, status: BuildStatus) {
+        // Make sure nothing the build spawned outlives it after a cancel
+        if self.cancel_state.is_some() {
+            if let Some(ref process) = self.process {
+                process.signal(Signal::SIGKILL);
+            }
+        }
+
+        if let Some(recorder) = self.recorder.take() {
+            let exit = self.last_exit.clone();
+            self.saving.push(std::thread::spawn(move || {
+                recorder.finish(status, exit.as_ref())
+            }));
+        }
+
+        self.status = status;
+        self.event_rx = None;
+        self.input_tx = None;
+        self.process = None;
+        self.pty = None;
+        self.cancel_state = None;
+    }
+
+    /// Collect the history records that have been written, returning why
+    /// any of them couldn't be
+    pub fn take_save_errors(&mut self) -> Vec<String> {
+        let (done, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.saving)
+            .into_iter()
+            .partition(|saving| saving.is_finished());
+        self.saving = running;
+        done.into_iter()
+            .filter_map(|saving| match saving.join() {
+                Ok(Err(e)) => Some(e.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Whether history records of finished builds are still being written
+    pub fn is_saving(&self) -> bool {
+        !self.saving.is_empty()
+    }
+
+    /// Abandon the build for shutdown, returning its process so the caller
+    /// can terminate it together with the others
+    pub fn abandon(&mut self) -> Option<ProcessHandle> {
+        let process = self.process.take();
+        self.pending = None;
+        if self.is_active() {
+            self.finish(BuildStatus::Cancelled);
+        }
+        process
+    }
+}
@@ -1,87 +1,227 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use unicode_width::UnicodeWidthChar;
 use vte::{Params, Parser, Perform};
 
+mod search;
+
+pub use search::SearchMatch;
+
 const MAX_SCROLLBACK: usize = 10_000;
 
-#[derive(Clone, Debug)]
+/// Lines removed at once when the scrollback is full
+const SCROLLBACK_TRIM: usize = 1000;
+
+/// A color set through SGR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellColor {
+    /// Palette index: 0-7 normal, 8-15 bright, 16-255 the xterm 256-color cube
+    /// and grayscale ramp
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// SGR attributes of a cell
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Attrs {
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub reverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Cell {
     pub ch: char,
-    pub fg: Option<u8>,
-    pub bg: Option<u8>,
-    pub bold: bool,
+    /// Combining marks drawn over `ch`
+    pub combining: Option<Box<str>>,
+    /// Columns `ch` covers: 1, or 2 for wide characters. The cell a wide
+    /// character spills into has width 0 and shows nothing of its own
+    pub width: u8,
+    pub fg: Option<CellColor>,
+    pub bg: Option<CellColor>,
+    pub attrs: Attrs,
+}
+
+impl Cell {
+    /// Whether this is the second half of a wide character
+    pub fn is_continuation(&self) -> bool {
+        self.width == 0
+    }
+
+    /// Append the text the cell displays
+    pub fn push_text(&self, out: &mut String) {
+        if self.is_continuation() {
+            return;
+        }
+        out.push(self.ch);
+        if let Some(ref marks) = self.combining {
+            out.push_str(marks);
+        }
+    }
+
+    /// Whether the cell shows nothing: a space without a background
+    pub fn is_blank(&self) -> bool {
+        self.ch == ' ' && self.combining.is_none() && self.bg.is_none() && !self.attrs.reverse
+    }
+
+    /// Whether the cell has the same colors and attributes as another
+    pub fn same_style(&self, other: &Cell) -> bool {
+        (self.fg, self.bg, self.attrs) == (other.fg, other.bg, other.attrs)
+    }
 }
 
 impl Default for Cell {
     fn default() -> Self {
         Self {
             ch: ' ',
+            combining: None,
+            width: 1,
             fg: None,
             bg: None,
-            bold: false,
+            attrs: Attrs::default(),
+        }
+    }
+}
+
+/// A line of the screen or scrollback
+#[derive(Clone, Debug, PartialEq)]
+pub struct Row {
+    pub cells: Vec<Cell>,
+    /// The text continues on the next row because it reached the right
+    /// margin, rather than being ended by a newline
+    pub wrapped: bool,
+}
+
+impl Row {
+    fn new(cells: Vec<Cell>) -> Self {
+        Self {
+            cells,
+            wrapped: false,
         }
     }
 }
 
+/// Cursor position and rendition saved by DECSC (`ESC 7`) or `CSI s`
+#[derive(Clone, Debug, Default)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    pen: Cell,
+}
+
 pub struct VirtualTerminal {
     width: usize,
     height: usize,
-    screen: Vec<Vec<Cell>>,
-    scrollback: Vec<Vec<Cell>>,
+    screen: Vec<Row>,
+    scrollback: Vec<Row>,
     cursor_x: usize,
     cursor_y: usize,
     parser: Parser,
-    current_fg: Option<u8>,
-    current_bg: Option<u8>,
-    current_bold: bool,
+    /// Colors and attributes applied to newly printed characters
+    pen: Cell,
+    dropped_lines: usize,
+    /// Replaced on every change, so derived data knows when to refresh
+    revision: u64,
+    /// First and last row of the scroll region (DECSTBM), inclusive
+    scroll_top: usize,
+    scroll_bottom: usize,
+    saved_cursor: Option<SavedCursor>,
+    cursor_visible: bool,
+    autowrap: bool,
+    /// The primary screen and its cursor while the alternate screen is shown
+    primary: Option<(Vec<Row>, SavedCursor)>,
 }
 
 impl VirtualTerminal {
     pub fn new(width: usize, height: usize) -> Self {
-        let mut screen = Vec::with_capacity(height);
-        for _ in 0..height {
-            screen.push(vec![Cell::default(); width]);
-        }
-
         Self {
             width,
             height,
-            screen,
+            screen: blank_screen(width, height),
             scrollback: Vec::new(),
             cursor_x: 0,
             cursor_y: 0,
             parser: Parser::new(),
-            current_fg: None,
-            current_bg: None,
-            current_bold: false,
+            pen: Cell::default(),
+            dropped_lines: 0,
+            revision: next_revision(),
+            scroll_top: 0,
+            scroll_bottom: height.saturating_sub(1),
+            saved_cursor: None,
+            cursor_visible: true,
+            autowrap: true,
+            primary: None,
         }
     }
 
+    /// Resize the terminal, rewrapping the primary screen and scrollback
+    /// The alternate screen is cropped instead; its program redraws it
     pub fn resize(&mut self, width: usize, height: usize) {
+        if width == self.width && height == self.height {
+            return;
+        }
+        self.revision = next_revision();
+
+        if let Some((screen, cursor)) = self.primary.take() {
+            self.screen = resized_screen(&self.screen, width, height);
+            let (screen, (x, y)) = self.reflow_primary(screen, (cursor.x, cursor.y), width, height);
+            self.primary = Some((screen, SavedCursor { x, y, ..cursor }));
+        } else {
+            let screen = std::mem::take(&mut self.screen);
+            let (screen, (x, y)) =
+                self.reflow_primary(screen, (self.cursor_x, self.cursor_y), width, height);
+            self.screen = screen;
+            self.cursor_x = x;
+            self.cursor_y = y;
+        }
+
         self.width = width;
         self.height = height;
+        self.scroll_top = 0;
+        self.scroll_bottom = height.saturating_sub(1);
+        self.cursor_x = self.cursor_x.min(width);
+        self.cursor_y = self.cursor_y.min(height.saturating_sub(1));
+    }
 
-        // Recreate screen with new dimensions
-        let mut new_screen = Vec::with_capacity(height);
-        for _ in 0..height {
-            new_screen.push(vec![Cell::default(); width]);
+    /// Rewrap the scrollback and a primary screen to a new size
+    /// Returns the new screen and where the cursor ends up on it
+    fn reflow_primary(
+        &mut self,
+        screen: Vec<Row>,
+        cursor: (usize, usize),
+        width: usize,
+        height: usize,
+    ) -> (Vec<Row>, (usize, usize)) {
+        if width == 0 || height == 0 {
+            return (resized_screen(&screen, width, height), (0, 0));
         }
 
-        // Copy old content
-        for (y, line) in self.screen.iter().enumerate() {
-            if y >= height {
-                break;
-            }
-            for (x, cell) in line.iter().enumerate() {
-                if x >= width {
-                    break;
-                }
-                new_screen[y][x] = cell.clone();
-            }
-        }
+        let cursor_row = self.scrollback.len() + cursor.1;
+        let mut rows = std::mem::take(&mut self.scrollback);
+        rows.extend(screen);
+        let (mut rows, (x, y)) = reflow(rows, (cursor.0, cursor_row), width);
+
+        // Keep the bottom of the output on screen, as long as the cursor is
+        let start = rows.len().saturating_sub(height).min(y);
+        let mut screen = rows.split_off(start);
+        screen.truncate(height);
+        screen.resize(height, Row::new(vec![Cell::default(); width]));
 
-        self.screen = new_screen;
+        if rows.len() > MAX_SCROLLBACK {
+            let excess = rows.len() - MAX_SCROLLBACK;
+            rows.drain(0..excess);
+            self.dropped_lines += excess;
+        }
+        self.scrollback = rows;
+        (screen, (x, y - start))
     }
 
     pub fn feed_bytes(&mut self, data: &[u8]) {
+        self.revision = next_revision();
         // Temporarily swap out parser to avoid borrow checker issues
         let mut parser = std::mem::replace(&mut self.parser, Parser::new());
         for byte in data {
@@ -90,26 +230,114 @@ impl VirtualTerminal {
         self.parser = parser;
     }
 
-    pub fn get_screen(&self) -> &[Vec<Cell>] {
+    /// The screen being shown, which is the alternate one while it is active
+    pub fn get_screen(&self) -> &[Row] {
         &self.screen
     }
 
-    pub fn get_scrollback(&self) -> &[Vec<Cell>] {
+    pub fn get_scrollback(&self) -> &[Row] {
         &self.scrollback
     }
 
+    /// Number of lines trimmed from the top of the scrollback so far
+    pub fn dropped_lines(&self) -> usize {
+        self.dropped_lines
+    }
+
+    /// Changes whenever the contents of the terminal may have, and is never
+    /// the same for two terminals
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Scrollback and screen as text with soft-wrapped rows joined, each
+    /// with the index of the row it starts on
+    pub fn logical_lines(&self) -> Vec<(usize, String)> {
+        let mut lines: Vec<(usize, String)> = Vec::new();
+        let mut continued = false;
+        for (idx, row) in self.scrollback.iter().chain(self.screen.iter()).enumerate() {
+            let text = row_text(&row.cells);
+            match lines.last_mut() {
+                Some((_, line)) if continued => line.push_str(&text),
+                _ => lines.push((idx, text)),
+            }
+            continued = row.wrapped;
+        }
+        for (_, line) in &mut lines {
+            line.truncate(line.trim_end().len());
+        }
+        lines
+    }
+
+    /// Lines of output, not counting blank rows at the bottom of the screen
+    pub fn line_count(&self) -> usize {
+        let screen_lines = self
+            .screen
+            .iter()
+            .rposition(|row| !row_text(&row.cells).trim().is_empty())
+            .map_or(0, |idx| idx + 1);
+        self.scrollback.len() + screen_lines
+    }
+
+    /// Cursor column and screen row, unless the program has hidden it
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        if !self.cursor_visible || self.width == 0 {
+            return None;
+        }
+        Some((self.cursor_x.min(self.width - 1), self.cursor_y))
+    }
+
+    /// Reset to a blank terminal, dropping the scrollback and any modes the
+    /// previous program left behind
     pub fn clear(&mut self) {
-        self.clear_screen();
+        self.revision = next_revision();
+        self.reset();
         self.scrollback.clear();
+        self.dropped_lines = 0;
+    }
+
+    /// Scrollback and screen as plain text, without trailing blank lines
+    pub fn to_plain_text(&self) -> String {
+        let mut lines: Vec<String> = self
+            .scrollback
+            .iter()
+            .chain(self.screen.iter())
+            .map(|row| row_text(&row.cells).trim_end().to_string())
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// Scrollback and screen with their colors encoded as ANSI escapes
+    pub fn to_ansi(&self) -> String {
+        let mut lines: Vec<String> = self
+            .scrollback
+            .iter()
+            .chain(self.screen.iter())
+            .map(|row| row_to_ansi(&row.cells))
+            .collect();
+        while lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
     }
 
     fn write_char(&mut self, ch: char) {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+
         if ch == '\n' {
             self.cursor_x = 0;
-            self.cursor_y += 1;
-            if self.cursor_y >= self.height {
-                self.scroll_up();
-            }
+            self.linefeed();
             return;
         }
 
@@ -119,62 +347,602 @@ impl VirtualTerminal {
         }
 
         if ch == '\t' {
-            // Tab to next 8-column boundary
-            self.cursor_x = ((self.cursor_x / 8) + 1) * 8;
-            if self.cursor_x >= self.width {
-                self.cursor_x = 0;
-                self.cursor_y += 1;
-                if self.cursor_y >= self.height {
-                    self.scroll_up();
+            // Tab to next 8-column boundary, stopping at the right margin
+            self.cursor_x = (((self.cursor_x / 8) + 1) * 8).min(self.width - 1);
+            return;
+        }
+
+        // Control characters that reach here have no width; drop them
+        let Some(char_width) = ch.width() else {
+            return;
+        };
+        if char_width == 0 {
+            self.attach_combining(ch);
+            return;
+        }
+        let char_width = char_width.min(self.width);
+
+        // The cursor sits past the last column after filling it; the wrap
+        // only happens once there is another character to print. A wide
+        // character that doesn't fit in the last column wraps early.
+        if self.cursor_x + char_width > self.width {
+            if self.autowrap {
+                if self.cursor_x < self.width {
+                    self.erase_cells(self.cursor_y, self.cursor_x, self.width);
                 }
+                self.screen[self.cursor_y].wrapped = true;
+                self.cursor_x = 0;
+                self.linefeed();
+            } else {
+                self.cursor_x = self.width - char_width;
             }
-            return;
         }
 
-        if self.cursor_x >= self.width {
-            self.cursor_x = 0;
+        let (x, y) = (self.cursor_x, self.cursor_y);
+        self.screen[y].cells[x] = Cell {
+            ch,
+            width: char_width as u8,
+            ..self.pen.clone()
+        };
+        if char_width == 2 {
+            self.screen[y].cells[x + 1] = Cell {
+                width: 0,
+                ..self.pen.clone()
+            };
+        }
+        // Overwriting half of a wide character leaves the other half blank
+        repair_wide_chars(&mut self.screen[y].cells);
+        self.cursor_x += char_width;
+    }
+
+    /// Attach a zero-width character to the character before the cursor
+    fn attach_combining(&mut self, mark: char) {
+        let row = &mut self.screen[self.cursor_y].cells;
+        let Some(mut x) = self.cursor_x.min(self.width).checked_sub(1) else {
+            return; // Nothing to combine with at the start of a line
+        };
+        if row[x].is_continuation() && x > 0 {
+            x -= 1;
+        }
+
+        let cell = &mut row[x];
+        let mut marks = cell.combining.take().map(String::from).unwrap_or_default();
+        marks.push(mark);
+        cell.combining = Some(marks.into_boxed_str());
+    }
+
+    /// Move down a line, scrolling the region when at its bottom
+    fn linefeed(&mut self) {
+        if self.height == 0 {
+            return;
+        }
+        if self.cursor_y == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor_y + 1 < self.height {
             self.cursor_y += 1;
-            if self.cursor_y >= self.height {
-                self.scroll_up();
+        }
+    }
+
+    /// Move up a line, scrolling the region down when at its top
+    fn reverse_index(&mut self) {
+        if self.cursor_y == self.scroll_top {
+            self.scroll_down(1);
+        } else {
+            self.cursor_y = self.cursor_y.saturating_sub(1);
+        }
+    }
+
+    /// Scroll the scroll region up by `n` lines
+    /// Lines leaving the top of the primary screen go to the scrollback
+    fn scroll_up(&mut self, n: usize) {
+        for _ in 0..n.min(self.scroll_bottom + 1 - self.scroll_top) {
+            let line = self.screen.remove(self.scroll_top);
+            if self.scroll_top == 0 && self.primary.is_none() {
+                self.scrollback.push(line);
+
+                // Trim scrollback if too large, in batches to avoid shifting
+                // the whole buffer for every new line
+                if self.scrollback.len() > MAX_SCROLLBACK {
+                    self.scrollback.drain(0..SCROLLBACK_TRIM);
+                    self.dropped_lines += SCROLLBACK_TRIM;
+                }
             }
+            self.screen.insert(self.scroll_bottom, self.blank_row());
         }
+    }
 
-        if self.cursor_y < self.height {
-            self.screen[self.cursor_y][self.cursor_x] = Cell {
-                ch,
-                fg: self.current_fg,
-                bg: self.current_bg,
-                bold: self.current_bold,
-            };
-            self.cursor_x += 1;
+    /// Scroll the scroll region down by `n` lines
+    fn scroll_down(&mut self, n: usize) {
+        for _ in 0..n.min(self.scroll_bottom + 1 - self.scroll_top) {
+            self.screen.remove(self.scroll_bottom);
+            self.screen.insert(self.scroll_top, self.blank_row());
+        }
+    }
+
+    /// Insert `n` blank lines at the cursor, pushing the rest of the region down
+    fn insert_lines(&mut self, n: usize) {
+        if !self.cursor_in_region() {
+            return;
+        }
+        for _ in 0..n.min(self.scroll_bottom + 1 - self.cursor_y) {
+            self.screen.remove(self.scroll_bottom);
+            self.screen.insert(self.cursor_y, self.blank_row());
+        }
+        self.cursor_x = 0;
+    }
+
+    /// Delete `n` lines at the cursor, pulling the rest of the region up
+    fn delete_lines(&mut self, n: usize) {
+        if !self.cursor_in_region() {
+            return;
+        }
+        for _ in 0..n.min(self.scroll_bottom + 1 - self.cursor_y) {
+            self.screen.remove(self.cursor_y);
+            self.screen.insert(self.scroll_bottom, self.blank_row());
+        }
+        self.cursor_x = 0;
+    }
+
+    fn cursor_in_region(&self) -> bool {
+        (self.scroll_top..=self.scroll_bottom).contains(&self.cursor_y)
+    }
+
+    /// Insert `n` blank cells at the cursor, shifting the rest of the line right
+    fn insert_chars(&mut self, n: usize) {
+        let x = self.cursor_x.min(self.width.saturating_sub(1));
+        let blank = self.blank();
+        let row = &mut self.screen[self.cursor_y].cells;
+        for _ in 0..n.min(self.width - x) {
+            row.insert(x, blank.clone());
         }
+        row.truncate(self.width);
+        repair_wide_chars(row);
     }
 
-    fn scroll_up(&mut self) {
-        // Move top line to scrollback
-        if !self.screen.is_empty() {
-            let top_line = self.screen.remove(0);
-            self.scrollback.push(top_line);
+    /// Delete `n` cells at the cursor, shifting the rest of the line left
+    fn delete_chars(&mut self, n: usize) {
+        let x = self.cursor_x.min(self.width.saturating_sub(1));
+        let blank = self.blank();
+        let row = &mut self.screen[self.cursor_y].cells;
+        for _ in 0..n.min(self.width - x) {
+            row.remove(x);
+            row.push(blank.clone());
+        }
+        repair_wide_chars(row);
+    }
 
-            // Trim scrollback if too large
-            if self.scrollback.len() > MAX_SCROLLBACK {
-                self.scrollback.drain(0..1000);
+    /// Blank the cells of a row in `start..end`
+    fn erase_cells(&mut self, y: usize, start: usize, end: usize) {
+        let blank = self.blank();
+        let end = end.min(self.width);
+        for cell in &mut self.screen[y].cells[start.min(end)..end] {
+            *cell = blank.clone();
+        }
+        // Nothing is left to continue onto the next row
+        if end == self.width {
+            self.screen[y].wrapped = false;
+        }
+        repair_wide_chars(&mut self.screen[y].cells);
+    }
+
+    /// Erase in display (ED)
+    fn erase_display(&mut self, mode: u16) {
+        let (x, y) = (self.cursor_x, self.cursor_y);
+        match mode {
+            0 => {
+                self.erase_cells(y, x, self.width);
+                for row in y + 1..self.height {
+                    self.erase_cells(row, 0, self.width);
+                }
             }
+            1 => {
+                for row in 0..y {
+                    self.erase_cells(row, 0, self.width);
+                }
+                self.erase_cells(y, 0, x + 1);
+            }
+            2 => {
+                for row in 0..self.height {
+                    self.erase_cells(row, 0, self.width);
+                }
+            }
+            3 => {
+                // The log still has it; count it like a trim so the UI says so
+                self.dropped_lines += self.scrollback.len();
+                self.scrollback.clear();
+            }
+            _ => {}
+        }
+    }
+
+    /// Erase in line (EL)
+    fn erase_line(&mut self, mode: u16) {
+        let (x, y) = (self.cursor_x, self.cursor_y);
+        match mode {
+            0 => self.erase_cells(y, x, self.width),
+            1 => self.erase_cells(y, 0, x + 1),
+            2 => self.erase_cells(y, 0, self.width),
+            _ => {}
         }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(self.cursor_state());
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved_cursor.clone().unwrap_or_default();
+        self.set_cursor_state(saved);
+    }
 
-        // Add blank line at bottom
-        self.screen.push(vec![Cell::default(); self.width]);
-        self.cursor_y = self.height.saturating_sub(1);
+    fn cursor_state(&self) -> SavedCursor {
+        SavedCursor {
+            x: self.cursor_x,
+            y: self.cursor_y,
+            pen: self.pen.clone(),
+        }
+    }
+
+    fn set_cursor_state(&mut self, state: SavedCursor) {
+        self.cursor_x = state.x.min(self.width);
+        self.cursor_y = state.y.min(self.height.saturating_sub(1));
+        self.pen = state.pen;
+    }
+
+    /// Switch to the alternate screen, which has no scrollback
+    fn enter_alt_screen(&mut self) {
+        if self.primary.is_some() {
+            return;
+        }
+        let primary = std::mem::replace(&mut self.screen, blank_screen(self.width, self.height));
+        self.primary = Some((primary, self.cursor_state()));
+    }
+
+    /// Switch back to the primary screen as it was left
+    fn leave_alt_screen(&mut self, restore_cursor: bool) {
+        if let Some((screen, cursor)) = self.primary.take() {
+            self.screen = screen;
+            if restore_cursor {
+                self.set_cursor_state(cursor);
+            }
+        }
     }
 
-    fn clear_screen(&mut self) {
-        for line in &mut self.screen {
-            for cell in line {
-                *cell = Cell::default();
+    /// Set or reset DEC private modes (`CSI ? Pm h` / `CSI ? Pm l`)
+    fn set_private_mode(&mut self, params: &Params, enable: bool) {
+        for param in params.iter() {
+            match param.first().copied().unwrap_or(0) {
+                7 => self.autowrap = enable,
+                25 => self.cursor_visible = enable,
+                47 | 1047 => {
+                    if enable {
+                        self.enter_alt_screen();
+                    } else {
+                        self.leave_alt_screen(false);
+                    }
+                }
+                1049 => {
+                    if enable {
+                        self.save_cursor();
+                        self.enter_alt_screen();
+                    } else {
+                        self.leave_alt_screen(true);
+                    }
+                }
+                _ => {}
             }
         }
+    }
+
+    /// Full reset (RIS), keeping the scrollback
+    fn reset(&mut self) {
+        self.leave_alt_screen(false);
+        self.screen = blank_screen(self.width, self.height);
         self.cursor_x = 0;
         self.cursor_y = 0;
+        self.pen = Cell::default();
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height.saturating_sub(1);
+        self.saved_cursor = None;
+        self.cursor_visible = true;
+        self.autowrap = true;
+    }
+
+    /// Apply an SGR sequence to the pen
+    /// Extended colors are accepted both as `38;5;n` and as `38:5:n`
+    fn set_graphics_rendition(&mut self, params: &Params) {
+        let params: Vec<&[u16]> = params.iter().collect();
+        if params.is_empty() {
+            self.pen = Cell::default();
+            return;
+        }
+
+        let mut i = 0;
+        while i < params.len() {
+            let param = params[i];
+            i += 1;
+            let attrs = &mut self.pen.attrs;
+            match param[0] {
+                0 => self.pen = Cell::default(),
+                1 => attrs.bold = true,
+                2 => attrs.dim = true,
+                3 => attrs.italic = true,
+                // 4:0 turns underline off; other styles all count as underline
+                4 => attrs.underline = param.get(1) != Some(&0),
+                5 | 6 => attrs.blink = true,
+                7 => attrs.reverse = true,
+                8 => attrs.hidden = true,
+                9 => attrs.strikethrough = true,
+                21 => attrs.underline = true,
+                22 => {
+                    attrs.bold = false;
+                    attrs.dim = false;
+                }
+                23 => attrs.italic = false,
+                24 => attrs.underline = false,
+                25 => attrs.blink = false,
+                27 => attrs.reverse = false,
+                28 => attrs.hidden = false,
+                29 => attrs.strikethrough = false,
+                code @ 30..=37 => self.pen.fg = Some(CellColor::Indexed((code - 30) as u8)),
+                39 => self.pen.fg = None,
+                code @ 40..=47 => self.pen.bg = Some(CellColor::Indexed((code - 40) as u8)),
+                49 => self.pen.bg = None,
+                code @ 90..=97 => self.pen.fg = Some(CellColor::Indexed((code - 90 + 8) as u8)),
+                code @ 100..=107 => self.pen.bg = Some(CellColor::Indexed((code - 100 + 8) as u8)),
+                code @ (38 | 48 | 58) => {
+                    let color = if param.len() > 1 {
+                        extended_color(&param[1..])
+                    } else {
+                        // Semicolon form: the color spec is in the following params
+                        let rest: Vec<u16> = params[i..]
+                            .iter()
+                            .filter_map(|p| p.first())
+                            .copied()
+                            .collect();
+                        i += match rest.first() {
+                            Some(5) => 2,
+                            Some(2) => 4,
+                            _ => 0,
+                        }
+                        .min(rest.len());
+                        extended_color(&rest)
+                    };
+                    // 58 is the underline color, which there is nowhere to show
+                    match code {
+                        38 => self.pen.fg = color.or(self.pen.fg),
+                        48 => self.pen.bg = color.or(self.pen.bg),
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Blank cell carrying the current background, as erasing leaves it
+    fn blank(&self) -> Cell {
+        Cell {
+            bg: self.pen.bg,
+            ..Cell::default()
+        }
+    }
+
+    fn blank_row(&self) -> Row {
+        Row::new(vec![self.blank(); self.width])
+    }
+}
+
+/// A revision no terminal has had yet
+fn next_revision() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+fn blank_screen(width: usize, height: usize) -> Vec<Row> {
+    vec![Row::new(vec![Cell::default(); width]); height]
+}
+
+/// Copy a screen into new dimensions, cropping or padding with blanks
+fn resized_screen(screen: &[Row], width: usize, height: usize) -> Vec<Row> {
+    let mut new_screen = blank_screen(width, height);
+    for (new_row, row) in new_screen.iter_mut().zip(screen) {
+        for (new_cell, cell) in new_row.cells.iter_mut().zip(&row.cells) {
+            *new_cell = cell.clone();
+        }
+        repair_wide_chars(&mut new_row.cells);
+    }
+    new_screen
+}
+
+/// Rewrap rows to a new width
+/// Rows joined by soft wraps form one logical line, so text wrapped at the
+/// old width flows back together. Blank lines below the cursor are dropped.
+/// `cursor` is a column and an index into `rows`, and is returned mapped onto
+/// the new rows
+fn reflow(rows: Vec<Row>, cursor: (usize, usize), width: usize) -> (Vec<Row>, (usize, usize)) {
+    // Join soft-wrapped rows, noting the line and offset of the cursor
+    let mut lines: Vec<Vec<Cell>> = Vec::new();
+    let mut current = Vec::new();
+    let mut cursor_at = (0, 0);
+    for (idx, row) in rows.into_iter().enumerate() {
+        // A blank before a wrapped wide character is where it didn't fit
+        let starts_wide = row.cells.first().is_some_and(|cell| cell.width == 2);
+        if starts_wide && current.last().is_some_and(Cell::is_blank) {
+            current.pop();
+        }
+        if idx == cursor.1 {
+            cursor_at = (lines.len(), current.len() + cursor.0);
+        }
+        let wrapped = row.wrapped;
+        current.extend(row.cells);
+        if !wrapped {
+            lines.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    // Trailing blanks are padding from the old width, not text
+    for line in &mut lines {
+        let end = line
+            .iter()
+            .rposition(|cell| !cell.is_blank())
+            .map_or(0, |idx| idx + 1);
+        line.truncate(end);
+    }
+    if let Some(line) = lines.get_mut(cursor_at.0) {
+        if line.len() < cursor_at.1 {
+            line.resize(cursor_at.1, Cell::default());
+        }
+    }
+    while lines.len() > cursor_at.0 + 1 && lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    let mut new_rows = Vec::new();
+    let mut new_cursor = (0, 0);
+    let finish_row = |cells: &mut Vec<Cell>, wrapped: bool, rows: &mut Vec<Row>| {
+        let mut cells = std::mem::take(cells);
+        cells.resize(width, Cell::default());
+        repair_wide_chars(&mut cells);
+        rows.push(Row { cells, wrapped });
+    };
+    for (idx, line) in lines.into_iter().enumerate() {
+        let cursor_offset = (idx == cursor_at.0).then_some(cursor_at.1);
+        let len = line.len();
+        let mut cells = Vec::with_capacity(width);
+        for (offset, cell) in line.into_iter().enumerate() {
+            // Wide characters move to the next row whole
+            let fits = cell.is_continuation() || cells.len() + cell.width as usize <= width;
+            if !fits && !cells.is_empty() {
+                finish_row(&mut cells, true, &mut new_rows);
+            }
+            if cursor_offset == Some(offset) {
+                new_cursor = (cells.len(), new_rows.len());
+            }
+            cells.push(cell);
+        }
+        if cursor_offset == Some(len) {
+            new_cursor = (cells.len(), new_rows.len());
+        }
+        finish_row(&mut cells, false, &mut new_rows);
+    }
+    if new_rows.is_empty() {
+        new_rows.push(Row::new(vec![Cell::default(); width]));
+    }
+
+    (new_rows, new_cursor)
+}
+
+/// Blank the halves of wide characters that lost their other half
+fn repair_wide_chars(row: &mut [Cell]) {
+    for x in 0..row.len() {
+        let orphaned = if row[x].is_continuation() {
+            x == 0 || row[x - 1].width != 2
+        } else {
+            row[x].width == 2 && row.get(x + 1).is_none_or(|next| !next.is_continuation())
+        };
+        if orphaned {
+            row[x] = Cell {
+                ch: ' ',
+                combining: None,
+                width: 1,
+                ..row[x].clone()
+            };
+        }
+    }
+}
+
+/// Text of a row as the host terminal would show it
+pub fn row_text(row: &[Cell]) -> String {
+    let mut text = String::with_capacity(row.len());
+    for cell in row {
+        cell.push_text(&mut text);
+    }
+    text
+}
+
+/// Parse the color spec following 38/48: `5;n` or `2;r;g;b`
+/// The colon form may carry a color space id before r, g and b
+fn extended_color(spec: &[u16]) -> Option<CellColor> {
+    let channel = |v: u16| v.min(255) as u8;
+    match spec {
+        [5, n, ..] => Some(CellColor::Indexed(channel(*n))),
+        [2, _, r, g, b, ..] => Some(CellColor::Rgb(channel(*r), channel(*g), channel(*b))),
+        [2, r, g, b] => Some(CellColor::Rgb(channel(*r), channel(*g), channel(*b))),
+        _ => None,
+    }
+}
+
+/// Numeric parameter `index` of a CSI sequence; 0 or missing means `default`
+fn param(params: &Params, index: usize, default: u16) -> usize {
+    params
+        .iter()
+        .nth(index)
+        .and_then(|p| p.first())
+        .copied()
+        .filter(|&v| v != 0)
+        .unwrap_or(default) as usize
+}
+
+/// Encode a row as text with SGR escapes, without trailing blanks
+fn row_to_ansi(row: &[Cell]) -> String {
+    let end = row
+        .iter()
+        .rposition(|cell| cell.ch != ' ' || cell.bg.is_some())
+        .map_or(0, |idx| idx + 1);
+
+    let mut out = String::new();
+    let mut current = Cell::default();
+    for cell in &row[..end] {
+        if !cell.same_style(&current) {
+            out.push_str(&sgr_sequence(cell));
+            current = cell.clone();
+        }
+        cell.push_text(&mut out);
+    }
+    if !current.same_style(&Cell::default()) {
+        out.push_str("\x1b[0m");
+    }
+    out
+}
+
+/// SGR sequence that resets and then sets a cell's colors and attributes
+fn sgr_sequence(cell: &Cell) -> String {
+    let mut codes = vec!["0".to_string()];
+    let attrs = cell.attrs;
+    for (set, code) in [
+        (attrs.bold, "1"),
+        (attrs.dim, "2"),
+        (attrs.italic, "3"),
+        (attrs.underline, "4"),
+        (attrs.blink, "5"),
+        (attrs.reverse, "7"),
+        (attrs.hidden, "8"),
+        (attrs.strikethrough, "9"),
+    ] {
+        if set {
+            codes.push(code.to_string());
+        }
+    }
+    if let Some(fg) = cell.fg {
+        codes.push(color_code(fg, 30, 90, 38));
+    }
+    if let Some(bg) = cell.bg {
+        codes.push(color_code(bg, 40, 100, 48));
+    }
+    format!("\x1b[{}m", codes.join(";"))
+}
+
+/// SGR code for a color, using the short forms for the 16 basic colors
+fn color_code(color: CellColor, normal: u16, bright: u16, extended: u16) -> String {
+    match color {
+        CellColor::Indexed(n @ 0..=7) => (normal + n as u16).to_string(),
+        CellColor::Indexed(n @ 8..=15) => (bright + n as u16 - 8).to_string(),
+        CellColor::Indexed(n) => format!("{};5;{}", extended, n),
+        CellColor::Rgb(r, g, b) => format!("{};2;{};{};{}", extended, r, g, b),
     }
 }
 
@@ -188,11 +956,11 @@ impl Perform for VirtualTerminal {
             b'\n' => self.write_char('\n'),
             b'\r' => self.write_char('\r'),
             b'\t' => self.write_char('\t'),
+            // Vertical tab and form feed move down like a line feed
+            0x0b | 0x0c => self.linefeed(),
             0x08 => {
                 // Backspace
-                if self.cursor_x > 0 {
-                    self.cursor_x -= 1;
-                }
+                self.cursor_x = self.cursor_x.min(self.width).saturating_sub(1);
             }
             _ => {}
         }
@@ -206,65 +974,286 @@ impl Perform for VirtualTerminal {
 
     fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {}
 
-    fn csi_dispatch(&mut self, params: &Params, _intermediates: &[u8], _ignore: bool, c: char) {
-        match c {
-            'H' | 'f' => {
-                // Cursor position
-                let mut iter = params.iter();
-                let y = iter.next().and_then(|p| p.first()).copied().unwrap_or(1) as usize;
-                let x = iter.next().and_then(|p| p.first()).copied().unwrap_or(1) as usize;
-                self.cursor_y = (y.saturating_sub(1)).min(self.height - 1);
-                self.cursor_x = (x.saturating_sub(1)).min(self.width - 1);
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+
+        match (intermediates, c) {
+            ([b'?'], 'h') => self.set_private_mode(params, true),
+            ([b'?'], 'l') => self.set_private_mode(params, false),
+            // Anything else with a private marker or intermediates is a
+            // mode or report we don't emulate
+            ([_, ..], _) => {}
+            (_, 'A') => {
+                // Cursor up, stopping at the top margin when inside the region
+                let top = if self.cursor_y >= self.scroll_top {
+                    self.scroll_top
+                } else {
+                    0
+                };
+                self.cursor_y = self.cursor_y.saturating_sub(param(params, 0, 1)).max(top);
             }
-            'J' => {
-                // Clear screen
-                let param = params
-                    .iter()
-                    .next()
-                    .and_then(|p| p.first())
-                    .copied()
-                    .unwrap_or(0);
-                if param == 2 {
-                    self.clear_screen();
-                }
+            (_, 'B') => {
+                // Cursor down, stopping at the bottom margin when inside the region
+                let bottom = if self.cursor_y <= self.scroll_bottom {
+                    self.scroll_bottom
+                } else {
+                    self.height - 1
+                };
+                self.cursor_y = (self.cursor_y + param(params, 0, 1)).min(bottom);
+            }
+            (_, 'C') => {
+                self.cursor_x = (self.cursor_x + param(params, 0, 1)).min(self.width - 1);
+            }
+            (_, 'D') => {
+                self.cursor_x = self
+                    .cursor_x
+                    .min(self.width - 1)
+                    .saturating_sub(param(params, 0, 1));
+            }
+            (_, 'E') => {
+                // Cursor next line
+                self.cursor_y = (self.cursor_y + param(params, 0, 1)).min(self.height - 1);
+                self.cursor_x = 0;
+            }
+            (_, 'F') => {
+                // Cursor previous line
+                self.cursor_y = self.cursor_y.saturating_sub(param(params, 0, 1));
+                self.cursor_x = 0;
+            }
+            (_, 'G') | (_, '`') => {
+                // Cursor horizontal absolute
+                self.cursor_x = (param(params, 0, 1) - 1).min(self.width - 1);
+            }
+            (_, 'd') => {
+                // Line position absolute
+                self.cursor_y = (param(params, 0, 1) - 1).min(self.height - 1);
+            }
+            (_, 'H') | (_, 'f') => {
+                // Cursor position
+                self.cursor_y = (param(params, 0, 1) - 1).min(self.height - 1);
+                self.cursor_x = (param(params, 1, 1) - 1).min(self.width - 1);
             }
-            'K' => {
+            (_, 'J') => self.erase_display(param(params, 0, 0) as u16),
+            (_, 'K') => {
                 // Clear line
+                let mode = param(params, 0, 0) as u16;
                 if self.cursor_y < self.height {
-                    for x in self.cursor_x..self.width {
-                        self.screen[self.cursor_y][x] = Cell::default();
-                    }
+                    self.erase_line(mode);
                 }
             }
-            'm' => {
-                // SGR - Set graphics rendition
-                if params.is_empty() {
-                    // Reset
-                    self.current_fg = None;
-                    self.current_bg = None;
-                    self.current_bold = false;
+            (_, 'L') => self.insert_lines(param(params, 0, 1)),
+            (_, 'M') => self.delete_lines(param(params, 0, 1)),
+            (_, '@') => self.insert_chars(param(params, 0, 1)),
+            (_, 'P') => self.delete_chars(param(params, 0, 1)),
+            (_, 'X') => {
+                // Erase characters, without moving the rest of the line
+                let x = self.cursor_x.min(self.width - 1);
+                self.erase_cells(self.cursor_y, x, x + param(params, 0, 1));
+            }
+            (_, 'S') => self.scroll_up(param(params, 0, 1)),
+            (_, 'T') => self.scroll_down(param(params, 0, 1)),
+            (_, 'r') => {
+                // Set scroll region; an invalid one resets it to the full screen
+                let top = param(params, 0, 1) - 1;
+                let bottom = param(params, 1, self.height as u16) - 1;
+                if top < bottom && bottom < self.height {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
                 } else {
-                    for param in params.iter() {
-                        if let Some(&code) = param.first() {
-                            match code {
-                                0 => {
-                                    self.current_fg = None;
-                                    self.current_bg = None;
-                                    self.current_bold = false;
-                                }
-                                1 => self.current_bold = true,
-                                22 => self.current_bold = false,
-                                30..=37 => self.current_fg = Some((code - 30) as u8),
-                                40..=47 => self.current_bg = Some((code - 40) as u8),
-                                _ => {}
-                            }
-                        }
-                    }
+                    self.scroll_top = 0;
+                    self.scroll_bottom = self.height - 1;
                 }
+                self.cursor_x = 0;
+                self.cursor_y = 0;
             }
+            (_, 's') => self.save_cursor(),
+            (_, 'u') => self.restore_cursor(),
+            (_, 'm') => self.set_graphics_rendition(params),
             _ => {}
         }
     }
 
-    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {}
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        // Charset designations and the like carry intermediates; none matter here
+        if !intermediates.is_empty() || self.width == 0 || self.height == 0 {
+            return;
+        }
+
+        match byte {
+            b'7' => self.save_cursor(),
+            b'8' => self.restore_cursor(),
+            b'D' => self.linefeed(),
+            b'E' => {
+                self.cursor_x = 0;
+                self.linefeed();
+            }
+            b'M' => self.reverse_index(),
+            b'c' => self.reset(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text_export() {
+        let mut term = VirtualTerminal::new(20, 5);
+        term.feed_bytes(b"\x1b[31merror:\x1b[0m boom\r\nnext line\r\n");
+
+        assert_eq!(term.to_plain_text(), "error: boom\nnext line\n");
+        assert_eq!(term.to_ansi(), "\x1b[0;31merror:\x1b[0m boom\nnext line\n");
+    }
+
+    #[test]
+    fn test_scrollback_trim_is_counted() {
+        let mut term = VirtualTerminal::new(10, 2);
+        for i in 0..MAX_SCROLLBACK + 10 {
+            term.feed_bytes(format!("{}\n", i).as_bytes());
+        }
+
+        assert_eq!(term.dropped_lines(), SCROLLBACK_TRIM);
+        let first = term.get_scrollback()[0].cells[0].ch;
+        assert_eq!(first, '1'); // line 1000 is now the oldest
+    }
+
+    #[test]
+    fn test_sgr_colors_and_attributes() {
+        let mut term = VirtualTerminal::new(10, 2);
+        term.feed_bytes(b"\x1b[1;3;91ma\x1b[38;5;208;48;2;1;2;3mb\x1b[38:2::10:20:30;22;23mc");
+        term.feed_bytes(b"\x1b[0;4;7;9md\x1b[24;27;29;39;49me");
+
+        let row = &term.get_screen()[0].cells;
+        assert_eq!(row[0].fg, Some(CellColor::Indexed(9)));
+        assert!(row[0].attrs.bold && row[0].attrs.italic);
+        assert_eq!(row[1].fg, Some(CellColor::Indexed(208)));
+        assert_eq!(row[1].bg, Some(CellColor::Rgb(1, 2, 3)));
+        assert_eq!(row[2].fg, Some(CellColor::Rgb(10, 20, 30)));
+        assert!(!row[2].attrs.bold && !row[2].attrs.italic);
+        assert!(row[3].attrs.underline && row[3].attrs.reverse && row[3].attrs.strikethrough);
+        assert_eq!(
+            row[4],
+            Cell {
+                ch: 'e',
+                ..Cell::default()
+            }
+        );
+
+        assert_eq!(
+            term.to_ansi(),
+            "\x1b[0;1;3;91ma\x1b[0;1;3;38;5;208;48;2;1;2;3mb\x1b[0;38;2;10;20;30;48;2;1;2;3mc\
+             \x1b[0;4;7;9md\x1b[0me\n"
+        );
+    }
+
+    #[test]
+    fn test_wide_and_combining_chars() {
+        let mut term = VirtualTerminal::new(5, 3);
+        term.feed_bytes("日本e\u{301}x".as_bytes());
+
+        let row = &term.get_screen()[0].cells;
+        assert_eq!(row[0].width, 2);
+        assert!(row[1].is_continuation());
+        assert_eq!(row[4].ch, 'e');
+        assert_eq!(row[4].combining.as_deref(), Some("\u{301}"));
+        // "x" no longer fits after the 5 columns used, so it wraps
+        assert_eq!(screen_text(&term), vec!["日本e\u{301}", "x", ""]);
+
+        // A wide character that doesn't fit in the last column wraps early,
+        // and overwriting half of one blanks the other half
+        term.feed_bytes("\x1b[2;1Habcd🦀\x1b[1;2Hz".as_bytes());
+        assert_eq!(screen_text(&term), vec![" z本e\u{301}", "abcd", "🦀"]);
+    }
+
+    fn screen_text(term: &VirtualTerminal) -> Vec<String> {
+        term.get_screen()
+            .iter()
+            .map(|row| row_text(&row.cells).trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_cursor_movement_and_erase() {
+        let mut term = VirtualTerminal::new(10, 3);
+        term.feed_bytes(b"abcdef\r\nghij\x1b[A\x1b[3D\x1b[C\x1b[K");
+        term.feed_bytes(b"\x1b[3;2Hx\x1b[1GY\x1b[2;3H\x1b[1K");
+
+        assert_eq!(screen_text(&term), vec!["ab", "   j", "Yx"]);
+    }
+
+    #[test]
+    fn test_progress_lines_redrawn_in_place() {
+        // The way nix-output-monitor redraws its status block
+        let mut term = VirtualTerminal::new(20, 5);
+        term.feed_bytes(b"building a\r\nbuilding b\r\n");
+        term.feed_bytes(b"\x1b[2F\x1b[Jbuilt a\r\nbuilding b\r\n");
+
+        assert_eq!(screen_text(&term)[..3], ["built a", "building b", ""]);
+    }
+
+    #[test]
+    fn test_insert_delete_chars_and_lines() {
+        let mut term = VirtualTerminal::new(6, 3);
+        term.feed_bytes(b"abcdef\x1b[1;2H\x1b[2@\x1b[1;5H\x1b[P");
+        assert_eq!(screen_text(&term)[0], "a  bd");
+
+        term.feed_bytes(b"\x1b[2;1Hline2\x1b[3;1Hline3\x1b[2;1H\x1b[L");
+        assert_eq!(screen_text(&term), vec!["a  bd", "", "line2"]);
+        term.feed_bytes(b"\x1b[M\x1b[M");
+        assert_eq!(screen_text(&term), vec!["a  bd", "", ""]);
+    }
+
+    #[test]
+    fn test_scroll_region_keeps_header() {
+        let mut term = VirtualTerminal::new(10, 4);
+        term.feed_bytes(b"header\x1b[2;4r\x1b[2;1H1\r\n2\r\n3\r\n4");
+
+        assert_eq!(screen_text(&term), vec!["header", "2", "3", "4"]);
+        // Lines scrolled out of a region below the top don't reach the scrollback
+        assert!(term.get_scrollback().is_empty());
+    }
+
+    #[test]
+    fn test_alt_screen_restores_primary() {
+        let mut term = VirtualTerminal::new(10, 3);
+        term.feed_bytes(b"shell\x1b[?1049h\x1b[?25l\x1b[Hfullscreen");
+        assert_eq!(screen_text(&term)[0], "fullscreen");
+        assert!(term.cursor().is_none());
+
+        term.feed_bytes(b"\x1b[?1049l\x1b[?25h!");
+        assert_eq!(screen_text(&term)[0], "shell!");
+        assert_eq!(term.cursor(), Some((6, 0)));
+    }
+
+    #[test]
+    fn test_save_restore_cursor_and_reverse_index() {
+        let mut term = VirtualTerminal::new(10, 3);
+        term.feed_bytes(b"ab\x1b7\r\ncd\x1b8X");
+        assert_eq!(screen_text(&term), vec!["abX", "cd", ""]);
+
+        term.feed_bytes(b"\x1b[H\x1bMtop");
+        assert_eq!(screen_text(&term), vec!["top", "abX", "cd"]);
+    }
+
+    #[test]
+    fn test_resize_reflows_wrapped_lines() {
+        let mut term = VirtualTerminal::new(10, 3);
+        term.feed_bytes("abcdefghijklm\r\n日本語x".as_bytes());
+
+        term.resize(5, 3);
+        assert_eq!(screen_text(&term), vec!["klm", "日本", "語x"]);
+        assert_eq!(row_text(&term.get_scrollback()[1].cells), "fghij");
+        assert_eq!(term.cursor(), Some((3, 2)));
+
+        // Widening joins the wrapped rows back together
+        term.resize(10, 3);
+        assert!(term.get_scrollback().is_empty());
+        assert_eq!(screen_text(&term), vec!["abcdefghij", "klm", "日本語x"]);
+        term.feed_bytes(b"!");
+        assert_eq!(screen_text(&term)[2], "日本語x!");
+    }
 }
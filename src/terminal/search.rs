@@ -0,0 +1,138 @@
+use super::VirtualTerminal;
+use regex::Regex;
+use std::ops::Range;
+
+/// A match of a search in the output
+/// Rows joined by a soft wrap are searched as one line, so a match can
+/// continue on the rows after the one it starts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchMatch {
+    /// Index into the scrollback followed by the screen of the row the match
+    /// starts on
+    pub line: usize,
+    /// Cell column the match starts at
+    pub start: usize,
+    /// Row the match ends on, the same as `line` unless it crosses a wrap
+    pub end_line: usize,
+    /// Cell column just past the end of the match on `end_line`
+    pub end: usize,
+}
+
+impl SearchMatch {
+    /// Columns of a row the match covers, if it touches the row at all
+    pub fn columns_on(&self, line: usize) -> Option<Range<usize>> {
+        if line < self.line || line > self.end_line {
+            return None;
+        }
+        let start = if line == self.line { self.start } else { 0 };
+        let end = if line == self.end_line {
+            self.end
+        } else {
+            usize::MAX
+        };
+        Some(start..end)
+    }
+}
+
+impl VirtualTerminal {
+    /// Every non-empty match of `regex` in the scrollback and screen, in order
+    pub fn search(&self, regex: &Regex) -> Vec<SearchMatch> {
+        let mut matches = Vec::new();
+        let mut line = LogicalLine::default();
+        for (idx, row) in self.scrollback.iter().chain(self.screen.iter()).enumerate() {
+            for (x, cell) in row.cells.iter().enumerate() {
+                cell.push_text(&mut line.text);
+                line.cells.resize(line.text.len(), (idx, x));
+            }
+            line.widths.push((idx, row.cells.len()));
+            if !row.wrapped {
+                line.find(regex, &mut matches);
+            }
+        }
+        line.find(regex, &mut matches);
+        matches
+    }
+}
+
+/// Text of soft-wrapped rows joined up, with where each byte came from
+#[derive(Default)]
+struct LogicalLine {
+    text: String,
+    /// Row and column of the cell each byte of `text` came from
+    cells: Vec<(usize, usize)>,
+    /// Number of cells of each row in the line
+    widths: Vec<(usize, usize)>,
+}
+
+impl LogicalLine {
+    /// Add the matches in the line to `matches` and start a new line
+    fn find(&mut self, regex: &Regex, matches: &mut Vec<SearchMatch>) {
+        for m in regex.find_iter(&self.text).filter(|m| !m.is_empty()) {
+            let (line, start) = self.cells[m.start()];
+            let (end_line, last) = self.cells[m.end() - 1];
+            // The cell after the match, unless the match ends its row
+            let end = match self.cells.get(m.end()) {
+                Some(&(row, x)) if row == end_line => x,
+                _ => self
+                    .widths
+                    .iter()
+                    .find(|(row, _)| *row == end_line)
+                    .map_or(last + 1, |(_, width)| *width),
+            };
+            matches.push(SearchMatch {
+                line,
+                start,
+                end_line,
+                end,
+            });
+        }
+        self.text.clear();
+        self.cells.clear();
+        self.widths.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_maps_matches_to_columns() {
+        let mut term = VirtualTerminal::new(16, 4);
+        term.feed_bytes("ok\r\n日本 error: x\r\nerror: y\r\n0123456789abcdefwrapped".as_bytes());
+
+        let regex = Regex::new("error: ?").unwrap();
+        let matches = term.search(&regex);
+        assert_eq!(
+            matches,
+            vec![
+                SearchMatch {
+                    line: 1,
+                    start: 5,
+                    end_line: 1,
+                    end: 12
+                },
+                SearchMatch {
+                    line: 2,
+                    start: 0,
+                    end_line: 2,
+                    end: 7
+                },
+            ]
+        );
+
+        // Soft-wrapped onto the next row
+        let matches = term.search(&Regex::new("efwr").unwrap());
+        assert_eq!(
+            matches,
+            vec![SearchMatch {
+                line: 3,
+                start: 14,
+                end_line: 4,
+                end: 2
+            }]
+        );
+        assert_eq!(matches[0].columns_on(3), Some(14..usize::MAX));
+        assert_eq!(matches[0].columns_on(4), Some(0..2));
+    }
+}
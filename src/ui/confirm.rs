@@ -0,0 +1,48 @@
+use crate::app::App;
+use ratatui::{
+    layout::{Alignment, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Frame,
+};
+
+/// Render the pending confirmation as a popup over the middle of the screen
+pub fn render(frame: &mut Frame, app: &App) {
+    let Some(ref confirmation) = app.confirmation else {
+        return;
+    };
+
+    let area = frame.area();
+    let width = area.width.clamp(20, 64).min(area.width);
+    let height = 6.min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    let text = vec![
+        Line::from(confirmation.prompt.as_str()),
+        Line::from(""),
+        Line::from(vec![
+            Span::styled("y", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(": confirm   "),
+            Span::styled("n/Esc", Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(": cancel"),
+        ]),
+    ];
+    let paragraph = Paragraph::new(text)
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true })
+        .block(
+            Block::default()
+                .title(" confirm ")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Yellow)),
+        );
+
+    frame.render_widget(Clear, popup);
+    frame.render_widget(paragraph, popup);
+}
@@ -0,0 +1,77 @@
+use crate::app::App;
+use crate::nix::diagnostics::DiagnosticKind;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(ref panel) = app.diagnostics_panel else {
+        return;
+    };
+    let diagnostics = app.diagnostics();
+    let selected = app.selected_diagnostic();
+
+    let items: Vec<ListItem> = if diagnostics.is_empty() {
+        vec![ListItem::new("no errors or warnings in this output.")]
+    } else {
+        diagnostics
+            .iter()
+            .enumerate()
+            .map(|(idx, diagnostic)| {
+                let (symbol, color) = match diagnostic.kind {
+                    DiagnosticKind::Error => ("✗", Color::Red),
+                    DiagnosticKind::BuilderFailed => ("✗", Color::Magenta),
+                    DiagnosticKind::Warning => ("⚠", Color::Yellow),
+                };
+                let message = if diagnostic.message.is_empty() {
+                    "error"
+                } else {
+                    &diagnostic.message
+                };
+                let mut lines = vec![Line::from(vec![
+                    Span::styled(format!("{} ", symbol), Style::default().fg(color)),
+                    Span::raw(message.to_string()),
+                ])];
+                if let Some(ref location) = diagnostic.location {
+                    lines.push(Line::from(Span::styled(
+                        format!("  at {}", location),
+                        Style::default().fg(Color::Cyan),
+                    )));
+                }
+                if panel.expanded && selected == Some(idx) {
+                    for frame in &diagnostic.trace {
+                        lines.push(Line::from(Span::styled(
+                            format!("  … {}", frame),
+                            Style::default().fg(Color::DarkGray),
+                        )));
+                    }
+                }
+                ListItem::new(Text::from(lines))
+            })
+            .collect()
+    };
+
+    let count = |kind: DiagnosticKind| diagnostics.iter().filter(|d| d.kind == kind).count();
+    let title = format!(
+        " diagnostics: {} errors, {} failed builds, {} warnings [j/k:select | enter:trace | D:close] ",
+        count(DiagnosticKind::Error),
+        count(DiagnosticKind::BuilderFailed),
+        count(DiagnosticKind::Warning)
+    );
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Cyan)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED));
+
+    let mut state = ListState::default();
+    state.select(selected);
+    frame.render_stateful_widget(list, area, &mut state);
+}
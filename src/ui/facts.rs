@@ -0,0 +1,107 @@
+use crate::app::App;
+use crate::nix::flake::Drift;
+use crate::util::format_duration;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Wrap},
+    Frame,
+};
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let entry = app.selected_facts();
+    let label = |name: &str| Span::styled(format!("{:<9}", name), Style::default().fg(Color::Gray));
+
+    let mut lines = Vec::new();
+    match entry.and_then(|e| e.facts.as_ref()) {
+        Some(facts) => {
+            lines.push(Line::from(vec![
+                label("version"),
+                Span::raw(facts.nixos_version.clone()),
+            ]));
+            lines.push(Line::from(vec![
+                label("kernel"),
+                Span::raw(facts.kernel.clone()),
+            ]));
+            lines.push(Line::from(vec![
+                label("uptime"),
+                Span::raw(format_duration(facts.uptime)),
+            ]));
+            lines.push(Line::from(vec![
+                label("current"),
+                Span::styled(
+                    facts.current_system.clone(),
+                    Style::default().fg(Color::Cyan),
+                ),
+            ]));
+            lines.push(Line::from(vec![
+                label("booted"),
+                Span::styled(
+                    facts.booted_system.clone(),
+                    Style::default().fg(Color::Cyan),
+                ),
+            ]));
+            if let Some(Drift::Drifted { expected, .. }) = app
+                .get_selected_host()
+                .and_then(|(name, _)| app.drift.get(&name))
+            {
+                lines.push(Line::from(vec![
+                    label("flake"),
+                    Span::styled(expected.clone(), Style::default().fg(Color::Yellow)),
+                ]));
+            }
+            lines.push(if facts.reboot_required() {
+                Line::from(Span::styled(
+                    format!(
+                        "⚠ reboot required: {} changed",
+                        facts.changed_boot_parts.join(", ")
+                    ),
+                    Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                ))
+            } else {
+                Line::from(Span::styled(
+                    "✓ running the current kernel and initrd",
+                    Style::default().fg(Color::Green),
+                ))
+            });
+        }
+        None if app.get_selected_host().is_none() => {
+            lines.push(Line::from("no host selected."));
+        }
+        None if entry.is_none() => {
+            lines.push(Line::from("host has no connection configured."));
+        }
+        None => {}
+    }
+
+    if let Some(entry) = entry {
+        if let Some(ref error) = entry.error {
+            lines.push(Line::from(Span::styled(
+                error.clone(),
+                Style::default().fg(Color::Red),
+            )));
+        } else if entry.loading.is_some() && entry.facts.is_none() {
+            lines.push(Line::from(Span::styled(
+                "gathering facts...",
+                Style::default().fg(Color::Yellow),
+            )));
+        }
+    }
+
+    let age = match entry {
+        Some(e) if e.loading.is_some() => " (refreshing)".to_string(),
+        Some(e) => e
+            .fetched_at
+            .map(|at| format!(" ({} ago)", format_duration(at.elapsed())))
+            .unwrap_or_default(),
+        None => String::new(),
+    };
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title(format!(" facts{} [r:refresh | F:close] ", age))
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Cyan)),
+    );
+    frame.render_widget(paragraph, area);
+}
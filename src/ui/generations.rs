@@ -0,0 +1,83 @@
+use crate::app::App;
+use crate::util::format_timestamp;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(ref view) = app.generations_view else {
+        return;
+    };
+
+    let items: Vec<ListItem> = if view.loading.is_some() {
+        vec![ListItem::new("loading generations...")]
+    } else if let Some(ref error) = view.error {
+        vec![ListItem::new(Line::from(Span::styled(
+            format!("Failed to list generations: {}", error),
+            Style::default().fg(Color::Red),
+        )))]
+    } else if view.generations.is_empty() {
+        vec![ListItem::new("no system generations found.")]
+    } else {
+        view.generations
+            .iter()
+            .map(|generation| {
+                let mut spans = vec![
+                    Span::styled(
+                        format!("{:>5}  ", generation.number),
+                        Style::default().fg(Color::Cyan),
+                    ),
+                    Span::raw(format!("{}  ", format_timestamp(generation.created_at))),
+                    Span::raw(format!("{:<28}", or_dash(&generation.nixos_version))),
+                    Span::styled(
+                        format!("{:<12}", or_dash(&generation.kernel)),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                ];
+                if generation.current {
+                    spans.push(Span::styled(" current", Style::default().fg(Color::Green)));
+                }
+                if generation.booted {
+                    spans.push(Span::styled(" booted", Style::default().fg(Color::Magenta)));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect()
+    };
+
+    let title = format!(
+        " generations: {} [j/k:select | enter:switch to | r:rollback | Esc:close] (UTC) ",
+        view.host
+    );
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Cyan)),
+        )
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default();
+    if !view.generations.is_empty() {
+        state.select(Some(view.selected));
+    }
+    frame.render_stateful_widget(list, area, &mut state);
+}
+
+fn or_dash(s: &str) -> &str {
+    if s.is_empty() {
+        "-"
+    } else {
+        s
+    }
+}
@@ -0,0 +1,86 @@
+use super::main_panel::status_style;
+use crate::app::App;
+use crate::session::BuildStatus;
+use crate::util::format_duration;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+/// Hosts of the selected group with how their builds went, and the summary
+/// of the group's last run
+pub fn render(frame: &mut Frame, app: &App, group: &str, area: Rect) {
+    let hosts = app.config.groups().remove(group).unwrap_or_default();
+    let run = app.group_runs.get(group);
+    let name_width = hosts.iter().map(|h| h.len()).max().unwrap_or(0);
+
+    let mut lines = Vec::new();
+    if let Some(summary) = run.and_then(|r| r.summary.as_ref()) {
+        lines.push(Line::from(Span::styled(
+            summary.clone(),
+            Style::default().fg(Color::Magenta),
+        )));
+        lines.push(Line::from(""));
+    }
+
+    for host in &hosts {
+        let configured = app
+            .config
+            .hosts
+            .get(host)
+            .is_some_and(|h| h.connection.is_configured());
+        let status = app.host_status(host);
+        let active = matches!(status, BuildStatus::Queued | BuildStatus::Running);
+        // Hosts a sequential run stopped before
+        let skipped =
+            run.is_some_and(|r| r.summary.is_some() && r.hosts[r.started..].contains(host));
+
+        let detail = if !configured {
+            "not configured".to_string()
+        } else if skipped && !active {
+            "skipped".to_string()
+        } else {
+            match app.sessions.get(host).and_then(|s| s.last_exit.as_ref()) {
+                Some(exit) if !active => {
+                    format!("{} in {}", exit.describe(), format_duration(exit.duration))
+                }
+                _ => format!("{:?}", status).to_lowercase(),
+            }
+        };
+
+        lines.push(Line::from(vec![
+            Span::styled(format!("{} ", status.symbol()), status_style(status)),
+            Span::raw(format!("{:<width$}  ", host, width = name_width)),
+            Span::styled(
+                detail,
+                if configured {
+                    Style::default()
+                } else {
+                    Style::default().fg(Color::DarkGray)
+                },
+            ),
+        ]));
+    }
+
+    let title = format!(
+        " {} - {} on {} hosts {} [enter:run | p:parallel/sequence | space:collapse] ",
+        group,
+        app.selected_operation.as_str(),
+        app.group_hosts(group).len(),
+        if app.group_parallel {
+            "in parallel"
+        } else {
+            "in sequence"
+        }
+    );
+    let paragraph = Paragraph::new(lines).block(
+        Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Cyan)),
+    );
+    frame.render_widget(paragraph, area);
+}
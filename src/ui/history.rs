@@ -0,0 +1,95 @@
+use crate::app::App;
+use crate::session::BuildStatus;
+use crate::util::{format_duration, format_timestamp};
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+use std::time::Duration;
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(ref view) = app.history_view else {
+        return;
+    };
+
+    let items: Vec<ListItem> = if view.records.is_empty() {
+        vec![ListItem::new("no builds recorded for this host yet.")]
+    } else {
+        view.records
+            .iter()
+            .map(|record| {
+                let (symbol, color) = match record.status {
+                    BuildStatus::Succeeded => ("✓", Color::Green),
+                    BuildStatus::Failed => ("✗", Color::Red),
+                    _ => ("⊘", Color::DarkGray),
+                };
+                let revision = record
+                    .flake_revision
+                    .as_deref()
+                    .or(record.git_commit.as_deref())
+                    .map(|rev| rev.chars().take(12).collect::<String>())
+                    .unwrap_or_else(|| "-".to_string());
+
+                let mut spans = vec![
+                    Span::styled(format!("{} ", symbol), Style::default().fg(color)),
+                    Span::raw(format!("{}  ", format_timestamp(record.started_at))),
+                    Span::styled(
+                        format!("{:<12}", record.operation),
+                        Style::default().fg(Color::Cyan),
+                    ),
+                    Span::raw(format!(
+                        "{:>8}  ",
+                        format_duration(Duration::from_secs_f64(record.duration_secs))
+                    )),
+                    Span::styled(revision, Style::default().fg(Color::Yellow)),
+                ];
+                if let Some(ref git_ref) = record.git_ref {
+                    spans.push(Span::styled(
+                        format!(" {}", git_ref),
+                        Style::default().fg(Color::Magenta),
+                    ));
+                }
+                if record.git_dirty {
+                    spans.push(Span::styled(" dirty", Style::default().fg(Color::Red)));
+                }
+                if record.upgrade {
+                    spans.push(Span::raw(" --upgrade"));
+                }
+                if !record.extra_args.is_empty() {
+                    spans.push(Span::styled(
+                        format!(" {}", record.extra_args.join(" ")),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect()
+    };
+
+    let title = format!(
+        " history: {} [j/k:select | enter:open log | Esc:close] (UTC) ",
+        view.host
+    );
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Cyan)),
+        )
+        .highlight_style(
+            Style::default()
+                .fg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol("> ");
+
+    let mut state = ListState::default();
+    if !view.records.is_empty() {
+        state.select(Some(view.selected));
+    }
+    frame.render_stateful_widget(list, area, &mut state);
+}
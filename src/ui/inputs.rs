@@ -0,0 +1,125 @@
+use crate::app::App;
+use crate::util::format_age;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState},
+    Frame,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length revisions are shortened to
+const REV_LEN: usize = 12;
+
+fn short(rev: &str) -> String {
+    rev.chars().take(REV_LEN).collect()
+}
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(ref view) = app.inputs_view else {
+        return;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let name_width = view.inputs.iter().map(|i| i.name.len()).max().unwrap_or(0);
+
+    let items: Vec<ListItem> = if let Some(ref error) = view.error {
+        vec![ListItem::new(Span::styled(
+            error.clone(),
+            Style::default().fg(Color::Red),
+        ))]
+    } else if view.inputs.is_empty() && view.loading.is_some() {
+        vec![ListItem::new(Span::styled(
+            "reading flake.lock...",
+            Style::default().fg(Color::Yellow),
+        ))]
+    } else if view.inputs.is_empty() {
+        vec![ListItem::new("the flake has no inputs.")]
+    } else {
+        view.inputs
+            .iter()
+            .map(|input| {
+                let mut spans = vec![Span::raw(format!(
+                    "{:<width$}  ",
+                    input.name,
+                    width = name_width
+                ))];
+                if let Some(ref follows) = input.follows {
+                    spans.push(Span::styled(
+                        format!("follows {}", follows),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                    return ListItem::new(Line::from(spans));
+                }
+
+                spans.push(Span::styled(
+                    format!(
+                        "{:<12}  ",
+                        input.rev.as_deref().map(short).unwrap_or_default()
+                    ),
+                    Style::default().fg(Color::Yellow),
+                ));
+                spans.push(Span::raw(format!(
+                    "{:>4}  ",
+                    input
+                        .last_modified
+                        .map(|at| format_age(now.saturating_sub(at)))
+                        .unwrap_or_default()
+                )));
+                spans.push(Span::styled(
+                    input.source.clone(),
+                    Style::default().fg(Color::Cyan),
+                ));
+
+                let change = view
+                    .changes
+                    .iter()
+                    .flatten()
+                    .find(|change| change.name == input.name);
+                if let Some(change) = change {
+                    spans.push(Span::styled(
+                        format!(
+                            "  updated from {}",
+                            change.before.as_deref().map(short).unwrap_or_default()
+                        ),
+                        Style::default()
+                            .fg(Color::Green)
+                            .add_modifier(Modifier::BOLD),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect()
+    };
+
+    let state_note = if view.before.is_some() {
+        " | updating...".to_string()
+    } else {
+        match view.changes {
+            Some(ref changes) if changes.is_empty() => " | already up to date".to_string(),
+            Some(ref changes) => format!(" | {} updated", changes.len()),
+            None => String::new(),
+        }
+    };
+    let title = format!(
+        " flake inputs{} [j/k:select | u:update | a:update all | U:close] ",
+        state_note
+    );
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Cyan)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED));
+
+    let mut state = ListState::default();
+    if !view.inputs.is_empty() && view.error.is_none() {
+        state.select(Some(view.selected));
+    }
+    frame.render_stateful_widget(list, area, &mut state);
+}
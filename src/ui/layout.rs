@@ -1,17 +1,95 @@
 use crate::app::{App, FocusedPanel};
 use ratatui::{
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Margin, Rect},
     Frame,
 };
+use std::rc::Rc;
 
-use super::{main_panel, settings};
+use super::{confirm, main_panel, settings};
 
-pub fn render(frame: &mut Frame, app: &App) {
-    // Create 85/15 vertical split
-    let chunks = Layout::default()
+/// Height of the progress header, borders included
+const PROGRESS_HEIGHT: u16 = 4;
+
+/// Height of the facts pane when it shares the side column, borders included
+const FACTS_HEIGHT: u16 = 10;
+
+/// Split the screen into the main panel (top 85%) and settings panel
+fn panels(area: Rect) -> Rc<[Rect]> {
+    Layout::default()
         .direction(Direction::Vertical)
         .constraints([Constraint::Percentage(85), Constraint::Percentage(15)])
-        .split(frame.area());
+        .split(area)
+}
+
+/// Split the main panel into the host list (left 25%) and output (right 75%)
+pub fn main_columns(area: Rect) -> Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(25), Constraint::Percentage(75)])
+        .split(area)
+}
+
+/// Split the output pane into the output and, if open, the side column with
+/// the facts pane and diagnostics panel
+pub fn output_columns(area: Rect, side: bool) -> Rc<[Rect]> {
+    let constraints = if side {
+        [Constraint::Percentage(60), Constraint::Percentage(40)]
+    } else {
+        [Constraint::Percentage(100), Constraint::Length(0)]
+    };
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(constraints)
+        .split(area)
+}
+
+/// Split the side column between the facts pane and the diagnostics panel
+pub fn side_rows(area: Rect, facts: bool, diagnostics: bool) -> Rc<[Rect]> {
+    let facts_height = match (facts, diagnostics) {
+        (true, true) => FACTS_HEIGHT,
+        (true, false) => area.height,
+        (false, _) => 0,
+    };
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(facts_height), Constraint::Min(0)])
+        .split(area)
+}
+
+/// Split the output pane into the flake inputs list and the output of
+/// updating them
+pub fn inputs_rows(area: Rect) -> Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(45), Constraint::Percentage(55)])
+        .split(area)
+}
+
+/// Split the output into the progress header, if shown, and the output itself
+pub fn output_rows(area: Rect, progress: bool) -> Rc<[Rect]> {
+    let header = if progress { PROGRESS_HEIGHT } else { 0 };
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(header), Constraint::Min(0)])
+        .split(area)
+}
+
+/// Area inside the output pane's borders for a screen of the given size
+/// Build PTYs are sized to it so output lines up with what is drawn
+pub fn output_area(screen: Rect, app: &App) -> Rect {
+    let mut pane = main_columns(panels(screen)[0])[1];
+    if app.is_browsing_inputs() {
+        pane = inputs_rows(pane)[1];
+    }
+    let output = output_columns(pane, app.has_side_panel())[0];
+    output_rows(output, app.selected_progress().is_some())[1].inner(Margin {
+        horizontal: 1,
+        vertical: 1,
+    })
+}
+
+pub fn render(frame: &mut Frame, app: &App) {
+    let chunks = panels(frame.area());
 
     // Render main panel (top 85%)
     let main_focused = app.focused_panel == FocusedPanel::Main;
@@ -19,4 +97,7 @@ pub fn render(frame: &mut Frame, app: &App) {
 
     // Render settings panel (bottom 15%)
     settings::render(frame, app, chunks[1]);
+
+    // Confirmation prompts float over everything else
+    confirm::render(frame, app);
 }
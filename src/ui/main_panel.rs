@@ -1,6 +1,13 @@
-use crate::app::{App, FocusedPanel};
+use super::{diagnostics, facts, generations, group, history, inputs, layout, review};
+use crate::app::{App, FocusedPanel, HostRow};
+use crate::nix::flake::Drift;
+use crate::nix::progress::BuildProgress;
+use crate::nix::reachability::Reachability;
+use crate::session::BuildStatus;
+use crate::terminal::{Attrs, Cell, CellColor, SearchMatch};
+use crate::util::{format_duration, format_size, format_timestamp};
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Rect},
+    layout::{Margin, Rect},
     style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{Block, Borders, List, ListItem, Paragraph},
@@ -9,29 +16,71 @@ use ratatui::{
 
 pub fn render(frame: &mut Frame, app: &App, area: Rect, focused: bool) {
     // Split main panel into left (host list) and right (output)
-    let chunks = Layout::default()
-        .direction(Direction::Horizontal)
-        .constraints([Constraint::Percentage(25), Constraint::Percentage(75)])
-        .split(area);
+    let chunks = layout::main_columns(area);
 
     render_host_list(frame, app, chunks[0], focused);
-    render_output_area(frame, app, chunks[1]);
+    if app.is_browsing_generations() {
+        generations::render(frame, app, chunks[1]);
+    } else if app.is_browsing_history() {
+        history::render(frame, app, chunks[1]);
+    } else if app.is_reviewing() {
+        review::render(frame, app, chunks[1]);
+    } else if app.is_browsing_inputs() {
+        let rows = layout::inputs_rows(chunks[1]);
+        inputs::render(frame, app, rows[0]);
+        render_output_pane(frame, app, rows[1]);
+    } else if let Some(group) = app.selected_group() {
+        group::render(frame, app, &group, chunks[1]);
+    } else {
+        render_output_pane(frame, app, chunks[1]);
+    }
+}
+
+/// The output with its progress header, and the side column if open
+fn render_output_pane(frame: &mut Frame, app: &App, area: Rect) {
+    let columns = layout::output_columns(area, app.has_side_panel());
+    let progress = app.selected_progress();
+    let rows = layout::output_rows(columns[0], progress.is_some());
+    if let Some(progress) = progress {
+        render_progress(frame, progress, rows[0]);
+    }
+    render_output_area(frame, app, rows[1]);
+    let diagnostics = app.diagnostics_panel.is_some();
+    let side = layout::side_rows(columns[1], app.facts_panel, diagnostics);
+    if app.facts_panel {
+        facts::render(frame, app, side[0]);
+    }
+    if diagnostics {
+        diagnostics::render(frame, app, side[1]);
+    }
 }
 
 fn render_host_list(frame: &mut Frame, app: &App, area: Rect, focused: bool) {
-    let hosts = app.get_hosts();
+    let rows = app.host_rows();
 
-    let items: Vec<ListItem> = hosts
+    let items: Vec<ListItem> = rows
         .iter()
         .enumerate()
-        .map(|(idx, (name, connection))| {
+        .map(|(idx, row)| {
+            let (name, connection) = match row {
+                HostRow::Group(group) => {
+                    return ListItem::new(group_header(app, group, idx, focused));
+                }
+                HostRow::Host(name, connection) => (name, connection),
+            };
             let conn_display = connection.display();
-            let prefix = if idx == app.selected_host_idx {
-                "> "
+            let prefix = match (idx == app.selected_host_idx, app.grouped) {
+                (true, false) => "> ",
+                (false, false) => "  ",
+                (true, true) => "  > ",
+                (false, true) => "    ",
+            };
+            let mark = if app.marked_hosts.contains(name) {
+                "*"
             } else {
-                "  "
+                " "
             };
-            let line = if connection.is_configured() {
+            let text = if connection.is_configured() {
                 format!("{}{} ({})", prefix, name, conn_display)
             } else {
                 format!("{}{} {}", prefix, name, conn_display)
@@ -47,7 +96,24 @@ fn render_host_list(frame: &mut Frame, app: &App, area: Rect, focused: bool) {
                 Style::default()
             };
 
-            ListItem::new(line).style(style)
+            let status = app.host_status(name);
+            let check = app.host_checks.get(name);
+            let mut spans = vec![
+                Span::styled(mark, Style::default().fg(Color::Magenta)),
+                Span::styled(status.symbol(), status_style(status)),
+                reachability_badge(check.and_then(|c| c.result.as_ref())),
+                drift_badge(app, name),
+                Span::styled(text, style),
+            ];
+            if let Some(checked_at) = check.and_then(|c| c.checked_at) {
+                spans.push(Span::styled(
+                    format!(" {}", format_duration(checked_at.elapsed())),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+            let line = Line::from(spans);
+
+            ListItem::new(line)
         })
         .collect();
 
@@ -57,10 +123,26 @@ fn render_host_list(frame: &mut Frame, app: &App, area: Rect, focused: bool) {
         Style::default().fg(Color::Gray)
     };
 
+    let running = app.running_count();
     let title = format!(
-        " hosts - {}{} ",
+        " hosts - {}{}{}{}{} ",
         app.selected_operation.as_str(),
-        if app.use_upgrade { " --upgrade" } else { "" }
+        if app.use_upgrade { " --upgrade" } else { "" },
+        if app.marked_hosts.is_empty() {
+            String::new()
+        } else {
+            format!(" | {} marked", app.marked_hosts.len())
+        },
+        match (app.grouped, app.group_parallel) {
+            (false, _) => "",
+            (true, true) => " | groups in parallel",
+            (true, false) => " | groups in sequence",
+        },
+        if running > 0 {
+            format!(" | {}/{} running", running, app.config.max_parallel)
+        } else {
+            String::new()
+        }
     );
     let list = List::new(items)
         .block(
@@ -78,30 +160,164 @@ fn render_host_list(frame: &mut Frame, app: &App, area: Rect, focused: bool) {
     frame.render_widget(list, area);
 }
 
-fn render_output_area(frame: &mut Frame, app: &App, area: Rect) {
-    // Resize terminal to match output area (minus borders)
-    let _term_width = area.width.saturating_sub(2) as usize;
-    let _term_height = area.height.saturating_sub(2) as usize;
+/// Header of a group's section: fold marker, name and host statuses
+fn group_header<'a>(app: &App, group: &str, idx: usize, focused: bool) -> Line<'a> {
+    let hosts = app.config.groups().remove(group).unwrap_or_default();
+    let fold = if app.collapsed_groups.contains(group) {
+        "▸"
+    } else {
+        "▾"
+    };
+    let style = if idx == app.selected_host_idx && focused {
+        Style::default()
+            .fg(Color::Yellow)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+            .fg(Color::Cyan)
+            .add_modifier(Modifier::BOLD)
+    };
 
-    // Convert terminal cells to ratatui Lines
-    let scrollback = app.terminal.get_scrollback();
-    let screen = app.terminal.get_screen();
+    let mut spans = vec![Span::styled(
+        format!("{} {} ({})", fold, group, hosts.len()),
+        style,
+    )];
+    // Counts of finished and running builds, so collapsed groups still show them
+    for status in [
+        BuildStatus::Running,
+        BuildStatus::Succeeded,
+        BuildStatus::Failed,
+    ] {
+        let count = hosts
+            .iter()
+            .filter(|h| app.host_status(h) == status)
+            .count();
+        if count > 0 {
+            spans.push(Span::styled(
+                format!(" {}{}", status.symbol(), count),
+                status_style(status),
+            ));
+        }
+    }
+    Line::from(spans)
+}
+
+/// Header with the build counts, transfers and running builders nix reported
+fn render_progress(frame: &mut Frame, progress: &BuildProgress, area: Rect) {
+    let separator = || Span::styled(" │ ", Style::default().fg(Color::DarkGray));
+    let mut counts = vec![Span::raw(format!(
+        "built {}/{}",
+        progress.builds_done, progress.builds_expected
+    ))];
+    if progress.builds_failed > 0 {
+        counts.push(Span::styled(
+            format!(" ({} failed)", progress.builds_failed),
+            Style::default().fg(Color::Red),
+        ));
+    }
+    if progress.paths_expected > 0 || progress.paths_done > 0 {
+        counts.push(separator());
+        counts.push(Span::raw(format!(
+            "copied {}/{} paths",
+            progress.paths_done, progress.paths_expected
+        )));
+    }
+    let (bytes_done, bytes_expected) = progress.bytes();
+    if bytes_expected > 0 {
+        counts.push(separator());
+        counts.push(Span::raw(format!(
+            "{} / {}",
+            format_size(bytes_done),
+            format_size(bytes_expected)
+        )));
+    }
+
+    let running = if progress.running.is_empty() {
+        Span::styled("no builders running", Style::default().fg(Color::DarkGray))
+    } else {
+        let builds: Vec<String> = progress
+            .running
+            .values()
+            .map(|build| match build.phase {
+                Some(ref phase) => format!("{} ({})", build.name, phase),
+                None => build.name.clone(),
+            })
+            .collect();
+        Span::styled(
+            format!("building {}", builds.join(", ")),
+            Style::default().fg(Color::Yellow),
+        )
+    };
+
+    let header = Paragraph::new(vec![Line::from(counts), Line::from(running)]).block(
+        Block::default()
+            .title(" progress ")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Gray)),
+    );
+    frame.render_widget(header, area);
+}
+
+fn render_output_area(frame: &mut Frame, app: &App, area: Rect) {
+    // Convert the selected host's terminal cells to ratatui Lines
+    let session = app.selected_session();
+    let scrollback = app.terminal().get_scrollback();
+    let screen = app.terminal().get_screen();
 
     let mut lines: Vec<Line> = Vec::new();
 
+    // Point at the full log when the top of the output has been trimmed
+    let dropped = app.terminal().dropped_lines();
+    if dropped > 0 {
+        let location = app
+            .shown_log_path()
+            .map(|path| format!("full output in {}", path.display()))
+            .unwrap_or_else(|| "no log file was saved".to_string());
+        lines.push(Line::from(Span::styled(
+            format!("[{} earlier lines trimmed; {}]", dropped, location),
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+
+    // Search matches touching a line; they come in order and don't overlap,
+    // so both their start and end lines only go up
+    let matches = app.search_matches();
+    let current_match = app.search.as_ref().and_then(|s| s.current);
+    let line_matches = |line: usize| {
+        let first = matches.partition_point(|m| m.end_line < line);
+        let count = matches[first..].partition_point(|m| m.line <= line);
+        &matches[first..first + count]
+    };
+
     // Add scrollback
-    for row in scrollback {
-        lines.push(cells_to_line(row));
+    for (line, row) in scrollback.iter().enumerate() {
+        lines.push(cells_to_line(
+            &row.cells,
+            None,
+            line,
+            line_matches(line),
+            current_match,
+        ));
     }
 
-    // Add current screen
-    for row in screen {
-        lines.push(cells_to_line(row));
+    // Add current screen, showing the cursor while typing into the build
+    let cursor = app.input_mode.then(|| app.terminal().cursor()).flatten();
+    let cursor_line = cursor.map(|(_, y)| lines.len() + y);
+    for (y, row) in screen.iter().enumerate() {
+        let cursor_x = cursor.filter(|&(_, cy)| cy == y).map(|(x, _)| x);
+        let line = scrollback.len() + y;
+        lines.push(cells_to_line(
+            &row.cells,
+            cursor_x,
+            line,
+            line_matches(line),
+            current_match,
+        ));
     }
 
     // If empty, show placeholder
     if lines.is_empty() {
-        if app.is_building {
+        if app.selected_is_building() {
             lines.push(Line::from("building..."));
         } else {
             lines.push(Line::from(
@@ -111,21 +327,61 @@ fn render_output_area(frame: &mut Frame, app: &App, area: Rect) {
     }
 
     // Show scroll position in title if scrolled, or building status
-    let title = if app.input_mode {
+    let title = if let Some(search) = app.search.as_ref() {
+        let counter = match app.search_counter() {
+            _ if search.is_invalid() => "invalid regex".to_string(),
+            (Some(position), total) => format!("{}/{}", position, total),
+            (None, total) => format!("{} matches", total),
+        };
+        if search.editing {
+            format!(
+                " search: /{}▏ {} [enter:done | Esc:cancel] ",
+                search.query, counter
+            )
+        } else {
+            format!(
+                " output [/{} {} | n/N:next/prev | Esc:clear search] ",
+                search.query, counter
+            )
+        }
+    } else if let Some(ref replay) = app.replay {
+        format!(
+            " {} {} of {} (UTC) [j/k:scroll | Esc:back to history] ",
+            format_timestamp(replay.record.started_at),
+            replay.record.operation,
+            replay.record.host
+        )
+    } else if app.input_mode {
         " output [INPUT MODE - Type password, Esc to exit] ".to_string()
-    } else if app.is_building {
+    } else if let Some(state) = session.and_then(|s| s.cancel_state) {
+        format!(
+            " output [cancelling... sent {} | Esc to escalate] ",
+            state.stage.signal().as_str()
+        )
+    } else if app.selected_is_building() {
         " output [building... | press 'i' for input mode] ".to_string()
+    } else if session.is_some_and(|s| s.status == BuildStatus::Queued) {
+        " output [queued | Esc to cancel] ".to_string()
     } else if app.output_scroll > 0 {
         format!(
             " output [j/k:scroll | ↑{} lines | End:live] ",
             app.output_scroll
         )
+    } else if let Some(exit) = session.and_then(|s| s.last_exit.as_ref()) {
+        format!(
+            " output [{} {} in {} | j/k:scroll | enter:rebuild] ",
+            if exit.success() { "✓" } else { "✗" },
+            exit.describe(),
+            format_duration(exit.duration)
+        )
     } else {
         " output [j/k:scroll | h/l:operation | u:upgrade | enter:rebuild] ".to_string()
     };
 
     let border_color = if app.input_mode {
         Color::Yellow
+    } else if app.is_cancelling() {
+        Color::Red
     } else {
         Color::Gray
     };
@@ -139,13 +395,18 @@ fn render_output_area(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(block, area);
 
     // Get inner area (inside borders)
-    let inner_area = area.inner(ratatui::layout::Margin {
+    let inner_area = area.inner(Margin {
         horizontal: 1,
         vertical: 1,
     });
 
-    // Trim trailing empty lines to avoid showing blank space at bottom
+    // Trim trailing empty lines to avoid showing blank space at bottom,
+    // but keep the line the cursor is on
+    let keep_lines = cursor_line.map_or(0, |line| line + 1);
     while let Some(last_line) = lines.last() {
+        if lines.len() <= keep_lines {
+            break;
+        }
         if last_line.spans.is_empty()
             || last_line
                 .spans
@@ -186,22 +447,88 @@ fn render_output_area(frame: &mut Frame, app: &App, area: Rect) {
     frame.render_widget(output, inner_area);
 }
 
-fn cells_to_line(cells: &[crate::terminal::Cell]) -> Line<'_> {
+/// Online, offline or auth-failed badge; blank until the first check is back
+fn reachability_badge<'a>(result: Option<&Reachability>) -> Span<'a> {
+    match result {
+        Some(result @ Reachability::Online) => {
+            Span::styled(result.symbol(), Style::default().fg(Color::Green))
+        }
+        Some(result @ Reachability::Offline(_)) => {
+            Span::styled(result.symbol(), Style::default().fg(Color::Red))
+        }
+        Some(result @ Reachability::AuthFailed(_)) => {
+            Span::styled(result.symbol(), Style::default().fg(Color::Yellow))
+        }
+        None => Span::raw(" "),
+    }
+}
+
+/// Up-to-date, drifted or unknown badge; `…` while being checked
+fn drift_badge<'a>(app: &App, host: &str) -> Span<'a> {
+    if app.is_checking_drift(host) {
+        return Span::styled("…", Style::default().fg(Color::Blue));
+    }
+    match app.drift.get(host) {
+        Some(drift @ Drift::UpToDate) => {
+            Span::styled(drift.symbol(), Style::default().fg(Color::Green))
+        }
+        Some(drift @ Drift::Drifted { .. }) => {
+            Span::styled(drift.symbol(), Style::default().fg(Color::Yellow))
+        }
+        Some(drift @ Drift::Unknown(_)) => {
+            Span::styled(drift.symbol(), Style::default().fg(Color::DarkGray))
+        }
+        None => Span::raw(" "),
+    }
+}
+
+pub fn status_style(status: BuildStatus) -> Style {
+    match status {
+        BuildStatus::Idle => Style::default(),
+        BuildStatus::Queued => Style::default().fg(Color::Blue),
+        BuildStatus::Running => Style::default().fg(Color::Yellow),
+        BuildStatus::Succeeded => Style::default().fg(Color::Green),
+        BuildStatus::Failed => Style::default().fg(Color::Red),
+        BuildStatus::Cancelled => Style::default().fg(Color::DarkGray),
+    }
+}
+
+/// `line` is the row's index in the scrollback followed by the screen, which
+/// `highlights` are positioned by
+fn cells_to_line(
+    cells: &[Cell],
+    cursor_x: Option<usize>,
+    line: usize,
+    highlights: &[SearchMatch],
+    current_match: Option<(usize, usize)>,
+) -> Line<'static> {
     let mut spans = Vec::new();
     let mut current_text = String::new();
     let mut current_style = Style::default();
 
-    for cell in cells {
+    for (x, cell) in cells.iter().enumerate() {
         let mut new_style = Style::default();
 
         if let Some(fg) = cell.fg {
-            new_style = new_style.fg(ansi_to_color(fg));
+            new_style = new_style.fg(cell_color(fg));
         }
         if let Some(bg) = cell.bg {
-            new_style = new_style.bg(ansi_to_color(bg));
+            new_style = new_style.bg(cell_color(bg));
+        }
+        new_style = new_style.add_modifier(cell_modifiers(cell.attrs));
+        if cursor_x == Some(x) {
+            new_style = new_style.add_modifier(Modifier::REVERSED);
         }
-        if cell.bold {
-            new_style = new_style.add_modifier(Modifier::BOLD);
+        let highlight = highlights
+            .iter()
+            .find(|m| m.columns_on(line).is_some_and(|cols| cols.contains(&x)));
+        if let Some(m) = highlight {
+            let color = if current_match == Some((m.line, m.start)) {
+                Color::LightRed
+            } else {
+                Color::Yellow
+            };
+            new_style = new_style.fg(Color::Black).bg(color);
         }
 
         // If style changed, flush current span
@@ -211,7 +538,7 @@ fn cells_to_line(cells: &[crate::terminal::Cell]) -> Line<'_> {
         }
 
         current_style = new_style;
-        current_text.push(cell.ch);
+        cell.push_text(&mut current_text);
     }
 
     // Flush remaining text
@@ -222,16 +549,44 @@ fn cells_to_line(cells: &[crate::terminal::Cell]) -> Line<'_> {
     Line::from(spans)
 }
 
-fn ansi_to_color(code: u8) -> Color {
-    match code {
-        0 => Color::Black,
-        1 => Color::Red,
-        2 => Color::Green,
-        3 => Color::Yellow,
-        4 => Color::Blue,
-        5 => Color::Magenta,
-        6 => Color::Cyan,
-        7 => Color::White,
-        _ => Color::Reset,
+fn cell_color(color: CellColor) -> Color {
+    match color {
+        CellColor::Indexed(0) => Color::Black,
+        CellColor::Indexed(1) => Color::Red,
+        CellColor::Indexed(2) => Color::Green,
+        CellColor::Indexed(3) => Color::Yellow,
+        CellColor::Indexed(4) => Color::Blue,
+        CellColor::Indexed(5) => Color::Magenta,
+        CellColor::Indexed(6) => Color::Cyan,
+        CellColor::Indexed(7) => Color::Gray,
+        CellColor::Indexed(8) => Color::DarkGray,
+        CellColor::Indexed(9) => Color::LightRed,
+        CellColor::Indexed(10) => Color::LightGreen,
+        CellColor::Indexed(11) => Color::LightYellow,
+        CellColor::Indexed(12) => Color::LightBlue,
+        CellColor::Indexed(13) => Color::LightMagenta,
+        CellColor::Indexed(14) => Color::LightCyan,
+        CellColor::Indexed(15) => Color::White,
+        CellColor::Indexed(n) => Color::Indexed(n),
+        CellColor::Rgb(r, g, b) => Color::Rgb(r, g, b),
+    }
+}
+
+fn cell_modifiers(attrs: Attrs) -> Modifier {
+    let mut modifiers = Modifier::empty();
+    for (set, modifier) in [
+        (attrs.bold, Modifier::BOLD),
+        (attrs.dim, Modifier::DIM),
+        (attrs.italic, Modifier::ITALIC),
+        (attrs.underline, Modifier::UNDERLINED),
+        (attrs.blink, Modifier::SLOW_BLINK),
+        (attrs.reverse, Modifier::REVERSED),
+        (attrs.hidden, Modifier::HIDDEN),
+        (attrs.strikethrough, Modifier::CROSSED_OUT),
+    ] {
+        if set {
+            modifiers |= modifier;
+        }
     }
+    modifiers
 }
@@ -1,5 +1,13 @@
+pub mod confirm;
+pub mod diagnostics;
+pub mod facts;
+pub mod generations;
+pub mod group;
+pub mod history;
+pub mod inputs;
 pub mod layout;
 pub mod main_panel;
+pub mod review;
 pub mod settings;
 
 pub use layout::render;
@@ -0,0 +1,123 @@
+use crate::app::{App, ReviewStage};
+use crate::nix::closure::PackageChange;
+use crate::util::{format_size, format_size_delta};
+use ratatui::{
+    layout::Rect,
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    Frame,
+};
+
+pub fn render(frame: &mut Frame, app: &App, area: Rect) {
+    let (Some(review), Some((host, _))) = (app.selected_review(), app.get_selected_host()) else {
+        return;
+    };
+
+    let mut lines = Vec::new();
+    if let Some(ref toplevel) = review.toplevel {
+        lines.push(Line::from(Span::styled(
+            toplevel.clone(),
+            Style::default().fg(Color::DarkGray),
+        )));
+    }
+
+    match review.stage {
+        ReviewStage::Building => {}
+        ReviewStage::Diffing(_) => {
+            lines.push(Line::from(
+                "Copying closure and comparing with /run/current-system...",
+            ));
+        }
+        ReviewStage::Failed(ref error) => {
+            lines.push(Line::from(Span::styled(
+                format!("✗ Could not diff the new system: {}", error),
+                Style::default().fg(Color::Red),
+            )));
+        }
+        ReviewStage::Ready(ref diff) => {
+            let delta_color = if diff.size_delta() > 0 {
+                Color::Red
+            } else {
+                Color::Green
+            };
+            lines.push(Line::from(vec![
+                Span::raw(format!(
+                    "Closure size: {} → {} ",
+                    format_size(diff.size_before),
+                    format_size(diff.size_after)
+                )),
+                Span::styled(
+                    format!("({})", format_size_delta(diff.size_delta())),
+                    Style::default().fg(delta_color),
+                ),
+            ]));
+            lines.push(Line::from(""));
+
+            if diff.is_empty() {
+                lines.push(Line::from(
+                    "No packages were added, removed or changed version.",
+                ));
+            }
+            push_section(&mut lines, "Changed", "~", Color::Yellow, &diff.changed);
+            push_section(&mut lines, "Added", "+", Color::Green, &diff.added);
+            push_section(&mut lines, "Removed", "-", Color::Red, &diff.removed);
+            if diff.resized > 0 {
+                lines.push(Line::from(Span::styled(
+                    format!("{} more packages changed only in size", diff.resized),
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+        }
+    }
+
+    let title = format!(
+        " review: {} [s:switch | b:boot | j/k:scroll | Esc:abort] ",
+        host
+    );
+    let paragraph = Paragraph::new(lines).scroll((review.scroll, 0)).block(
+        Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(Color::Cyan)),
+    );
+    frame.render_widget(paragraph, area);
+}
+
+/// Append a titled list of package changes, if there are any
+fn push_section(
+    lines: &mut Vec<Line<'static>>,
+    title: &str,
+    symbol: &str,
+    color: Color,
+    changes: &[PackageChange],
+) {
+    if changes.is_empty() {
+        return;
+    }
+
+    lines.push(Line::from(Span::styled(
+        format!("{} ({})", title, changes.len()),
+        Style::default().add_modifier(Modifier::BOLD),
+    )));
+    for change in changes {
+        let versions = match symbol {
+            "+" => change.after.clone(),
+            "-" => change.before.clone(),
+            _ => format!("{} → {}", change.before, change.after),
+        };
+        let mut spans = vec![
+            Span::styled(format!("  {} ", symbol), Style::default().fg(color)),
+            Span::raw(format!("{}  ", change.name)),
+            Span::styled(versions, Style::default().fg(Color::Cyan)),
+        ];
+        if let Some(ref size) = change.size_delta {
+            spans.push(Span::styled(
+                format!("  {}", size),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        lines.push(Line::from(spans));
+    }
+    lines.push(Line::from(""));
+}
@@ -1,4 +1,6 @@
 use crate::app::{App, EditMode, FocusedPanel};
+use crate::config::Connection;
+use crate::util::format_duration;
 use ratatui::{
     layout::Rect,
     style::{Color, Modifier, Style},
@@ -26,14 +28,79 @@ pub fn render(frame: &mut Frame, app: &App, area: Rect) {
             .to_string()
     };
 
+    // Where the flake's checkout stands, and what a build would leave out
+    let mut git_spans = Vec::new();
+    if let (Some(status), false) = (&app.git_status, app.edit_mode == EditMode::FlakePath) {
+        git_spans.push(Span::styled(
+            format!(" · {}", status.describe_head()),
+            Style::default().fg(Color::DarkGray),
+        ));
+        if status.ahead > 0 || status.behind > 0 {
+            git_spans.push(Span::styled(
+                format!(" ↑{} ↓{}", status.ahead, status.behind),
+                Style::default().fg(Color::DarkGray),
+            ));
+        }
+        if status.is_dirty() {
+            git_spans.push(Span::styled(
+                format!(" · {} uncommitted", status.changed),
+                Style::default().fg(Color::Red),
+            ));
+        }
+        if !status.untracked.is_empty() {
+            git_spans.push(Span::styled(
+                format!(" · {} untracked, not in flake", status.untracked.len()),
+                Style::default().fg(Color::Yellow),
+            ));
+        }
+    }
+
+    // The git ref deployed instead of the working tree
+    let git_ref = if app.edit_mode == EditMode::GitRef {
+        Some(Span::styled(
+            format!(" · ref: {}_", app.edit_buffer),
+            Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+        ))
+    } else {
+        app.git_ref.as_ref().map(|git_ref| {
+            Span::styled(
+                format!(" · deploying {}", git_ref.describe()),
+                Style::default().fg(Color::Magenta),
+            )
+        })
+    };
+    git_spans.extend(git_ref);
+
     let selected_host = if app.edit_mode == EditMode::HostConnection {
         format!("{}_", app.edit_buffer)
     } else {
         app.get_selected_host()
             .map(|(name, conn)| format!("{} → {}", name, conn.display()))
+            .or_else(|| app.selected_group().map(|group| format!("group {}", group)))
             .unwrap_or_else(|| "(no host selected)".to_string())
     };
 
+    // Result of the selected host's last reachability check
+    let reachability = app
+        .get_selected_host()
+        .and_then(|(name, _)| app.host_checks.get(&name))
+        .and_then(|check| Some((check.result.as_ref()?, check.checked_at?)))
+        .map(|(result, at)| {
+            format!(
+                " · {}, checked {} ago",
+                result.describe(),
+                format_duration(at.elapsed())
+            )
+        })
+        .unwrap_or_default();
+    let drift = app
+        .get_selected_host()
+        .and_then(|(name, _)| app.drift.get(&name))
+        .map(|drift| format!(" · {}", drift.describe()))
+        .unwrap_or_default();
+
     let extra_args = if app.edit_mode == EditMode::ExtraArgs {
         format!("{}_", app.edit_buffer)
     } else {
@@ -49,6 +116,24 @@ pub fn render(frame: &mut Frame, app: &App, area: Rect) {
             .unwrap_or_else(|| "(none)".to_string())
     };
 
+    let build_host = if app.edit_mode == EditMode::BuildHost {
+        format!("{}_", app.edit_buffer)
+    } else {
+        app.get_selected_host()
+            .and_then(|(name, _)| {
+                let host_config = app.config.hosts.get(&name)?;
+                let build_host = host_config.build_host.as_ref()?;
+                // Show where a configured host's name resolves to
+                match app.config.build_host(&name)? {
+                    Connection::Remote(host) if host.target != *build_host => {
+                        Some(format!("{} → {}", build_host, host.target))
+                    }
+                    _ => Some(build_host.clone()),
+                }
+            })
+            .unwrap_or_else(|| "(local)".to_string())
+    };
+
     let flake_style = if app.edit_mode == EditMode::FlakePath {
         Style::default()
             .fg(Color::Yellow)
@@ -73,18 +158,31 @@ pub fn render(frame: &mut Frame, app: &App, area: Rect) {
         Style::default().fg(Color::Cyan)
     };
 
+    let build_style = if app.edit_mode == EditMode::BuildHost {
+        Style::default()
+            .fg(Color::Blue)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default().fg(Color::Blue)
+    };
+
+    let mut flake_line = vec![
+        Span::raw("flake: "),
+        Span::styled(flake_path, flake_style),
+        Span::raw(" "),
+        Span::styled("[f]", Style::default().fg(Color::Gray)),
+    ];
+    flake_line.extend(git_spans);
+
     let mut text = vec![
-        Line::from(vec![
-            Span::raw("flake: "),
-            Span::styled(flake_path, flake_style),
-            Span::raw(" "),
-            Span::styled("[f]", Style::default().fg(Color::Gray)),
-        ]),
+        Line::from(flake_line),
         Line::from(vec![
             Span::raw("selected: "),
             Span::styled(selected_host, host_style),
             Span::raw(" "),
             Span::styled("[c]", Style::default().fg(Color::Gray)),
+            Span::styled(reachability, Style::default().fg(Color::DarkGray)),
+            Span::styled(drift, Style::default().fg(Color::DarkGray)),
         ]),
         Line::from(vec![
             Span::raw("extra args: "),
@@ -92,17 +190,27 @@ pub fn render(frame: &mut Frame, app: &App, area: Rect) {
             Span::raw(" "),
             Span::styled("[a]", Style::default().fg(Color::Gray)),
         ]),
-        Line::from(""),
+        Line::from(vec![
+            Span::raw("build host: "),
+            Span::styled(build_host, build_style),
+            Span::raw(" "),
+            Span::styled("[b]", Style::default().fg(Color::Gray)),
+        ]),
     ];
 
-    if app.is_editing() {
+    if let Some(ref message) = app.status_message {
+        text.push(Line::from(Span::styled(
+            message.clone(),
+            Style::default().fg(Color::Magenta),
+        )));
+    } else if app.is_editing() {
         text.push(Line::from(Span::styled(
             "[enter] save | [esc] cancel",
             Style::default().fg(Color::Yellow),
         )));
     } else {
         text.push(Line::from(Span::styled(
-            "[tab] switch | [f] flake | [c] connection | [a] args",
+            "[tab] switch | [f] flake | [g] git ref | [c] connection | [a] args | [b] build host",
             Style::default().fg(Color::Gray),
         )));
     }
@@ -0,0 +1,123 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// Blocking work (usually a nix or ssh call) running on its own thread, so
+/// the UI can keep drawing while it waits
+pub struct BackgroundTask<T> {
+    rx: mpsc::Receiver<T>,
+}
+
+impl<T: Send + 'static> BackgroundTask<T> {
+    pub fn spawn(work: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = tx.send(work());
+        });
+        Self { rx }
+    }
+
+    /// Take the result if the work has finished
+    pub fn try_take(&self) -> Option<T> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// Format a duration compactly, e.g. "42s", "3m 07s" or "1h 02m"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+/// Format how long ago something was, coarsely, e.g. "12m", "5h" or "3d"
+pub fn format_age(secs: u64) -> String {
+    if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 2 * 86_400 {
+        format!("{}h", secs / 3600)
+    } else {
+        format!("{}d", secs / 86_400)
+    }
+}
+
+/// Format a byte count with binary units, e.g. "512 B" or "1.3 GiB"
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a signed byte count, e.g. "+50.0 MiB"
+pub fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_size(delta.unsigned_abs()))
+}
+
+/// Format a Unix timestamp as a UTC date and time, e.g. "2025-01-31 14:05"
+pub fn format_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs_of_day = unix_secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) civil date
+/// (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_timestamp(1_735_689_599), "2024-12-31 23:59");
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size_delta(-3 * 1024 * 1024 * 1024), "-3.0 GiB");
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(59), "0m");
+        assert_eq!(format_age(2 * 3600 + 59), "2h");
+        assert_eq!(format_age(10 * 86_400), "10d");
+    }
+}